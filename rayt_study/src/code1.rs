@@ -15,6 +15,8 @@ impl ScatterInfo {
 // 材質，Sync，Send継承
 trait Material: Sync + Send {
     fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo>;
+    // 拡散反射の反射率．拡散反射面では太陽を直接サンプリングする．
+    fn diffuse_albedo(&self) -> Option<Color> { None }
 }
 
 // ランバート反射，わからなくなったら調べる．
@@ -33,6 +35,10 @@ impl Material for Lambertian {
         let target = hit.p + hit.n + Vec3::random_in_unit_sphere();
         Some(ScatterInfo::new(Ray::new(hit.p, target - hit.p), self.albedo))
     }
+
+    fn diffuse_albedo(&self) -> Option<Color> {
+        Some(self.albedo)
+    }
 }
 
 // 鏡面反射する材質
@@ -223,6 +229,8 @@ impl ShapeBuilder {
 
 struct RandomScene {
     world: ShapeList,
+    // 空の解析モデル．Noneなら単純なグラデーション
+    sky: Option<Sky>,
 }
 
 impl RandomScene {
@@ -236,37 +244,40 @@ impl RandomScene {
             .build());
         
         // Small spheres
-        for au in -11..11 {
-            let a = au as f64;
-            for bu in -11..11 {
-                let b = bu as f64;
-                let [rx, rz, material_choice] = Float3::random().to_array();
-                let center = Point3::new(a + 0.9 * rx, 0.2, b + 0.9 * rz);
-                if (center - Point3::new(4.0, 0.2, 0.0)).length() > 0.9 {
-                    world.push({
-                        if material_choice < 0.8 {
-                            let albedo = Color::random() * Color::random();
-                            ShapeBuilder::new()
-                                .lambertian(albedo)
-                                .sphere(center, 0.2)
-                                .build()
-                        } else if material_choice < 0.95 {
-                            let albedo = Color::random_limit(0.5, 1.0);
-                            let fuzz = Float3::random_full().x();
-                            ShapeBuilder::new()
-                                .metal(albedo, fuzz)
-                                .sphere(center, 0.2)
-                                .build()
-                        } else {
-                            ShapeBuilder::new()
-                                .dielectric(1.5)
-                                .sphere(center, 0.2)
-                                .build()
-                        }
-                    });
+        // 配置は固定の乱数列で決めるので，実行ごとに同じシーンになる
+        with_stream(0, &[], || {
+            for au in -11..11 {
+                let a = au as f64;
+                for bu in -11..11 {
+                    let b = bu as f64;
+                    let [rx, rz, material_choice] = Float3::random().to_array();
+                    let center = Point3::new(a + 0.9 * rx, 0.2, b + 0.9 * rz);
+                    if (center - Point3::new(4.0, 0.2, 0.0)).length() > 0.9 {
+                        world.push({
+                            if material_choice < 0.8 {
+                                let albedo = Color::random() * Color::random();
+                                ShapeBuilder::new()
+                                    .lambertian(albedo)
+                                    .sphere(center, 0.2)
+                                    .build()
+                            } else if material_choice < 0.95 {
+                                let albedo = Color::random_limit(0.5, 1.0);
+                                let fuzz = Float3::random_full().x();
+                                ShapeBuilder::new()
+                                    .metal(albedo, fuzz)
+                                    .sphere(center, 0.2)
+                                    .build()
+                            } else {
+                                ShapeBuilder::new()
+                                    .dielectric(1.5)
+                                    .sphere(center, 0.2)
+                                    .build()
+                            }
+                        });
+                    }
                 }
             }
-        }
+        });
 
 
         world.push(ShapeBuilder::new()
@@ -282,15 +293,52 @@ impl RandomScene {
             .sphere(Point3::new(4.0, 1.0, 0.0), 1.0)
             .build());
 
-        Self { world }
+        Self { world, sky: None }
     }
 
-        
+    fn with_sky(mut self, sky: Sky) -> Self {
+        self.sky = Some(sky);
+        self
+    }
 
-    fn background(&self, d: Vec3) -> Color {
+    // sun_sampledは直前の衝突点で太陽を直接サンプリングしたか．その場合は太陽の円盤を数えない．
+    fn background(&self, d: Vec3, sun_sampled: bool) -> Color {
+        if let Some(sky) = &self.sky {
+            return if sun_sampled { sky.sky(d) } else { sky.value(d) };
+        }
         let t = 0.5 * (d.normalize().y() + 1.0);
         Color::one().lerp(Color::new(0.5, 0.7, 1.0), t)
     }
+
+    // 太陽の円盤内で一様に選んだ方向から届く直接光．太陽をサンプリングしなければNone．
+    fn direct_sun(&self, hit: &HitInfo) -> Option<Color> {
+        let sky = self.sky.as_ref()?;
+        let albedo = hit.m.diffuse_albedo()?;
+        if sky.sun_radiance().luminance() <= 0.0 {
+            return None;
+        }
+        let d = sky.sample_sun();
+        let cos = d.dot(hit.n);
+        if cos <= 0.0 || self.world.hit(&Ray::new(hit.p, d), 0.001, f64::MAX).is_some() {
+            return Some(Color::zero());
+        }
+        Some(albedo * sky.sun_radiance() * (FRAC_1_PI * cos * sky.sun_solid_angle()))
+    }
+
+    fn radiance(&self, ray: Ray, depth: usize, sun_sampled: bool) -> Color {
+        let hit_info = self.world.hit(&ray, 0.001, f64::MAX);
+        if let Some(hit) = hit_info {
+            let scatter_info = if depth > 0 {hit.m.scatter(&ray, &hit) } else { None };
+            if let Some(scatter) = scatter_info {
+                let sun = self.direct_sun(&hit);
+                sun.unwrap_or_else(Color::zero) + scatter.albedo * self.radiance(scatter.ray, depth - 1, sun.is_some())
+            } else {
+                Color::zero()
+            }
+        } else {
+            self.background(ray.direction, sun_sampled)
+        }
+    }
 }
 
 impl SceneWithDepth for RandomScene {
@@ -306,17 +354,7 @@ impl SceneWithDepth for RandomScene {
 
     // 反射率５０%
     fn trace(&self, ray: Ray, depth: usize) -> Color {
-        let hit_info = self.world.hit(&ray, 0.001, f64::MAX);
-        if let Some(hit) = hit_info {
-            let scatter_info = if depth > 0 {hit.m.scatter(&ray, &hit) } else { None };
-            if let Some(scatter) = scatter_info {
-                scatter.albedo * self.trace(scatter.ray, depth - 1)
-            } else {
-                Color::zero()
-            }
-        } else {
-            self.background(ray.direction)
-        }
+        self.radiance(ray, depth, false)
         // match hit_info {
         //     Some(hit) if depth > 0 => {
         //         if let Some(scatter) = hit.m.scatter(&ray, &hit) {
@@ -333,4 +371,9 @@ impl SceneWithDepth for RandomScene {
 
 pub fn run() {
     render_aa_with_depth(RandomScene::new());
+}
+
+// 昼光の空の下で描く
+pub fn run_sky() {
+    render_aa_with_depth(RandomScene::new().with_sky(Sky::new(30.0, -150.0, 3.0, Color::full(0.3))));
 }
\ No newline at end of file
@@ -1,4 +1,7 @@
 use crate::rayt_mod::*;
+use rayon::prelude::*;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
 trait Pdf: Send + Sync {
     fn value(&self, hit: &HitInfo, direction: Vec3) -> f64;
@@ -70,16 +73,451 @@ impl Pdf for MixturePdf {
     }
 }
 
+// 軸の周りのcos^n分布．再帰反射などの光沢ローブに使う．
+struct PhongPdf {
+    onb: ONB,
+    exponent: f64,
+}
+
+impl PhongPdf {
+    fn new(axis: Vec3, exponent: f64) -> Self {
+        Self { onb: ONB::new(axis), exponent }
+    }
+}
+
+impl Pdf for PhongPdf {
+    fn value(&self, _hit: &HitInfo, direction: Vec3) -> f64 {
+        let cosine = direction.normalize().dot(self.onb.w());
+        if cosine > 0.0 {
+            (self.exponent + 1.0) * 0.5 * FRAC_1_PI * cosine.powf(self.exponent)
+        } else {
+            0.0
+        }
+    }
+
+    fn generate(&self, _hit: &HitInfo) -> Vec3 {
+        let [r1, r2, _] = Vec3::random().to_array();
+        let z = r1.powf((self.exponent + 1.0).recip());
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let (x, y) = (PI2 * r2).sin_cos();
+        self.onb.local(Vec3::new(x * r, y * r, z))
+    }
+}
+
+// 球面上の一様分布．等方散乱の位相関数に使う．
+struct SpherePdf {}
+
+impl Pdf for SpherePdf {
+    fn value(&self, _hit: &HitInfo, _direction: Vec3) -> f64 {
+        0.25 * FRAC_1_PI
+    }
+
+    fn generate(&self, _hit: &HitInfo) -> Vec3 {
+        Vec3::random_unit_vector()
+    }
+}
+
+// 鏡面反射の方向に半径fuzzの球内の一様な点を足した方向の分布．ぼやけた金属に使う．
+struct FuzzPdf {
+    reflected: Vec3,
+    fuzz: f64,
+}
+
+impl FuzzPdf {
+    fn new(reflected: Vec3, fuzz: f64) -> Self {
+        Self { reflected: reflected.normalize(), fuzz }
+    }
+}
+
+impl Pdf for FuzzPdf {
+    // 方向dの半直線が球を切り取る部分の体積(∫t^2dt)を球の体積で割る
+    fn value(&self, _hit: &HitInfo, direction: Vec3) -> f64 {
+        let b = direction.normalize().dot(self.reflected);
+        let disc = b * b - (1.0 - self.fuzz * self.fuzz);
+        if disc < 0.0 {
+            return 0.0;
+        }
+        let t1 = (b - disc.sqrt()).max(0.0);
+        let t2 = (b + disc.sqrt()).max(0.0);
+        (t2.powi(3) - t1.powi(3)) / (4.0 * PI * self.fuzz.powi(3))
+    }
+
+    fn generate(&self, _hit: &HitInfo) -> Vec3 {
+        self.reflected + self.fuzz * Vec3::random_in_unit_sphere()
+    }
+}
+
+// 異方性GGXの反射方向の分布．ハーフベクトルをD(h)cosθhに従って選ぶ．
+// 接空間は法線と接ベクトルから作り，alpha_xが接ベクトル方向の粗さ．
+struct GgxPdf {
+    onb: ONB,
+    wi: Vec3,
+    alpha_x: f64,
+    alpha_y: f64,
+}
+
+impl GgxPdf {
+    fn new(onb: ONB, wi: Vec3, alpha_x: f64, alpha_y: f64) -> Self {
+        let wi = onb.project(wi.normalize());
+        Self { onb, wi, alpha_x, alpha_y }
+    }
+
+    // 法線分布関数．hは接空間の単位ベクトル．
+    fn d(&self, h: Vec3) -> f64 {
+        let e = (h.x() / self.alpha_x).powi(2) + (h.y() / self.alpha_y).powi(2) + h.z().powi(2);
+        FRAC_1_PI / (self.alpha_x * self.alpha_y * e * e)
+    }
+
+    // Smithのマスキング関数
+    fn g1(&self, w: Vec3) -> f64 {
+        let a2tan2 = ((self.alpha_x * w.x()).powi(2) + (self.alpha_y * w.y()).powi(2)) / w.z().powi(2);
+        2.0 / (1.0 + (1.0 + a2tan2).sqrt())
+    }
+
+    // BRDFとcosの積(フレネル項はアルベドに任せる)
+    fn brdf_cos(&self, direction: Vec3) -> f64 {
+        let wo = self.onb.project(direction.normalize());
+        if wo.z() <= 0.0 || self.wi.z() <= 0.0 {
+            return 0.0;
+        }
+        let h = (self.wi + wo).normalize();
+        self.d(h) * self.g1(self.wi) * self.g1(wo) / (4.0 * self.wi.z())
+    }
+}
+
+impl Pdf for GgxPdf {
+    fn value(&self, _hit: &HitInfo, direction: Vec3) -> f64 {
+        let wo = self.onb.project(direction.normalize());
+        if wo.z() <= 0.0 {
+            return 0.0;
+        }
+        let h = (self.wi + wo).normalize();
+        self.d(h) * h.z() / (4.0 * wo.dot(h).abs())
+    }
+
+    fn generate(&self, _hit: &HitInfo) -> Vec3 {
+        // 傾き空間で等方(alpha=1)にサンプルして引き伸ばす
+        let [r1, r2, _] = Vec3::random().to_array();
+        let r = (r1 / (1.0 - r1)).sqrt();
+        let (sin, cos) = (PI2 * r2).sin_cos();
+        let h = Vec3::new(-r * cos * self.alpha_x, -r * sin * self.alpha_y, 1.0).normalize();
+        let wo = 2.0 * self.wi.dot(h) * h - self.wi;
+        self.onb.local(wo)
+    }
+}
+
+// 経路誘導の方向の分布(Müller et al. 2017のSD木)．方向を円筒座標(cosθ, φ)で単位正方形に写し，
+// 4分木で区切って区画ごとに集めた入射光の明るさに比例する確率で選ぶ．
+const DTREE_MAX_DEPTH: usize = 20;
+// この割合より多く明るさを集めた区画は次の反復で細かく分ける
+const DTREE_SUBDIVIDE: f64 = 0.01;
+// 明るさは固定小数点で足す．整数の和は足す順番によらないので，
+// 並列に記録しても同じ分布になる．
+const DTREE_FIXED_POINT: f64 = 1048576.0;
+
+// sumは4つの区画それぞれの明るさ(固定小数点)．childrenが0の区画はそれ以上分かれていない．
+#[derive(Clone, Default)]
+struct DNode {
+    sum: [u64; 4],
+    children: [usize; 4],
+}
+
+impl DNode {
+    fn total(&self) -> u64 {
+        self.sum.iter().fold(0, |acc, &s| acc.saturating_add(s))
+    }
+}
+
+#[derive(Clone)]
+struct DTree {
+    nodes: Vec<DNode>,
+}
+
+impl DTree {
+    fn new() -> Self {
+        Self { nodes: vec![DNode::default()] }
+    }
+
+    // 集めた明るさの合計．比だけに使うので固定小数点のまま返す．
+    fn total(&self) -> f64 {
+        self.nodes[0].total() as f64
+    }
+
+    // 円筒座標への写像は面積を保つので，立体角あたりの確率密度は正方形の上の密度 / 4π
+    fn to_square(d: Vec3) -> (f64, f64) {
+        let d = d.normalize();
+        ((d.z() * 0.5 + 0.5).clamp(0.0, 1.0), (d.y().atan2(d.x()) / PI2).rem_euclid(1.0))
+    }
+
+    fn from_square(u: f64, v: f64) -> Vec3 {
+        let cos_theta = 2.0 * u - 1.0;
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let (sin_phi, cos_phi) = (PI2 * v).sin_cos();
+        Vec3::new(sin_theta * cos_phi, sin_theta * sin_phi, cos_theta)
+    }
+
+    // 点pの入っている区画を返し，pを区画の中の座標に直す
+    fn quadrant(p: &mut (f64, f64)) -> usize {
+        let (x, y) = (p.0 >= 0.5, p.1 >= 0.5);
+        *p = (p.0 * 2.0 - x as usize as f64, p.1 * 2.0 - y as usize as f64);
+        x as usize + 2 * y as usize
+    }
+
+    fn pdf(&self, d: Vec3) -> f64 {
+        let mut p = Self::to_square(d);
+        let (mut n, mut pdf) = (0, 1.0);
+        loop {
+            let node = &self.nodes[n];
+            let total = node.total();
+            if total == 0 {
+                break;
+            }
+            let i = Self::quadrant(&mut p);
+            pdf *= 4.0 * node.sum[i] as f64 / total as f64;
+            match node.children[i] {
+                0 => break,
+                c => n = c,
+            }
+        }
+        pdf / (2.0 * PI2)
+    }
+
+    fn sample(&self) -> Vec3 {
+        let (mut x, mut y, mut size) = (0.0, 0.0, 1.0);
+        let mut n = 0;
+        loop {
+            let node = &self.nodes[n];
+            let total = node.total();
+            if total == 0 {
+                break;
+            }
+            let mut r = Vec3::random_full().x() * total as f64;
+            let i = (0..3).find(|&i| {
+                r -= node.sum[i] as f64;
+                r < 0.0
+            }).unwrap_or(3);
+            size *= 0.5;
+            x += (i % 2) as f64 * size;
+            y += (i / 2) as f64 * size;
+            match node.children[i] {
+                0 => break,
+                c => n = c,
+            }
+        }
+        let [rx, ry, _] = Vec3::random().to_array();
+        Self::from_square(x + rx * size, y + ry * size)
+    }
+
+    // 集めた明るさから次の反復で使う構造を作る．明るさは0から集め直す．
+    fn refined(&self) -> Self {
+        let mut tree = Self::new();
+        let total = self.total();
+        if total <= 0.0 {
+            return tree;
+        }
+        // (新しい節点, 対応する古い節点, 節点の明るさ, 深さ)
+        let mut stack = vec![(0, Some(0), total, 1)];
+        while let Some((n, old, energy, depth)) = stack.pop() {
+            for i in 0..4 {
+                // 古い木で分かれていなかった区画は明るさが均等だとみなす
+                let (e, old_child) = match old {
+                    Some(o) => (self.nodes[o].sum[i] as f64, Some(self.nodes[o].children[i]).filter(|&c| c != 0)),
+                    None => (energy * 0.25, None),
+                };
+                if depth < DTREE_MAX_DEPTH && e / total > DTREE_SUBDIVIDE {
+                    tree.nodes.push(DNode::default());
+                    let child = tree.nodes.len() - 1;
+                    tree.nodes[n].children[i] = child;
+                    stack.push((child, old_child, e, depth + 1));
+                }
+            }
+        }
+        tree
+    }
+}
+
+// 反復の間に明るさを集める方向の分布．区画の分け方は反復の初めに決めておき，
+// 和だけを固定小数点で原子的に足すので，ロックせずに並列に記録できる．
+struct AtomicDTree {
+    tree: DTree,
+    sums: Vec<[AtomicU64; 4]>,
+}
+
+impl AtomicDTree {
+    fn new(tree: DTree) -> Self {
+        let sums = tree.nodes.iter().map(|_| Default::default()).collect();
+        Self { tree, sums }
+    }
+
+    fn record(&self, d: Vec3, value: f64) {
+        let mut p = DTree::to_square(d);
+        let value = (value * DTREE_FIXED_POINT).round() as u64;
+        let mut n = 0;
+        loop {
+            let i = DTree::quadrant(&mut p);
+            self.sums[n][i].fetch_add(value, Ordering::Relaxed);
+            match self.tree.nodes[n].children[i] {
+                0 => break,
+                c => n = c,
+            }
+        }
+    }
+
+    // 集めた明るさを持つ分布
+    fn to_tree(&self) -> DTree {
+        let mut tree = self.tree.clone();
+        for (node, sums) in tree.nodes.iter_mut().zip(&self.sums) {
+            for (sum, s) in node.sum.iter_mut().zip(sums) {
+                *sum = s.load(Ordering::Relaxed);
+            }
+        }
+        tree
+    }
+}
+
+// 空間の葉が持つ方向の分布．samplingは前の反復で学習した分布で，
+// buildingには今の反復の明るさを集める．
+struct GuideLeaf {
+    sampling: Arc<DTree>,
+    building: AtomicDTree,
+    count: AtomicUsize,
+}
+
+impl GuideLeaf {
+    fn new(sampling: DTree) -> Self {
+        let building = AtomicDTree::new(sampling.refined());
+        Self { sampling: Arc::new(sampling), building, count: AtomicUsize::new(0) }
+    }
+
+    // この葉で使う方向の分布．まだ何も学習していなければNone．
+    fn sampling(&self) -> Option<Arc<DTree>> {
+        Some(Arc::clone(&self.sampling)).filter(|tree| tree.total() > 0.0)
+    }
+
+    // 方向dから届いた光の明るさを記録する
+    fn record(&self, d: Vec3, value: f64) {
+        if !(value.is_finite() && value > 0.0) {
+            return;
+        }
+        self.building.record(d, value);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// 空間を二分木で分け，葉ごとに方向の分布を持つ．
+enum SNode {
+    Inner { axis: usize, split: f64, children: [usize; 2] },
+    Leaf { min: Point3, max: Point3, leaf: Arc<GuideLeaf> },
+}
+
+struct SdTree {
+    nodes: RwLock<Vec<SNode>>,
+}
+
+impl SdTree {
+    fn new(min: Point3, max: Point3) -> Self {
+        let leaf = SNode::Leaf { min, max, leaf: Arc::new(GuideLeaf::new(DTree::new())) };
+        Self { nodes: RwLock::new(vec![leaf]) }
+    }
+
+    // 点pの入っている葉．衝突点ごとに一度だけ探し，サンプリングと記録に使う．
+    fn leaf(&self, p: Point3) -> Arc<GuideLeaf> {
+        let nodes = self.nodes.read().unwrap();
+        let mut n = 0;
+        loop {
+            match &nodes[n] {
+                SNode::Inner { axis, split, children } => {
+                    n = children[(p.to_array()[*axis] >= *split) as usize];
+                }
+                SNode::Leaf { leaf, .. } => return Arc::clone(leaf),
+            }
+        }
+    }
+
+    // 点pで使う方向の分布．まだ何も学習していなければNone．
+    fn sampling(&self, p: Point3) -> Option<Arc<DTree>> {
+        self.leaf(p).sampling()
+    }
+
+    // 反復の終わりに，集めた明るさを次の反復の分布にする．
+    // 記録がsplitより多かった葉は一番長い軸の真ん中で2つに分ける．
+    fn refine(&self, split: usize) {
+        let mut nodes = self.nodes.write().unwrap();
+        for n in 0..nodes.len() {
+            let (min, max, building, count) = match &nodes[n] {
+                SNode::Leaf { min, max, leaf } => (*min, *max, leaf.building.to_tree(), leaf.count.load(Ordering::Relaxed)),
+                _ => continue,
+            };
+            let new_leaf = |min, max| SNode::Leaf { min, max, leaf: Arc::new(GuideLeaf::new(building.clone())) };
+            if count <= split {
+                nodes[n] = new_leaf(min, max);
+                continue;
+            }
+            let extent = (max - min).to_array();
+            let axis = (0..3).fold(0, |a, i| if extent[i] > extent[a] { i } else { a });
+            let s = min.to_array()[axis] + extent[axis] * 0.5;
+            let (mut left_max, mut right_min) = (max.to_array(), min.to_array());
+            left_max[axis] = s;
+            right_min[axis] = s;
+            let to_point = |a: [f64; 3]| Point3::new(a[0], a[1], a[2]);
+            nodes.push(new_leaf(min, to_point(left_max)));
+            nodes.push(new_leaf(to_point(right_min), max));
+            let children = [nodes.len() - 2, nodes.len() - 1];
+            nodes[n] = SNode::Inner { axis, split: s, children };
+        }
+    }
+}
+
+// 学習した方向の分布によるサンプリング
+struct GuidePdf {
+    tree: Arc<DTree>,
+}
+
+impl GuidePdf {
+    fn new(tree: Arc<DTree>) -> Self {
+        Self { tree }
+    }
+}
+
+impl Pdf for GuidePdf {
+    fn value(&self, _hit: &HitInfo, direction: Vec3) -> f64 {
+        self.tree.pdf(direction)
+    }
+
+    fn generate(&self, _hit: &HitInfo) -> Vec3 {
+        self.tree.sample()
+    }
+}
+
+// 散乱の種類．SpecularとTransmissionは一方向にしか散乱しないデルタ分布．
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Lobe {
+    Diffuse,
+    Glossy,
+    Specular,
+    Transmission,
+}
+
+impl Lobe {
+    fn is_delta(&self) -> bool {
+        matches!(self, Lobe::Specular | Lobe::Transmission)
+    }
+}
+
 // 光の散乱
+// デルタ分布の散乱(lobe.is_delta())ではpdfがNoneでrayが散乱方向．光源サンプリングもMISもせずにそのまま追跡する．
+// それ以外の散乱は必ずpdfを持ち，rayは使わない．
 struct ScatterInfo {
     ray: Ray,
     albedo: Color,
     pdf: Option<Arc<dyn Pdf>>,
+    lobe: Lobe,
 }
 
 impl ScatterInfo {
-    fn new(ray: Ray, albedo: Color, pdf: Option<Arc<dyn Pdf>>) -> Self {
-        Self { ray, albedo, pdf }
+    fn new(ray: Ray, albedo: Color, pdf: Option<Arc<dyn Pdf>>, lobe: Lobe) -> Self {
+        debug_assert_eq!(lobe.is_delta(), pdf.is_none());
+        Self { ray, albedo, pdf, lobe }
     }
 }
 
@@ -87,6 +525,10 @@ impl ScatterInfo {
 // u,vはテクスチャ座標，pはピクセルの位置情報
 trait Texture: Sync + Send {
     fn value(&self, u: f64, v: f64, p: Point3) -> Color;
+    // 不透明度．1.0で不透明，0.0で完全に透明．
+    fn alpha(&self, _u: f64, _v: f64, _p: Point3) -> f64 { 1.0 }
+    // 衝突点での色．光線微分からフィルタ幅が分かるテクスチャはこちらを上書きする．
+    fn evaluate(&self, hit: &HitInfo) -> Color { self.value(hit.u, hit.v, hit.p) }
 }
 
 // 手続き型テクスチャ，カラー（反射率)を持つ．
@@ -130,810 +572,4480 @@ impl Texture for CheckerTexture {
     }
 }
 
-// 画像テクスチャ
-struct ImageTexture {
-    pixels: Vec<Color>,
-    width: usize,
-    height: usize,
+// パーリンノイズ．格子点にランダムな勾配ベクトルを置く．
+// 同じ表と勾配を使ってシンプレックスノイズとボロノイ(Worley)ノイズも計算する．
+struct Perlin {
+    ranvec: Vec<Vec3>,
+    perm_x: Vec<usize>,
+    perm_y: Vec<usize>,
+    perm_z: Vec<usize>,
 }
 
-impl ImageTexture {
-    fn new(path: &str) -> Self {
-        let rgbimg = image::open(path).unwrap().to_rgb8();
-        let (w, h) = rgbimg.dimensions();
-        let mut image = vec![Color::zero(); (w * h) as usize];
-        for (i, (_, _, pixel)) in image.iter_mut().zip(rgbimg.enumerate_pixels()) {
-            *i = Color::from_rgb(pixel[0], pixel[1], pixel[2]);
+impl Perlin {
+    const POINT_COUNT: usize = 256;
+
+    // 表は固定の乱数列で作るので，実行ごとに同じ模様になる
+    fn new() -> Self {
+        with_stream(0, &[], || {
+            let ranvec = (0..Self::POINT_COUNT).map(|_| Vec3::random_limit(-1.0, 1.0).normalize()).collect();
+            Self {
+                ranvec,
+                perm_x: Self::generate_perm(),
+                perm_y: Self::generate_perm(),
+                perm_z: Self::generate_perm(),
+            }
+        })
+    }
+
+    fn generate_perm() -> Vec<usize> {
+        let mut p: Vec<usize> = (0..Self::POINT_COUNT).collect();
+        for i in (1..p.len()).rev() {
+            let target = (Vec3::random_full().x() * (i + 1) as f64) as usize;
+            p.swap(i, target.min(i));
         }
-        Self { pixels: image, width: w as usize, height: h as usize }
+        p
     }
 
-    fn sample(&self, u: i64, v: i64) -> Color {
-        let tu = if u < 0 { 0 } else if u as usize >= self.width { self.width - 1 } else { u as usize };
-        let tv = if v < 0 { 0 } else if v as usize >= self.height { self.height - 1 } else { v as usize };
-        self.pixels[tu + self.width * tv]
+    // 格子点(i, j, k)のハッシュ
+    fn hash(&self, i: i64, j: i64, k: i64) -> usize {
+        let mask = Self::POINT_COUNT as i64 - 1;
+        self.perm_x[(i & mask) as usize] ^ self.perm_y[(j & mask) as usize] ^ self.perm_z[(k & mask) as usize]
     }
-}
 
-impl Texture for ImageTexture {
-    fn value(&self, u: f64, v: f64, _p: Point3) -> Color {
-        let x = (u * self.width as f64) as i64;
-        let y = ((1.0 - v) * self.height as f64) as i64;
-        self.sample(x, y)
+    // 値域はおおよそ[-1, 1]
+    fn noise(&self, p: Point3) -> f64 {
+        let [x, y, z] = p.to_array();
+        let (i, j, k) = (x.floor() as i64, y.floor() as i64, z.floor() as i64);
+        let (u, v, w) = (x - x.floor(), y - y.floor(), z - z.floor());
+        // エルミート補間
+        let (uu, vv, ww) = (u * u * (3.0 - 2.0 * u), v * v * (3.0 - 2.0 * v), w * w * (3.0 - 2.0 * w));
+        let mut acc = 0.0;
+        for di in 0..2 {
+            for dj in 0..2 {
+                for dk in 0..2 {
+                    let (fi, fj, fk) = (di as f64, dj as f64, dk as f64);
+                    let weight = Vec3::new(u - fi, v - fj, w - fk);
+                    let grad = self.ranvec[self.hash(i + di, j + dj, k + dk)];
+                    acc += (fi * uu + (1.0 - fi) * (1.0 - uu))
+                        * (fj * vv + (1.0 - fj) * (1.0 - vv))
+                        * (fk * ww + (1.0 - fk) * (1.0 - ww))
+                        * grad.dot(weight);
+                }
+            }
+        }
+        acc
     }
-}
 
-// 材質，Sync，Send継承
-trait Material: Sync + Send {
-    fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo>;
-    // 発光色を返す．照明．
-    fn emitted(&self, _ray: &Ray, _hit: &HitInfo) -> Color { Color::zero() }
-    fn scattering_pdf(&self, _ray: &Ray, _hit: &HitInfo) -> f64 { 0.0 }
+    // 3次元シンプレックスノイズ．値域はおおよそ[-1, 1]
+    fn simplex(&self, p: Point3) -> f64 {
+        const F3: f64 = 1.0 / 3.0;
+        const G3: f64 = 1.0 / 6.0;
+        let s = p.iter().sum::<f64>() * F3;
+        let cell = p.iter().map(|x| (x + s).floor()).collect::<Vec3>();
+        let t = cell.iter().sum::<f64>() * G3;
+        let x0 = p - (cell - Vec3::full(t));
+        // どの単体に入っているかを座標の大小で決める
+        let [x, y, z] = x0.to_array();
+        let (o1, o2) = if x >= y {
+            if y >= z {
+                (Vec3::xaxis(), Vec3::new(1.0, 1.0, 0.0))
+            } else if x >= z {
+                (Vec3::xaxis(), Vec3::new(1.0, 0.0, 1.0))
+            } else {
+                (Vec3::zaxis(), Vec3::new(1.0, 0.0, 1.0))
+            }
+        } else if y < z {
+            (Vec3::zaxis(), Vec3::new(0.0, 1.0, 1.0))
+        } else if x < z {
+            (Vec3::yaxis(), Vec3::new(0.0, 1.0, 1.0))
+        } else {
+            (Vec3::yaxis(), Vec3::new(1.0, 1.0, 0.0))
+        };
+        let corners = [
+            (Vec3::zero(), x0),
+            (o1, x0 - o1 + Vec3::full(G3)),
+            (o2, x0 - o2 + Vec3::full(2.0 * G3)),
+            (Vec3::one(), x0 - Vec3::one() + Vec3::full(3.0 * G3)),
+        ];
+        let [ci, cj, ck] = cell.to_array();
+        32.0 * corners.iter().fold(0.0, |acc, (o, d)| {
+            let t = 0.6 - d.length_squared();
+            if t > 0.0 {
+                let grad = self.ranvec[self.hash((ci + o.x()) as i64, (cj + o.y()) as i64, (ck + o.z()) as i64)];
+                acc + t.powi(4) * grad.dot(*d)
+            } else {
+                acc
+            }
+        })
+    }
+
+    // 乱流(fBm)．オクターブごとに周波数を2倍，振幅を半分にして足す．
+    fn turb(&self, p: Point3, depth: usize) -> f64 {
+        let mut acc = 0.0;
+        let mut temp_p = p;
+        let mut weight = 1.0;
+        for _ in 0..depth {
+            acc += weight * self.noise(temp_p);
+            weight *= 0.5;
+            temp_p *= 2.0;
+        }
+        acc.abs()
+    }
+
+    // ボロノイ(Worley)ノイズ．最も近い特徴点までの距離
+    fn worley(&self, p: Point3) -> f64 {
+        let [x, y, z] = p.to_array();
+        let (i, j, k) = (x.floor() as i64, y.floor() as i64, z.floor() as i64);
+        let mut dist = f64::MAX;
+        for di in -1..=1 {
+            for dj in -1..=1 {
+                for dk in -1..=1 {
+                    let jitter = self.ranvec[self.hash(i + di, j + dj, k + dk)] * 0.5 + Vec3::full(0.5);
+                    let feature = Point3::new((i + di) as f64, (j + dj) as f64, (k + dk) as f64) + jitter;
+                    dist = dist.min((feature - p).length());
+                }
+            }
+        }
+        dist.min(1.0)
+    }
 }
 
-// ランバート反射，わからなくなったら調べる．
-struct Lambertian {
-    albedo: Box<dyn Texture>,
-    pdf: Arc<dyn Pdf>,
+// ノイズの種類
+enum NoiseType {
+    Perlin,
+    Simplex,
+    Turbulence(usize),
+    Worley,
 }
 
-impl Lambertian {
-    fn new(albedo: Box<dyn Texture>) -> Self {
-        Self { albedo, pdf: Arc::new(CosinePdf::new()) }
-    }
+// ノイズテクスチャ．ノイズの値で2色を補間する．
+struct NoiseTexture {
+    perlin: Perlin,
+    kind: NoiseType,
+    colors: [Color; 2],
+    freq: f64,
 }
 
-impl Material for Lambertian {
-    fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
-        let albedo = self.albedo.value(hit.u, hit.v, hit.p);
-        Some(ScatterInfo::new(*ray, albedo, Some(Arc::clone(&self.pdf))))
+impl NoiseTexture {
+    fn new(kind: NoiseType, color0: Color, color1: Color, freq: f64) -> Self {
+        Self { perlin: Perlin::new(), kind, colors: [color0, color1], freq }
     }
+}
 
-    fn scattering_pdf(&self, ray: &Ray, hit: &HitInfo) -> f64 {
-        ray.direction.normalize().dot(hit.n).max(0.0) * FRAC_1_PI
+impl Texture for NoiseTexture {
+    fn value(&self, _u: f64, _v: f64, p: Point3) -> Color {
+        let p = p * self.freq;
+        let t = match self.kind {
+            NoiseType::Perlin => 0.5 * (1.0 + self.perlin.noise(p)),
+            NoiseType::Simplex => 0.5 * (1.0 + self.perlin.simplex(p)),
+            NoiseType::Turbulence(depth) => self.perlin.turb(p, depth),
+            NoiseType::Worley => self.perlin.worley(p),
+        };
+        self.colors[0].lerp(self.colors[1], t.clamp(0.0, 1.0))
     }
 }
 
-// 鏡面反射する材質
-struct Metal {
-    albedo: Box<dyn Texture>,
-    fuzz: f64,
+// 大理石模様．縞を乱流で揺らす．
+struct MarbleTexture {
+    perlin: Perlin,
+    colors: [Color; 2],
+    freq: f64,
+    turbulence: f64,
 }
 
-impl Metal {
-    fn new(albedo: Box<dyn Texture>, fuzz: f64) -> Self {
-        Self { albedo, fuzz }
+impl MarbleTexture {
+    fn new(color0: Color, color1: Color, freq: f64, turbulence: f64) -> Self {
+        Self { perlin: Perlin::new(), colors: [color0, color1], freq, turbulence }
     }
 }
 
-impl Material for Metal {
-    fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
-        let mut reflected = ray.direction.normalize().reflect(hit.n);
-        reflected = reflected + self.fuzz * Vec3::random_in_unit_sphere();
-        if reflected.dot(hit.n) > 0.0 {
-            let albedo = self.albedo.value(hit.u, hit.v, hit.p);
-            Some(ScatterInfo::new(Ray::new(hit.p, reflected), albedo, None))
-        } else {
-            None
-        }
+impl Texture for MarbleTexture {
+    fn value(&self, _u: f64, _v: f64, p: Point3) -> Color {
+        let t = 0.5 * (1.0 + (self.freq * p.z() + self.turbulence * self.perlin.turb(p, 7)).sin());
+        self.colors[0].lerp(self.colors[1], t)
     }
 }
 
-// 誘導体媒質
-struct Dielectric {
-    ri: f64,
+// 木目．y軸周りの年輪を乱流で揺らす．
+struct WoodTexture {
+    perlin: Perlin,
+    colors: [Color; 2],
+    freq: f64,
+    turbulence: f64,
 }
 
-impl Dielectric {
-    const fn new(ri: f64) -> Self {
-        Self { ri }
+impl WoodTexture {
+    fn new(color0: Color, color1: Color, freq: f64, turbulence: f64) -> Self {
+        Self { perlin: Perlin::new(), colors: [color0, color1], freq, turbulence }
     }
-    
-    fn schlick(cosine: f64, ri: f64) -> f64 {
-        let r0 = ((1.0 - ri) / ( 1.0 + ri )).powi(2);
-        r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
+}
+
+impl Texture for WoodTexture {
+    fn value(&self, _u: f64, _v: f64, p: Point3) -> Color {
+        let r = (p.x().powi(2) + p.z().powi(2)).sqrt();
+        let rings = self.freq * r + self.turbulence * self.perlin.turb(p, 4);
+        self.colors[0].lerp(self.colors[1], rings - rings.floor())
     }
 }
 
-impl Material for Dielectric {
-    fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
-        let reflected = ray.direction.reflect(hit.n);
-        let (outward_normal, ni_over_nt, cosine) = {
-            let dot = ray.direction.dot(hit.n);
-            if dot > 0.0 {
-                (-hit.n, self.ri, self.ri * dot / ray.direction.length())
-            } else {
-                (hit.n, self.ri.recip(), -dot / ray.direction.length())
-            }
-        };
+// 画像テクスチャの補間方法
+#[derive(Clone, Copy)]
+enum TextureFilter {
+    Nearest,
+    Bilinear,
+    Bicubic,
+}
 
-        if let Some(refracted) = (-ray.direction).refract(outward_normal, ni_over_nt) {
-            if Vec3::random_full().x() > Self::schlick(cosine, self.ri) {
-                return Some(ScatterInfo::new(Ray::new(hit.p, refracted), Color::one(), None));
+// 範囲外のテクスチャ座標の扱い
+#[derive(Clone, Copy)]
+enum WrapMode {
+    Repeat,
+    Mirror,
+    Clamp,
+    Border(Color),
+}
+
+impl WrapMode {
+    // 画素の座標を範囲内に収める．Borderで範囲外ならNone．
+    fn apply(&self, i: i64, n: usize) -> Option<usize> {
+        let n = n as i64;
+        match self {
+            WrapMode::Repeat => Some(i.rem_euclid(n) as usize),
+            WrapMode::Mirror => {
+                let m = i.rem_euclid(2 * n);
+                Some(if m < n { m } else { 2 * n - 1 - m } as usize)
             }
+            WrapMode::Clamp => Some(i.clamp(0, n - 1) as usize),
+            WrapMode::Border(_) => if 0 <= i && i < n { Some(i as usize) } else { None },
         }
-
-        Some(ScatterInfo::new(Ray::new(hit.p, reflected), Color::one(), None))
     }
 }
 
-struct DiffuseLight {
-    emit: Box<dyn Texture>,
+// 画像の色空間
+#[derive(Clone, Copy, PartialEq)]
+enum ColorSpace {
+    // 色画像．sRGBの伝達関数を外してリニアにする．
+    Srgb,
+    // すでにリニアな色画像
+    Linear,
+    // 法線マップや粗さマップなど色ではないデータ．変換しない．
+    Data,
 }
 
-impl DiffuseLight {
-    fn new(emit: Box<dyn Texture>) -> Self {
-        Self { emit }
-    }
-}
-
-impl Material for DiffuseLight {
-    // 散乱させるわけではないので none
-    fn scatter(&self, _ray: &Ray, _hit: &HitInfo) -> Option<ScatterInfo> {
-        None
-    }
+// ミップマップの1段．画素値と不透明度を持つ．
+struct MipLevel {
+    pixels: Vec<Color>,
+    alphas: Vec<f64>,
+    width: usize,
+    height: usize,
+}
 
-    fn emitted(&self, ray: &Ray, hit: &HitInfo) -> Color {
-        if ray.direction.dot(hit.n) < 0.0 {
-            self.emit.value(hit.u, hit.v, hit.p)
-        } else {
-            Color::zero()
+impl MipLevel {
+    // 2x2画素を平均して半分の解像度にする
+    fn downsample(&self) -> Self {
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+        let mut pixels = Vec::with_capacity(width * height);
+        let mut alphas = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let mut color = Color::zero();
+                let mut alpha = 0.0;
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let sx = (2 * x + dx).min(self.width - 1);
+                    let sy = (2 * y + dy).min(self.height - 1);
+                    color += self.pixels[sx + self.width * sy];
+                    alpha += self.alphas[sx + self.width * sy];
+                }
+                pixels.push(color * 0.25);
+                alphas.push(alpha * 0.25);
+            }
         }
+        Self { pixels, alphas, width, height }
     }
 }
 
-// 当たり判定
-
-struct HitInfo {
-    t: f64,
-    p: Point3,
-    n: Vec3,
-    m: Arc<dyn Material>,
-    u: f64,
-    v: f64,
+// 画像テクスチャ
+// アルファチャンネルも保持する．画素値はリニアで持つ．
+// 読み込み時にミップマップを作り，光線微分から求めたフィルタ幅で段を選ぶ．
+struct ImageTexture {
+    levels: Vec<MipLevel>,
+    filter: TextureFilter,
+    wrap: [WrapMode; 2],
 }
 
-impl HitInfo {
-    fn new(t: f64, p: Point3, n:Vec3, m: Arc<dyn Material>, u: f64, v: f64) -> Self {
-        Self { t, p, n, m, u, v }
+impl ImageTexture {
+    fn new(path: &str) -> Self {
+        Self::load(path, ColorSpace::Srgb)
     }
-}
 
-// 物体トレイト．Syncトレイト継承．
-trait Shape: Send + Sync {
-    // 衝突関数．t0とt1は光線の衝突範囲．
-    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo>;
-    fn pdf_value(&self, _o: Vec3, _v: Vec3) -> f64 { 0.0 }
-    fn random(&self, _o: Vec3) -> Vec3 { Vec3::xaxis() }
-}
-
-struct Sphere{
-    center: Point3,
-    radius: f64,
-    material: Arc<dyn Material>,
-}
+    // .hdr, .exr, .pfmは浮動小数点のまま読む．HDR画像は常にリニアとして扱う．
+    fn load(path: &str, space: ColorSpace) -> Self {
+        if is_hdr_path(path) {
+            Self::from_float(read_float_image(path).unwrap())
+        } else {
+            Self::from_rgba(image::open(path).unwrap().to_rgba8(), space)
+        }
+    }
 
-impl Sphere {
-    fn new(center: Point3, radius: f64, material: Arc<dyn Material>) -> Self {
-        Self { center, radius, material }
+    fn from_float(image: FloatImage) -> Self {
+        let FloatImage { width, height, pixels, alphas } = image;
+        Self::from_level(MipLevel { pixels, alphas, width, height })
     }
 
-    // 極座標上の位置を指定するイメージ
-    // 位置から球状マッピングしたテクスチャ座標を取得するコード
-    fn uv(p: Point3) -> (f64, f64) {
-        let phi = p.z().atan2(p.x());
-        let theta = p.y().asin();
-        (1.0 - (phi + PI) / (2.0 * PI), (theta + PI / 2.0) / PI) 
+    fn from_rgba(rgbaimg: image::RgbaImage, space: ColorSpace) -> Self {
+        let (w, h) = rgbaimg.dimensions();
+        let mut image = vec![Color::zero(); (w * h) as usize];
+        let mut alphas = vec![1.0; (w * h) as usize];
+        for ((i, a), (_, _, pixel)) in image.iter_mut().zip(alphas.iter_mut()).zip(rgbaimg.enumerate_pixels()) {
+            let color = Color::from_rgb(pixel[0], pixel[1], pixel[2]);
+            *i = if space == ColorSpace::Srgb { color.srgb_to_linear() } else { color };
+            // アルファは常にリニア
+            *a = pixel[3] as f64 / 255.0;
+        }
+        Self::from_level(MipLevel { pixels: image, alphas, width: w as usize, height: h as usize })
     }
-}
 
-impl Shape for Sphere {
-    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
-        let oc = ray.origin - self.center;
-        let a = ray.direction.dot(ray.direction);
-        let b = 2.0 * ray.direction.dot(oc);
-        let c = oc.dot(oc) - self.radius.powi(2);
-        let d = b * b - 4.0 * a * c;
-        if d > 0.0 {
-            let root = d.sqrt();
-            let temp = (-b - root) / (2.0 * a);
-            if t0 < temp && temp < t1 {
-                let p = ray.at(temp);
-                let n = (p - self.center) / self.radius;
-                let (u, v) = Self::uv(n);
-                return Some(HitInfo::new(temp, p, n, Arc::clone(&self.material), u, v));
-            }
-            let temp = (-b + root) / (2.0 * a);
-            if t0 < temp && temp < t1 {
-                let p = ray.at(temp);
-                let n = (p - self.center) / self.radius;
-                let (u, v) = Self::uv(n);
-                return Some(HitInfo::new(temp, p, n, Arc::clone(&self.material), u, v));
+    fn from_level(base: MipLevel) -> Self {
+        let mut levels = vec![base];
+        loop {
+            let last = levels.last().unwrap();
+            if last.width == 1 && last.height == 1 {
+                break;
             }
+            let next = last.downsample();
+            levels.push(next);
         }
+        Self { levels, filter: TextureFilter::Nearest, wrap: [WrapMode::Clamp; 2] }
+    }
 
-        None
+    fn filter(mut self, filter: TextureFilter) -> Self {
+        self.filter = filter;
+        self
     }
-}
 
-enum RectAxisType {
-    XY,
-    XZ,
-    YZ,
-}
+    fn wrap(mut self, wrap_u: WrapMode, wrap_v: WrapMode) -> Self {
+        self.wrap = [wrap_u, wrap_v];
+        self
+    }
 
-struct Rect {
-    x0: f64,
-    x1: f64,
-    y0: f64,
-    y1: f64,
-    k: f64,
-    axis: RectAxisType,
-    material: Arc<dyn Material>,
-}
+    // level段目の画素(x, y)の色と不透明度．範囲外はラップモードに従う．
+    fn fetch(&self, level: usize, x: i64, y: i64) -> (Color, f64) {
+        let mip = &self.levels[level];
+        match (self.wrap[0].apply(x, mip.width), self.wrap[1].apply(y, mip.height)) {
+            (Some(tu), Some(tv)) => {
+                let i = tu + mip.width * tv;
+                (mip.pixels[i], mip.alphas[i])
+            }
+            _ => {
+                let border = match self.wrap {
+                    [WrapMode::Border(c), _] | [_, WrapMode::Border(c)] => c,
+                    _ => Color::zero(),
+                };
+                (border, 1.0)
+            }
+        }
+    }
 
-impl Rect {
-    fn new(x0: f64, x1: f64, y0: f64, y1: f64, k: f64, axis: RectAxisType, material: Arc<dyn Material>) -> Self {
-        Self { x0, x1, y0, y1, k, axis, material }
+    // テクスチャ座標(u, v)での色と不透明度
+    fn lookup(&self, u: f64, v: f64) -> (Color, f64) {
+        self.lookup_level(0, u, v)
     }
-}
 
-impl Shape for Rect {
-    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
-        let mut origin = ray.origin;
-        let mut direction = ray.direction;
-        let mut axis = Vec3::zaxis();
-        match self.axis {
-            RectAxisType::XY => {}
-            RectAxisType::XZ => {
-                origin = Point3::new(origin.x(), origin.z(), origin.y());
-                direction = Vec3::new(direction.x(), direction.z(), direction.y());
-                axis = Vec3::yaxis();
+    // 段の間を線形補間する(トライリニア)．lodは小数の段番号．
+    fn lookup_lod(&self, u: f64, v: f64, lod: f64) -> (Color, f64) {
+        let lod = lod.clamp(0.0, (self.levels.len() - 1) as f64);
+        let l0 = lod.floor() as usize;
+        let l1 = (l0 + 1).min(self.levels.len() - 1);
+        let t = lod - l0 as f64;
+        let (c0, a0) = self.lookup_level(l0, u, v);
+        if t == 0.0 {
+            return (c0, a0);
+        }
+        let (c1, a1) = self.lookup_level(l1, u, v);
+        (c0.lerp(c1, t), a0 + (a1 - a0) * t)
+    }
+
+    fn lookup_level(&self, level: usize, u: f64, v: f64) -> (Color, f64) {
+        let mip = &self.levels[level];
+        let x = u * mip.width as f64;
+        let y = (1.0 - v) * mip.height as f64;
+        match self.filter {
+            TextureFilter::Nearest => self.fetch(level, x.floor() as i64, y.floor() as i64),
+            TextureFilter::Bilinear => {
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (x0, y0) = (x.floor(), y.floor());
+                let (fx, fy) = (x - x0, y - y0);
+                self.weighted(level, x0 as i64, y0 as i64, &[1.0 - fx, fx], &[1.0 - fy, fy])
             }
-            RectAxisType::YZ => {
-                origin = Point3::new(origin.y(), origin.z(), origin.x());
-                direction = Vec3::new(direction.y(), direction.z(), direction.x());
-                axis = Vec3::xaxis();
+            TextureFilter::Bicubic => {
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (x0, y0) = (x.floor(), y.floor());
+                let (wx, wy) = (Self::catmull_rom(x - x0), Self::catmull_rom(y - y0));
+                let (c, a) = self.weighted(level, x0 as i64 - 1, y0 as i64 - 1, &wx, &wy);
+                (c.iter().map(|x| x.max(0.0)).collect(), a.clamp(0.0, 1.0))
             }
         }
+    }
 
-        let t = (self.k - origin.z()) / direction.z();
-        if t < t0 || t > t1 {
-            return None;
+    // (x0, y0)から始まる画素を重み付きで足し合わせる
+    fn weighted(&self, level: usize, x0: i64, y0: i64, wx: &[f64], wy: &[f64]) -> (Color, f64) {
+        let mut color = Color::zero();
+        let mut alpha = 0.0;
+        for (j, wj) in wy.iter().enumerate() {
+            for (i, wi) in wx.iter().enumerate() {
+                let (c, a) = self.fetch(level, x0 + i as i64, y0 + j as i64);
+                color += c * (wi * wj);
+                alpha += a * wi * wj;
+            }
         }
+        (color, alpha)
+    }
 
-        let x = origin.x() + t * direction.x();
-        let y = origin.y() + t * direction.y();
-        if x < self.x0 || x>self.x1 || y < self.y0 || y > self.y1 {
-            return None;
-        }
+    // Catmull-Romスプラインの4点の重み
+    fn catmull_rom(t: f64) -> [f64; 4] {
+        let (t2, t3) = (t * t, t * t * t);
+        [
+            0.5 * (-t3 + 2.0 * t2 - t),
+            0.5 * (3.0 * t3 - 5.0 * t2 + 2.0),
+            0.5 * (-3.0 * t3 + 4.0 * t2 + t),
+            0.5 * (t3 - t2),
+        ]
+    }
 
-        Some(HitInfo::new(
-            t,
-            ray.at(t),
-            axis,
-            Arc::clone(&self.material),
-            (x - self.x0) / (self.x1 - self.x0),
-            (y - self.y0) / (self.y1 - self.y0),
-        ))
+    // フィルタ幅(テクスチャ座標の差)からミップマップの段を選ぶ
+    fn lod(&self, duv: &[f64; 4]) -> f64 {
+        let (w, h) = (self.levels[0].width as f64, self.levels[0].height as f64);
+        let [dudx, dvdx, dudy, dvdy] = *duv;
+        let width = (dudx.abs() * w).max(dvdx.abs() * h).max(dudy.abs() * w).max(dvdy.abs() * h);
+        if width > 1.0 { width.log2() } else { 0.0 }
     }
+}
 
-    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
-        if let Some(hit) = self.hit(&Ray::new(o, v), 0.001, f64::MAX) {
-            let area = (self.x1 - self.x0) * (self.y1 - self.y0);
-            let distance_squared = hit.t.powi(2) * v.length_squared();
-            let cosine = v.dot(hit.n).abs() / v.length();
-            distance_squared / (cosine * area)
-        } else {
-            0.0
-        }
+impl Texture for ImageTexture {
+    fn value(&self, u: f64, v: f64, _p: Point3) -> Color {
+        self.lookup(u, v).0
     }
 
-    fn random(&self, o: Vec3) -> Vec3 {
-        let [rx, ry, _] = Vec3::random().to_array();
-        let x = self.x0 + rx * (self.x1 - self.x0);
-        let y = self.y0 + ry * (self.y1 - self.y0);
-        match self.axis {
-            RectAxisType::XY => Point3::new(x, y, self.k) - o,
-            RectAxisType::XZ => Point3::new(x, self.k, y) - o,
-            RectAxisType::YZ => Point3::new(self.k, x, y) - o,
-        }
+    fn alpha(&self, u: f64, v: f64, _p: Point3) -> f64 {
+        self.lookup(u, v).1
+    }
+
+    fn evaluate(&self, hit: &HitInfo) -> Color {
+        self.lookup_lod(hit.u, hit.v, self.lod(&hit.duv)).0
     }
 }
 
-struct Box3D {
-    p0: Point3,
-    p1: Point3,
-    shapes: ShapeList,
+// 材質，Sync，Send継承
+trait Material: Sync + Send {
+    fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo>;
+    // 発光色を返す．照明．
+    fn emitted(&self, _ray: &Ray, _hit: &HitInfo) -> Color { Color::zero() }
+    // 散乱方向scatteredに対するBRDFとcosの積．rayは入射光線．
+    fn scattering_pdf(&self, _ray: &Ray, _hit: &HitInfo, _scattered: &Ray) -> f64 { 0.0 }
+    // 光源の明るさの目安となる放射輝度．光源を選ぶ確率の重みに使う．
+    fn emittance(&self) -> Color { Color::zero() }
+    // 複数の材質を混ぜる場合，この衝突で使う材質を一つ選ぶ．
+    fn select(&self, _hit: &HitInfo) -> Option<Arc<dyn Material>> { None }
+    // 表面で反射しなかった光の散乱．薄膜の下の母材に使う．表面の反射率を持つ材質は
+    // その分を除いた散乱を返し，それ以外は散乱全体を返す．
+    fn scatter_transmitted(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> { self.scatter(ray, hit) }
 }
 
-impl Box3D {
-    fn new(p0: Point3, p1: Point3, material: Arc<dyn Material>) -> Self {
-        let mut shapes = ShapeList::new();
-        shapes.push(ShapeBuilder::new()
-            .material(Arc::clone(&material))
-            .rect_xy(p0.x(), p1.x(), p0.y(), p1.y(), p1.z())
-            .build());
-        shapes.push(ShapeBuilder::new()
-            .material(Arc::clone(&material))
-            .rect_xy(p0.x(), p1.x(), p0.y(), p1.y(), p0.z())
-            .flip_face()
-            .build());
-        shapes.push(ShapeBuilder::new()
-            .material(Arc::clone(&material))
-            .rect_xz(p0.x(), p1.x(), p0.z(), p1.z(), p1.y())
-            .build());
-        shapes.push(ShapeBuilder::new()
-            .material(Arc::clone(&material))
-            .rect_xz(p0.x(), p1.x(), p0.z(), p1.z(), p0.y())
-            .flip_face()
-            .build());
-        shapes.push(ShapeBuilder::new()
-            .material(Arc::clone(&material))
-            .rect_yz(p0.y(), p1.y(), p0.z(), p1.z(), p1.x())
-            .build());
-        shapes.push(ShapeBuilder::new()
-            .material(Arc::clone(&material))
-            .rect_yz(p0.y(), p1.y(), p0.z(), p1.z(), p0.x())
-            .flip_face()
-            .build());
-        
-        Self { p0, p1, shapes }
-    }
+// ランバート反射，わからなくなったら調べる．
+struct Lambertian {
+    albedo: Box<dyn Texture>,
+    pdf: Arc<dyn Pdf>,
 }
 
-impl Shape for Box3D {
-    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
-        self.shapes.hit(ray, t0, t1)
+impl Lambertian {
+    fn new(albedo: Box<dyn Texture>) -> Self {
+        Self { albedo, pdf: Arc::new(CosinePdf::new()) }
     }
 }
 
-// Decorators
+impl Material for Lambertian {
+    fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
+        let albedo = self.albedo.evaluate(hit);
+        Some(ScatterInfo::new(*ray, albedo, Some(Arc::clone(&self.pdf)), Lobe::Diffuse))
+    }
 
-struct FlipFace {
-    shape: Box<dyn Shape>,
+    fn scattering_pdf(&self, _ray: &Ray, hit: &HitInfo, scattered: &Ray) -> f64 {
+        scattered.direction.normalize().dot(hit.n).max(0.0) * FRAC_1_PI
+    }
 }
 
-impl FlipFace {
-    fn new(shape: Box<dyn Shape>) -> Self {
-        Self { shape }
+// Oren-Nayarの粗い拡散反射．sigmaは表面の傾きの標準偏差(度)．
+// sigmaが0のときはランバート反射と一致する．
+struct OrenNayar {
+    albedo: Box<dyn Texture>,
+    a: f64,
+    b: f64,
+    pdf: Arc<dyn Pdf>,
+}
+
+impl OrenNayar {
+    fn new(albedo: Box<dyn Texture>, sigma: f64) -> Self {
+        let sigma2 = sigma.to_radians().powi(2);
+        let a = 1.0 - sigma2 / (2.0 * (sigma2 + 0.33));
+        let b = 0.45 * sigma2 / (sigma2 + 0.09);
+        Self { albedo, a, b, pdf: Arc::new(CosinePdf::new()) }
     }
 }
 
-impl Shape for FlipFace {
-    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
-        if let Some(hit) = self.shape.hit(ray, t0, t1) {
-            Some(HitInfo { n: -hit.n, ..hit })
-        } else {
-            None
+impl Material for OrenNayar {
+    fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
+        let albedo = self.albedo.evaluate(hit);
+        Some(ScatterInfo::new(*ray, albedo, Some(Arc::clone(&self.pdf)), Lobe::Diffuse))
+    }
+
+    fn scattering_pdf(&self, ray: &Ray, hit: &HitInfo, scattered: &Ray) -> f64 {
+        let wi = -ray.direction.normalize();
+        let wo = scattered.direction.normalize();
+        let cos_i = wi.dot(hit.n);
+        let cos_o = wo.dot(hit.n);
+        if cos_o <= 0.0 {
+            return 0.0;
         }
+        let sin_i = (1.0 - cos_i * cos_i).max(0.0).sqrt();
+        let sin_o = (1.0 - cos_o * cos_o).max(0.0).sqrt();
+        // 接平面に射影した2方向のなす角のcos
+        let max_cos = if sin_i > 1e-4 && sin_o > 1e-4 {
+            ((wi - hit.n * cos_i) / sin_i).dot((wo - hit.n * cos_o) / sin_o).max(0.0)
+        } else {
+            0.0
+        };
+        let (sin_alpha, tan_beta) = if cos_i.abs() > cos_o.abs() {
+            (sin_o, sin_i / cos_i.abs())
+        } else {
+            (sin_i, sin_o / cos_o.abs())
+        };
+        (self.a + self.b * max_cos * sin_alpha * tan_beta) * FRAC_1_PI * cos_o
     }
 }
 
-struct Translate {
-    shape: Box<dyn Shape>,
-    offset: Point3,
+// 再帰反射．入射してきた方向へ光を返す．道路標識など．
+// exponentが大きいほど返る光のローブが鋭くなる．
+struct RetroReflector {
+    albedo: Box<dyn Texture>,
+    exponent: f64,
 }
 
-impl Translate {
-    fn new(shape: Box<dyn Shape>, offset: Point3) -> Self {
-        Self { shape, offset }
+impl RetroReflector {
+    fn new(albedo: Box<dyn Texture>, exponent: f64) -> Self {
+        Self { albedo, exponent }
     }
 }
 
-impl Shape for Translate {
-    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
-        let moved_ray = Ray::new(ray.origin - self.offset, ray.direction);
-        if let Some(hit) = self.shape.hit(&moved_ray, t0, t1) {
-            Some(HitInfo { p: hit.p + self.offset, ..hit })
-        } else {
-            None
+impl Material for RetroReflector {
+    fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
+        let albedo = self.albedo.evaluate(hit);
+        let pdf = Arc::new(PhongPdf::new(-ray.direction, self.exponent));
+        Some(ScatterInfo::new(*ray, albedo, Some(pdf), Lobe::Glossy))
+    }
+
+    fn scattering_pdf(&self, ray: &Ray, hit: &HitInfo, scattered: &Ray) -> f64 {
+        if scattered.direction.dot(hit.n) <= 0.0 {
+            return 0.0;
         }
+        PhongPdf::new(-ray.direction, self.exponent).value(hit, scattered.direction)
     }
 }
 
-struct Rotate {
-    shape: Box<dyn Shape>,
-    quat: Quat,
+// 鏡面反射する材質
+struct Metal {
+    albedo: Box<dyn Texture>,
+    fuzz: f64,
 }
 
-impl Rotate {
-    fn new(shape: Box<dyn Shape>, axis: Vec3, angle: f64) -> Self {
-        Self { shape, quat: Quat::from_rot(axis, angle.to_radians()) }
+impl Metal {
+    fn new(albedo: Box<dyn Texture>, fuzz: f64) -> Self {
+        Self { albedo, fuzz }
     }
 }
 
-impl Shape for Rotate {
-    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
-        let revq = self.quat.conj();
-        let rotated_ray = Ray::new(revq.rotate(ray.origin), revq.rotate(ray.direction));
-        if let Some(hit) = self.shape.hit(&rotated_ray, t0, t1) {
-            Some(HitInfo { p: self.quat.rotate(hit.p), n: self.quat.rotate(hit.n), ..hit })
+impl Material for Metal {
+    fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
+        let reflected = ray.direction.normalize().reflect(hit.n);
+        let albedo = self.albedo.evaluate(hit);
+        if self.fuzz > 0.0 {
+            let pdf = Arc::new(FuzzPdf::new(reflected, self.fuzz));
+            return Some(ScatterInfo::new(*ray, albedo, Some(pdf), Lobe::Glossy));
+        }
+        if reflected.dot(hit.n) > 0.0 {
+            let scattered = Ray::new(hit.p, reflected).with_differential(hit.reflect_differential(ray));
+            Some(ScatterInfo::new(scattered, albedo, None, Lobe::Specular))
         } else {
             None
         }
     }
+
+    // 面の下に向いた方向は吸収されるので，面の上では反射の分布そのもの
+    fn scattering_pdf(&self, ray: &Ray, hit: &HitInfo, scattered: &Ray) -> f64 {
+        if self.fuzz <= 0.0 || scattered.direction.dot(hit.n) <= 0.0 {
+            return 0.0;
+        }
+        FuzzPdf::new(ray.direction.reflect(hit.n), self.fuzz).value(hit, scattered.direction)
+    }
 }
 
-// 物体リスト．複数物体の管理．
-struct ShapeList {
-    pub objects: Vec<Box<dyn Shape>>,
+// 異方性のある金属．ヘアライン加工のステンレスなど．
+// alpha_xが接ベクトル方向，alpha_yがそれと直交する方向の粗さ．
+struct AnisotropicMetal {
+    albedo: Box<dyn Texture>,
+    alpha_x: f64,
+    alpha_y: f64,
 }
 
-impl ShapeList {
-    pub fn new() -> Self {
-        Self { objects: Vec::new() }
+impl AnisotropicMetal {
+    fn new(albedo: Box<dyn Texture>, alpha_x: f64, alpha_y: f64) -> Self {
+        Self { albedo, alpha_x: alpha_x.max(1e-3), alpha_y: alpha_y.max(1e-3) }
     }
 
-    pub fn push(&mut self, object: Box<dyn Shape>){
-        self.objects.push(object);
+    fn pdf(&self, ray: &Ray, hit: &HitInfo) -> GgxPdf {
+        GgxPdf::new(hit.onb(), -ray.direction, self.alpha_x, self.alpha_y)
     }
 }
 
-impl Shape for ShapeList {
-    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
-        let mut hit_info: Option<HitInfo> = None;
-        let mut closest_so_far = t1;
-        for object in &self.objects {
-            if let Some(info) = object.hit(ray, t0, closest_so_far) {
-                closest_so_far = info.t;
-                hit_info = Some(info);
+impl Material for AnisotropicMetal {
+    fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
+        if ray.direction.dot(hit.n) >= 0.0 {
+            return None;
+        }
+        let albedo = self.albedo.evaluate(hit);
+        Some(ScatterInfo::new(*ray, albedo, Some(Arc::new(self.pdf(ray, hit))), Lobe::Glossy))
+    }
+
+    fn scattering_pdf(&self, ray: &Ray, hit: &HitInfo, scattered: &Ray) -> f64 {
+        self.pdf(ray, hit).brdf_cos(scattered.direction)
+    }
+}
+
+// 誘導体媒質
+struct Dielectric {
+    ri: f64,
+}
+
+impl Dielectric {
+    const fn new(ri: f64) -> Self {
+        Self { ri }
+    }
+    
+    fn schlick(cosine: f64, ri: f64) -> f64 {
+        let r0 = ((1.0 - ri) / ( 1.0 + ri )).powi(2);
+        r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
+    }
+
+    // 振幅反射率(s偏光, p偏光)．cos_tは屈折角のcos．
+    fn fresnel_amplitude(cos_i: f64, cos_t: f64, ni: f64, nt: f64) -> (f64, f64) {
+        let rs = (ni * cos_i - nt * cos_t) / (ni * cos_i + nt * cos_t);
+        let rp = (nt * cos_i - ni * cos_t) / (nt * cos_i + ni * cos_t);
+        (rs, rp)
+    }
+
+    // 厳密なフレネル反射率(非偏光)．全反射なら1．
+    fn fresnel(cos_i: f64, ni: f64, nt: f64) -> f64 {
+        let sin_t = ni / nt * (1.0 - cos_i * cos_i).max(0.0).sqrt();
+        if sin_t >= 1.0 {
+            return 1.0;
+        }
+        let cos_t = (1.0 - sin_t * sin_t).sqrt();
+        let (rs, rp) = Self::fresnel_amplitude(cos_i, cos_t, ni, nt);
+        0.5 * (rs * rs + rp * rp)
+    }
+}
+
+impl Material for Dielectric {
+    fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
+        let reflected = ray.direction.reflect(hit.n);
+        let (outward_normal, ni_over_nt, cosine) = {
+            let dot = ray.direction.dot(hit.n);
+            if dot > 0.0 {
+                (-hit.n, self.ri, self.ri * dot / ray.direction.length())
+            } else {
+                (hit.n, self.ri.recip(), -dot / ray.direction.length())
+            }
+        };
+
+        if let Some(refracted) = (-ray.direction).refract(outward_normal, ni_over_nt) {
+            if Vec3::random_full().x() > Self::schlick(cosine, self.ri) {
+                let differential = hit.refract_differential(ray, outward_normal, ni_over_nt);
+                let scattered = Ray::new(hit.p, refracted).with_differential(differential);
+                return Some(ScatterInfo::new(scattered, Color::one(), None, Lobe::Transmission));
             }
         }
 
-        hit_info
+        let scattered = Ray::new(hit.p, reflected).with_differential(hit.reflect_differential(ray));
+        Some(ScatterInfo::new(scattered, Color::one(), None, Lobe::Specular))
     }
 
-    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
-        if self.objects.is_empty() { panic!(); }
-        let weight = 1.0 / self.objects.len() as f64;
-        self.objects.iter().fold(0.0,|acc, s| acc + weight * s.pdf_value(o, v))
+    // 外から入る光を反射させずに屈折させる．屈折を選ぶ確率(1 - フレネル反射率)で割った重みは1．
+    fn scatter_transmitted(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
+        let ni_over_nt = self.ri.recip();
+        let refracted = (-ray.direction).refract(hit.n, ni_over_nt)?;
+        let differential = hit.refract_differential(ray, hit.n, ni_over_nt);
+        let scattered = Ray::new(hit.p, refracted).with_differential(differential);
+        Some(ScatterInfo::new(scattered, Color::one(), None, Lobe::Transmission))
     }
+}
 
-    fn random(&self, o: Vec3) -> Vec3 {
-        if self.objects.is_empty() { panic!(); }
-        let index = (Vec3::random_full().x() * self.objects.len() as f64).floor() as usize;
-        self.objects[index].random(o)
+// 等色関数の多峰ガウス近似(Wyman et al. 2013)
+fn cie_xyz(lambda: f64) -> Vec3 {
+    let g = |mu: f64, s1: f64, s2: f64| {
+        let s = if lambda < mu { s1 } else { s2 };
+        (-0.5 * ((lambda - mu) / s).powi(2)).exp()
+    };
+    Vec3::new(
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
+
+// 薄膜干渉コーティング．母材の上に厚さthickness(nm)，屈折率film_riの膜を載せる．
+// シャボン玉，油膜，陽極酸化した金属など．反射率は波長ごとに計算してRGBに変換する．
+// 膜を透過した光はbaseで散乱させ，母材の中から出る光もbaseに任せる．
+struct ThinFilm {
+    base: Arc<dyn Material>,
+    thickness: f64,
+    film_ri: f64,
+    base_ri: f64,
+    // (波長, 正規化済みのRGB重み)
+    spectrum: Vec<(f64, Color)>,
+}
+
+impl ThinFilm {
+    const SPECTRAL_SAMPLES: usize = 32;
+
+    fn new(base: Arc<dyn Material>, thickness: f64, film_ri: f64, base_ri: f64) -> Self {
+        let mut spectrum: Vec<(f64, Color)> = (0..Self::SPECTRAL_SAMPLES).map(|i| {
+            let lambda = 380.0 + 350.0 * (i as f64 + 0.5) / Self::SPECTRAL_SAMPLES as f64;
+            (lambda, cie_xyz(lambda).xyz_to_rgb())
+        }).collect();
+        // 平坦な反射率が白になるように正規化する
+        let white = spectrum.iter().fold(Color::zero(), |acc, (_, c)| acc + *c);
+        for (_, c) in spectrum.iter_mut() {
+            *c = c.iter().zip(white.iter()).map(|(x, w)| x / w).collect();
+        }
+        Self { base, thickness, film_ri, base_ri, spectrum }
+    }
+
+    // 空気/膜/母材の3層の多重反射を足し合わせた反射率(Airyの式)
+    fn reflectance(&self, cos_i: f64, lambda: f64) -> f64 {
+        let (n1, n2, n3) = (1.0, self.film_ri, self.base_ri);
+        let sin_i2 = (1.0 - cos_i * cos_i).max(0.0);
+        let sin_2 = n1 / n2 * sin_i2.sqrt();
+        let sin_3 = n1 / n3 * sin_i2.sqrt();
+        if sin_2 >= 1.0 || sin_3 >= 1.0 {
+            return 1.0;
+        }
+        let cos_2 = (1.0 - sin_2 * sin_2).sqrt();
+        let cos_3 = (1.0 - sin_3 * sin_3).sqrt();
+        let (r12s, r12p) = Dielectric::fresnel_amplitude(cos_i, cos_2, n1, n2);
+        let (r23s, r23p) = Dielectric::fresnel_amplitude(cos_2, cos_3, n2, n3);
+        let delta = 4.0 * PI * n2 * self.thickness * cos_2 / lambda;
+        let airy = |r12: f64, r23: f64| {
+            let c = 2.0 * r12 * r23 * delta.cos();
+            (r12 * r12 + r23 * r23 + c) / (1.0 + r12 * r12 * r23 * r23 + c)
+        };
+        0.5 * (airy(r12s, r23s) + airy(r12p, r23p))
+    }
+
+    fn reflectance_rgb(&self, cos_i: f64) -> Color {
+        self.spectrum.iter()
+            .fold(Color::zero(), |acc, (lambda, c)| acc + *c * self.reflectance(cos_i, *lambda))
+            .saturate()
     }
 }
 
-struct ShapeBuilder {
-    texture: Option<Box<dyn Texture>>,
-    material: Option<Arc<dyn Material>>,
-    shape: Option<Box<dyn Shape>>,
+impl Material for ThinFilm {
+    fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
+        let dot = ray.direction.dot(hit.n);
+        if dot > 0.0 {
+            return self.base.scatter(ray, hit);
+        }
+        let cos_i = -dot / ray.direction.length();
+        let reflectance = self.reflectance_rgb(cos_i);
+        let prob = reflectance.iter().sum::<f64>() / 3.0;
+        if Vec3::random_full().x() < prob {
+            let reflected = Ray::new(hit.p, ray.direction.reflect(hit.n))
+                .with_differential(hit.reflect_differential(ray));
+            Some(ScatterInfo::new(reflected, reflectance / prob, None, Lobe::Specular))
+        } else {
+            // 母材の界面での反射は反射率に含まれているので，母材には表面で反射しなかった分だけを
+            // 散乱させる．膜の中の屈折は平行な面なので出ていく向きに影響しない．
+            let transmittance = (Color::one() - reflectance) / (1.0 - prob);
+            self.base.scatter_transmitted(ray, hit).map(|s| ScatterInfo { albedo: s.albedo * transmittance, ..s })
+        }
+    }
+
+    fn emitted(&self, ray: &Ray, hit: &HitInfo) -> Color {
+        self.base.emitted(ray, hit)
+    }
+
+    fn scattering_pdf(&self, ray: &Ray, hit: &HitInfo, scattered: &Ray) -> f64 {
+        self.base.scattering_pdf(ray, hit, scattered)
+    }
 }
 
-impl ShapeBuilder {
-    fn new() -> Self {
-        Self { texture: None, material: None, shape: None }
+// 等方散乱．媒質内部の散乱に使う位相関数．
+struct Isotropic {
+    albedo: Box<dyn Texture>,
+}
+
+impl Isotropic {
+    fn new(albedo: Box<dyn Texture>) -> Self {
+        Self { albedo }
     }
+}
 
-    // テクスチャ
+impl Material for Isotropic {
+    fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
+        let albedo = self.albedo.evaluate(hit);
+        Some(ScatterInfo::new(*ray, albedo, Some(Arc::new(SpherePdf {})), Lobe::Diffuse))
+    }
 
-    fn color_texture(mut self, color: Color) -> Self {
-        self.texture = Some(Box::new(ColorTexture::new(color)));
-        self
+    fn scattering_pdf(&self, _ray: &Ray, _hit: &HitInfo, _scattered: &Ray) -> f64 {
+        0.25 * FRAC_1_PI
     }
+}
 
-    fn checker_texture(mut self, odd_color: Color, even_color: Color, freq: f64) -> Self {
-        self.texture = Some(Box::new(CheckerTexture::new(
-            Box::new(ColorTexture::new(odd_color)),
-            Box::new(ColorTexture::new(even_color)),
-            freq,
-        )));
-        self
+struct DiffuseLight {
+    emit: Box<dyn Texture>,
+}
+
+impl DiffuseLight {
+    fn new(emit: Box<dyn Texture>) -> Self {
+        Self { emit }
     }
+}
 
-    fn image_texture(mut self, path: &str) -> Self {
-        self.texture = Some(Box::new(ImageTexture::new(path)));
-        self
+impl Material for DiffuseLight {
+    // 散乱させるわけではないので none
+    fn scatter(&self, _ray: &Ray, _hit: &HitInfo) -> Option<ScatterInfo> {
+        None
     }
 
-    // 材質
+    fn emitted(&self, ray: &Ray, hit: &HitInfo) -> Color {
+        if ray.direction.dot(hit.n) < 0.0 {
+            self.emit.evaluate(hit)
+        } else {
+            Color::zero()
+        }
+    }
 
-    fn lambertian(mut self) -> Self {
-        self.material = Some(Arc::new(Lambertian::new(self.texture.unwrap())));
-        self.texture = None;
-        self
+    fn emittance(&self) -> Color {
+        self.emit.value(0.5, 0.5, Point3::zero())
     }
+}
 
-    fn metal(mut self, fuzz: f64) -> Self {
-        self.material = Some(Arc::new(Metal::new(self.texture.unwrap(), fuzz)));
-        self.texture = None;
-        self
+// 2つの材質の混合．weightは2つ目の材質を選ぶ確率．
+// 例えば金属の上の錆をテクスチャで指定する．
+struct MixMaterial {
+    materials: [Arc<dyn Material>; 2],
+    weight: Box<dyn Texture>,
+}
+
+impl MixMaterial {
+    fn new(m0: Arc<dyn Material>, m1: Arc<dyn Material>, weight: Box<dyn Texture>) -> Self {
+        Self { materials: [m0, m1], weight }
     }
 
-    fn dielectric(mut self, ri: f64) -> Self {
-        self.material = Some(Arc::new(Dielectric::new(ri)));
-        self
+    fn with_ratio(m0: Arc<dyn Material>, m1: Arc<dyn Material>, ratio: f64) -> Self {
+        Self::new(m0, m1, Box::new(ColorTexture::new(Color::full(ratio))))
     }
 
-    fn diffuse_light(mut self) -> Self {
-        self.material = Some(Arc::new(DiffuseLight::new(self.texture.unwrap())));
-        self.texture = None;
-        self
+    fn weight(&self, hit: &HitInfo) -> f64 {
+        let w = self.weight.evaluate(hit);
+        (w.iter().sum::<f64>() / 3.0).clamp(0.0, 1.0)
     }
+}
 
-    fn material(mut self, material: Arc<dyn Material>) -> Self {
-        self.material = Some(material);
-        self.texture = None;
-        self
+impl Material for MixMaterial {
+    fn scatter(&self, ray: &Ray, hit: &HitInfo) -> Option<ScatterInfo> {
+        self.select(hit).unwrap().scatter(ray, hit)
     }
 
-    // 形状
+    // 直接呼ばれた場合は重みで混ぜた値を返す．
+    fn emitted(&self, ray: &Ray, hit: &HitInfo) -> Color {
+        let w = self.weight(hit);
+        self.materials[0].emitted(ray, hit).lerp(self.materials[1].emitted(ray, hit), w)
+    }
 
-    fn sphere(mut self, center: Point3, radius: f64) -> Self {
-        self.shape = Some(Box::new(Sphere::new(center, radius, self.material.unwrap())));
-        self.material = None;
-        self
+    fn scattering_pdf(&self, ray: &Ray, hit: &HitInfo, scattered: &Ray) -> f64 {
+        let w = self.weight(hit);
+        (1.0 - w) * self.materials[0].scattering_pdf(ray, hit, scattered)
+            + w * self.materials[1].scattering_pdf(ray, hit, scattered)
     }
 
-    fn rect_xy(mut self, x0: f64, x1: f64, y0: f64, y1: f64, k: f64) -> Self {
-        self.shape = Some(Box::new(Rect::new(x0, x1, y0, y1, k, RectAxisType::XY, self.material.unwrap())));
-        self.material = None;
-        self
+    fn emittance(&self) -> Color {
+        let w = (self.weight.value(0.5, 0.5, Point3::zero()).iter().sum::<f64>() / 3.0).clamp(0.0, 1.0);
+        self.materials[0].emittance().lerp(self.materials[1].emittance(), w)
     }
 
-    fn rect_xz(mut self, x0: f64, x1: f64, y0: f64, y1: f64, k: f64) -> Self {
-        self.shape = Some(Box::new(Rect::new(x0, x1, y0, y1, k, RectAxisType::XZ, self.material.unwrap())));
-        self.material = None;
-        self
+    fn select(&self, hit: &HitInfo) -> Option<Arc<dyn Material>> {
+        if Vec3::random_full().x() < self.weight(hit) {
+            Some(Arc::clone(&self.materials[1]))
+        } else {
+            Some(Arc::clone(&self.materials[0]))
+        }
     }
+}
 
-    fn rect_yz(mut self, x0: f64, x1: f64, y0: f64, y1: f64, k: f64) -> Self {
-        self.shape = Some(Box::new(Rect::new(x0, x1, y0, y1, k, RectAxisType::YZ, self.material.unwrap())));
-        self.material = None;
-        self
+// 当たり判定
+
+#[derive(Clone)]
+struct HitInfo {
+    t: f64,
+    p: Point3,
+    n: Vec3,
+    m: Arc<dyn Material>,
+    u: f64,
+    v: f64,
+    // 接ベクトル．異方性材質の向きを決める．
+    tangent: Vec3,
+    // テクスチャ座標に対する位置の偏微分
+    dpdu: Vec3,
+    dpdv: Vec3,
+    // 光線微分から求めた，隣の画素での位置の差とテクスチャ座標の差(du/dx, dv/dx, du/dy, dv/dy)
+    dpdx: Vec3,
+    dpdy: Vec3,
+    duv: [f64; 4],
+}
+
+impl HitInfo {
+    fn new(t: f64, p: Point3, n:Vec3, m: Arc<dyn Material>, u: f64, v: f64) -> Self {
+        let tangent = ONB::new(n).u();
+        Self {
+            t, p, n, m, u, v, tangent,
+            dpdu: Vec3::zero(),
+            dpdv: Vec3::zero(),
+            dpdx: Vec3::zero(),
+            dpdy: Vec3::zero(),
+            duv: [0.0; 4],
+        }
     }
-    fn box3d(mut self, p0: Point3, p1: Point3) -> Self {
-        self.shape = Some(Box::new(Box3D::new(p0, p1, self.material.unwrap())));
-        self.material = None;
-        self
+
+    fn with_tangent(self, tangent: Vec3) -> Self {
+        Self { tangent, ..self }
     }
 
-    // decorators
+    fn with_dpduv(self, dpdu: Vec3, dpdv: Vec3) -> Self {
+        Self { dpdu, dpdv, ..self }
+    }
 
-    fn flip_face(mut self) -> Self {
-        self.shape = Some(Box::new(FlipFace::new(self.shape.unwrap())));
+    // 光線微分を接平面と交差させて，隣の画素でのテクスチャ座標の差を求める．
+    fn with_ray_differential(mut self, ray: &Ray) -> Self {
+        let d = match ray.differential {
+            Some(d) => d,
+            None => return self,
+        };
+        let plane = |o: Point3, dir: Vec3| {
+            let t = self.n.dot(self.p - o) / self.n.dot(dir);
+            o + t * dir - self.p
+        };
+        let dpdx = plane(d.rx_origin, d.rx_direction);
+        let dpdy = plane(d.ry_origin, d.ry_direction);
+        if !(dpdx.iter().chain(dpdy.iter()).all(|x| x.is_finite())) {
+            return self;
+        }
+        self.dpdx = dpdx;
+        self.dpdy = dpdy;
+        // dpdu, dpdvで張る平面上で最小二乗法により解く
+        let a = self.dpdu.dot(self.dpdu);
+        let b = self.dpdu.dot(self.dpdv);
+        let c = self.dpdv.dot(self.dpdv);
+        let det = a * c - b * b;
+        if det.abs() < 1e-12 {
+            return self;
+        }
+        let solve = |dp: Vec3| {
+            let (pu, pv) = (self.dpdu.dot(dp), self.dpdv.dot(dp));
+            ((c * pu - b * pv) / det, (a * pv - b * pu) / det)
+        };
+        let (dudx, dvdx) = solve(dpdx);
+        let (dudy, dvdy) = solve(dpdy);
+        self.duv = [dudx, dvdx, dudy, dvdy];
         self
     }
 
-    fn translate(mut self, offset: Point3) -> Self {
-        self.shape = Some(Box::new(Translate::new(self.shape.unwrap(), offset)));
-        self
+    // 鏡面反射した光線の微分．法線の変化は無視する．
+    fn reflect_differential(&self, ray: &Ray) -> Option<RayDifferential> {
+        ray.differential.map(|d| RayDifferential {
+            rx_origin: self.p + self.dpdx,
+            rx_direction: d.rx_direction.reflect(self.n),
+            ry_origin: self.p + self.dpdy,
+            ry_direction: d.ry_direction.reflect(self.n),
+        })
     }
 
-    fn rotate(mut self, axis: Vec3, angle: f64) -> Self {
-        self.shape = Some(Box::new(Rotate::new(self.shape.unwrap(), axis, angle)));
-        self
+    // 屈折した光線の微分．normalとni_over_ntはDielectricと同じもの．
+    fn refract_differential(&self, ray: &Ray, normal: Vec3, ni_over_nt: f64) -> Option<RayDifferential> {
+        let d = ray.differential?;
+        Some(RayDifferential {
+            rx_origin: self.p + self.dpdx,
+            rx_direction: (-d.rx_direction).refract(normal, ni_over_nt)?,
+            ry_origin: self.p + self.dpdy,
+            ry_direction: (-d.ry_direction).refract(normal, ni_over_nt)?,
+        })
     }
 
-    fn build(self) -> Box<dyn Shape> {
-        self.shape.unwrap()
+    // 法線と接ベクトルによる接空間
+    fn onb(&self) -> ONB {
+        ONB::from_tangent(self.n, self.tangent)
     }
-}
 
-// struct SimpleScene {
+    // 混合材質を解決する．放射・散乱・pdfが同じ材質で計算されるように，
+    // 衝突ごとに一度だけ選んでおく．
+    fn resolve(self) -> Self {
+        let mut hit = self;
+        while let Some(m) = hit.m.select(&hit) {
+            hit.m = m;
+        }
+        hit
+    }
+}
+
+// 物体トレイト．Syncトレイト継承．
+trait Shape: Send + Sync {
+    // 衝突関数．t0とt1は光線の衝突範囲．
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo>;
+    fn pdf_value(&self, _o: Vec3, _v: Vec3) -> f64 { 0.0 }
+    fn random(&self, _o: Vec3) -> Vec3 { Vec3::xaxis() }
+    // 放射束(輝度)．0より大きければ光源として光源リストに集める．
+    fn power(&self) -> f64 { 0.0 }
+    // 光源としての範囲と向き．光源の階層構造を作るのに使う．
+    fn light_bounds(&self) -> Option<LightBounds> { None }
+    // 表面積
+    fn area(&self) -> f64 { 0.0 }
+    // 表面上の点を面積について一様に選ぶ．光源から光を出すときに使う．(点, 面積あたりの確率密度)を返す．
+    fn sample_surface(&self) -> Option<(HitInfo, f64)> { None }
+}
+
+struct Sphere{
+    center: Point3,
+    radius: f64,
+    material: Arc<dyn Material>,
+}
+
+impl Sphere {
+    fn new(center: Point3, radius: f64, material: Arc<dyn Material>) -> Self {
+        Self { center, radius, material }
+    }
+
+    // 極座標上の位置を指定するイメージ
+    // 位置から球状マッピングしたテクスチャ座標を取得するコード
+    fn uv(p: Point3) -> (f64, f64) {
+        let phi = p.z().atan2(p.x());
+        let theta = p.y().asin();
+        (1.0 - (phi + PI) / (2.0 * PI), (theta + PI / 2.0) / PI) 
+    }
+
+    // uが増える向きの接ベクトル．経線に沿って回る．
+    fn tangent(n: Vec3) -> Vec3 {
+        Vec3::new(n.z(), 0.0, -n.x())
+    }
+
+    // uvに対する位置の偏微分
+    fn dpduv(&self, n: Vec3) -> (Vec3, Vec3) {
+        let cos_theta = (n.x().powi(2) + n.z().powi(2)).sqrt().max(1e-8);
+        let dpdu = PI2 * self.radius * Self::tangent(n);
+        let dpdv = PI * self.radius * Vec3::new(-n.y() * n.x() / cos_theta, cos_theta, -n.y() * n.z() / cos_theta);
+        (dpdu, dpdv)
+    }
+}
+
+impl Shape for Sphere {
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        let oc = ray.origin - self.center;
+        let a = ray.direction.dot(ray.direction);
+        let b = 2.0 * ray.direction.dot(oc);
+        let c = oc.dot(oc) - self.radius.powi(2);
+        let d = b * b - 4.0 * a * c;
+        if d > 0.0 {
+            let root = d.sqrt();
+            let temp = (-b - root) / (2.0 * a);
+            if t0 < temp && temp < t1 {
+                let p = ray.at(temp);
+                let n = (p - self.center) / self.radius;
+                let (u, v) = Self::uv(n);
+                let (dpdu, dpdv) = self.dpduv(n);
+                let hit = HitInfo::new(temp, p, n, Arc::clone(&self.material), u, v);
+                return Some(hit.with_tangent(Self::tangent(n)).with_dpduv(dpdu, dpdv));
+            }
+            let temp = (-b + root) / (2.0 * a);
+            if t0 < temp && temp < t1 {
+                let p = ray.at(temp);
+                let n = (p - self.center) / self.radius;
+                let (u, v) = Self::uv(n);
+                let (dpdu, dpdv) = self.dpduv(n);
+                let hit = HitInfo::new(temp, p, n, Arc::clone(&self.material), u, v);
+                return Some(hit.with_tangent(Self::tangent(n)).with_dpduv(dpdu, dpdv));
+            }
+        }
+
+        None
+    }
+
+    // 球が見える円錐の立体角で一様
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        if self.hit(&Ray::new(o, v), 0.001, f64::MAX).is_none() {
+            return 0.0;
+        }
+        let distance_squared = (self.center - o).length_squared();
+        let cos_max = (1.0 - self.radius.powi(2) / distance_squared).max(0.0).sqrt();
+        1.0 / (PI2 * (1.0 - cos_max))
+    }
+
+    fn random(&self, o: Vec3) -> Vec3 {
+        let direction = self.center - o;
+        let cos_max = (1.0 - self.radius.powi(2) / direction.length_squared()).max(0.0).sqrt();
+        let [r1, r2, _] = Vec3::random().to_array();
+        let z = 1.0 + r1 * (cos_max - 1.0);
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = PI2 * r2;
+        ONB::new(direction).local(Vec3::new(r * phi.cos(), r * phi.sin(), z))
+    }
+
+    fn power(&self) -> f64 {
+        self.material.emittance().luminance() * self.area() * PI
+    }
+
+    fn area(&self) -> f64 {
+        2.0 * PI2 * self.radius.powi(2)
+    }
+
+    fn sample_surface(&self) -> Option<(HitInfo, f64)> {
+        let n = Vec3::random_unit_vector();
+        let (u, v) = Self::uv(n);
+        let (dpdu, dpdv) = self.dpduv(n);
+        let hit = HitInfo::new(0.0, self.center + n * self.radius, n, Arc::clone(&self.material), u, v)
+            .with_tangent(Self::tangent(n))
+            .with_dpduv(dpdu, dpdv);
+        Some((hit, 1.0 / self.area()))
+    }
+
+    // 全方向を向いている
+    fn light_bounds(&self) -> Option<LightBounds> {
+        let r = Vec3::full(self.radius);
+        Some(LightBounds::new(self.center - r, self.center + r, Vec3::yaxis(), PI, PI * 0.5, self.power()))
+    }
+}
+
+enum RectAxisType {
+    XY,
+    XZ,
+    YZ,
+}
+
+struct Rect {
+    x0: f64,
+    x1: f64,
+    y0: f64,
+    y1: f64,
+    k: f64,
+    axis: RectAxisType,
+    material: Arc<dyn Material>,
+}
+
+impl Rect {
+    fn new(x0: f64, x1: f64, y0: f64, y1: f64, k: f64, axis: RectAxisType, material: Arc<dyn Material>) -> Self {
+        Self { x0, x1, y0, y1, k, axis, material }
+    }
+}
+
+impl Shape for Rect {
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        let mut origin = ray.origin;
+        let mut direction = ray.direction;
+        let mut axis = Vec3::zaxis();
+        let mut tangent = Vec3::xaxis();
+        let mut bitangent = Vec3::yaxis();
+        match self.axis {
+            RectAxisType::XY => {}
+            RectAxisType::XZ => {
+                origin = Point3::new(origin.x(), origin.z(), origin.y());
+                direction = Vec3::new(direction.x(), direction.z(), direction.y());
+                axis = Vec3::yaxis();
+                bitangent = Vec3::zaxis();
+            }
+            RectAxisType::YZ => {
+                origin = Point3::new(origin.y(), origin.z(), origin.x());
+                direction = Vec3::new(direction.y(), direction.z(), direction.x());
+                axis = Vec3::xaxis();
+                tangent = Vec3::yaxis();
+                bitangent = Vec3::zaxis();
+            }
+        }
+
+        let t = (self.k - origin.z()) / direction.z();
+        if t < t0 || t > t1 {
+            return None;
+        }
+
+        let x = origin.x() + t * direction.x();
+        let y = origin.y() + t * direction.y();
+        if x < self.x0 || x>self.x1 || y < self.y0 || y > self.y1 {
+            return None;
+        }
+
+        Some(HitInfo::new(
+            t,
+            ray.at(t),
+            axis,
+            Arc::clone(&self.material),
+            (x - self.x0) / (self.x1 - self.x0),
+            (y - self.y0) / (self.y1 - self.y0),
+        )
+        .with_tangent(tangent)
+        .with_dpduv(tangent * (self.x1 - self.x0), bitangent * (self.y1 - self.y0)))
+    }
+
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        if let Some(hit) = self.hit(&Ray::new(o, v), 0.001, f64::MAX) {
+            let area = (self.x1 - self.x0) * (self.y1 - self.y0);
+            let distance_squared = hit.t.powi(2) * v.length_squared();
+            let cosine = v.dot(hit.n).abs() / v.length();
+            distance_squared / (cosine * area)
+        } else {
+            0.0
+        }
+    }
+
+    fn random(&self, o: Vec3) -> Vec3 {
+        let [rx, ry, _] = Vec3::random().to_array();
+        let x = self.x0 + rx * (self.x1 - self.x0);
+        let y = self.y0 + ry * (self.y1 - self.y0);
+        match self.axis {
+            RectAxisType::XY => Point3::new(x, y, self.k) - o,
+            RectAxisType::XZ => Point3::new(x, self.k, y) - o,
+            RectAxisType::YZ => Point3::new(self.k, x, y) - o,
+        }
+    }
+
+    // 片面だけが光るので放射束は輝度・面積・π
+    fn power(&self) -> f64 {
+        self.material.emittance().luminance() * self.area() * PI
+    }
+
+    fn area(&self) -> f64 {
+        (self.x1 - self.x0) * (self.y1 - self.y0)
+    }
+
+    fn sample_surface(&self) -> Option<(HitInfo, f64)> {
+        let [u, v, _] = Vec3::random().to_array();
+        let x = self.x0 + u * (self.x1 - self.x0);
+        let y = self.y0 + v * (self.y1 - self.y0);
+        let (p, n, tangent) = match self.axis {
+            RectAxisType::XY => (Point3::new(x, y, self.k), Vec3::zaxis(), Vec3::xaxis()),
+            RectAxisType::XZ => (Point3::new(x, self.k, y), Vec3::yaxis(), Vec3::xaxis()),
+            RectAxisType::YZ => (Point3::new(self.k, x, y), Vec3::xaxis(), Vec3::yaxis()),
+        };
+        let hit = HitInfo::new(0.0, p, n, Arc::clone(&self.material), u, v).with_tangent(tangent);
+        Some((hit, 1.0 / self.area()))
+    }
+
+    // 法線の向きの半球に光る
+    fn light_bounds(&self) -> Option<LightBounds> {
+        let (min, max, axis) = match self.axis {
+            RectAxisType::XY => (Point3::new(self.x0, self.y0, self.k), Point3::new(self.x1, self.y1, self.k), Vec3::zaxis()),
+            RectAxisType::XZ => (Point3::new(self.x0, self.k, self.y0), Point3::new(self.x1, self.k, self.y1), Vec3::yaxis()),
+            RectAxisType::YZ => (Point3::new(self.k, self.x0, self.y0), Point3::new(self.k, self.x1, self.y1), Vec3::xaxis()),
+        };
+        Some(LightBounds::new(min, max, axis, 0.0, PI * 0.5, self.power()))
+    }
+}
+
+struct Box3D {
+    p0: Point3,
+    p1: Point3,
+    shapes: ShapeList,
+}
+
+impl Box3D {
+    fn new(p0: Point3, p1: Point3, material: Arc<dyn Material>) -> Self {
+        let mut shapes = ShapeList::new();
+        shapes.push(ShapeBuilder::new()
+            .material(Arc::clone(&material))
+            .rect_xy(p0.x(), p1.x(), p0.y(), p1.y(), p1.z())
+            .build());
+        shapes.push(ShapeBuilder::new()
+            .material(Arc::clone(&material))
+            .rect_xy(p0.x(), p1.x(), p0.y(), p1.y(), p0.z())
+            .flip_face()
+            .build());
+        shapes.push(ShapeBuilder::new()
+            .material(Arc::clone(&material))
+            .rect_xz(p0.x(), p1.x(), p0.z(), p1.z(), p1.y())
+            .build());
+        shapes.push(ShapeBuilder::new()
+            .material(Arc::clone(&material))
+            .rect_xz(p0.x(), p1.x(), p0.z(), p1.z(), p0.y())
+            .flip_face()
+            .build());
+        shapes.push(ShapeBuilder::new()
+            .material(Arc::clone(&material))
+            .rect_yz(p0.y(), p1.y(), p0.z(), p1.z(), p1.x())
+            .build());
+        shapes.push(ShapeBuilder::new()
+            .material(Arc::clone(&material))
+            .rect_yz(p0.y(), p1.y(), p0.z(), p1.z(), p0.x())
+            .flip_face()
+            .build());
+        
+        Self { p0, p1, shapes }
+    }
+}
+
+impl Shape for Box3D {
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        self.shapes.hit(ray, t0, t1)
+    }
+
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        self.shapes.pdf_value(o, v)
+    }
+
+    fn random(&self, o: Vec3) -> Vec3 {
+        self.shapes.random(o)
+    }
+
+    fn power(&self) -> f64 {
+        self.shapes.power()
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        self.shapes.light_bounds()
+    }
+
+    fn area(&self) -> f64 {
+        self.shapes.area()
+    }
+
+    fn sample_surface(&self) -> Option<(HitInfo, f64)> {
+        self.shapes.sample_surface()
+    }
+}
+
+// Decorators
+
+struct FlipFace {
+    shape: Box<dyn Shape>,
+}
+
+impl FlipFace {
+    fn new(shape: Box<dyn Shape>) -> Self {
+        Self { shape }
+    }
+}
+
+impl Shape for FlipFace {
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        if let Some(hit) = self.shape.hit(ray, t0, t1) {
+            Some(HitInfo { n: -hit.n, ..hit })
+        } else {
+            None
+        }
+    }
+
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        self.shape.pdf_value(o, v)
+    }
+
+    fn random(&self, o: Vec3) -> Vec3 {
+        self.shape.random(o)
+    }
+
+    fn power(&self) -> f64 {
+        self.shape.power()
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        self.shape.light_bounds().map(|b| b.flip())
+    }
+
+    fn area(&self) -> f64 {
+        self.shape.area()
+    }
+
+    fn sample_surface(&self) -> Option<(HitInfo, f64)> {
+        self.shape.sample_surface().map(|(hit, pdf)| (HitInfo { n: -hit.n, ..hit }, pdf))
+    }
+}
+
+struct Translate {
+    shape: Box<dyn Shape>,
+    offset: Point3,
+}
+
+impl Translate {
+    fn new(shape: Box<dyn Shape>, offset: Point3) -> Self {
+        Self { shape, offset }
+    }
+}
+
+impl Shape for Translate {
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        let moved_ray = Ray::new(ray.origin - self.offset, ray.direction);
+        if let Some(hit) = self.shape.hit(&moved_ray, t0, t1) {
+            Some(HitInfo { p: hit.p + self.offset, ..hit })
+        } else {
+            None
+        }
+    }
+
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        self.shape.pdf_value(o - self.offset, v)
+    }
+
+    fn random(&self, o: Vec3) -> Vec3 {
+        self.shape.random(o - self.offset)
+    }
+
+    fn power(&self) -> f64 {
+        self.shape.power()
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        self.shape.light_bounds().map(|b| b.translate(self.offset))
+    }
+
+    fn area(&self) -> f64 {
+        self.shape.area()
+    }
+
+    fn sample_surface(&self) -> Option<(HitInfo, f64)> {
+        self.shape.sample_surface().map(|(hit, pdf)| (HitInfo { p: hit.p + self.offset, ..hit }, pdf))
+    }
+}
+
+struct Rotate {
+    shape: Box<dyn Shape>,
+    quat: Quat,
+}
+
+impl Rotate {
+    fn new(shape: Box<dyn Shape>, axis: Vec3, angle: f64) -> Self {
+        Self { shape, quat: Quat::from_rot(axis, angle.to_radians()) }
+    }
+}
+
+impl Shape for Rotate {
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        let revq = self.quat.conj();
+        let rotated_ray = Ray::new(revq.rotate(ray.origin), revq.rotate(ray.direction));
+        if let Some(hit) = self.shape.hit(&rotated_ray, t0, t1) {
+            Some(HitInfo {
+                p: self.quat.rotate(hit.p),
+                n: self.quat.rotate(hit.n),
+                tangent: self.quat.rotate(hit.tangent),
+                dpdu: self.quat.rotate(hit.dpdu),
+                dpdv: self.quat.rotate(hit.dpdv),
+                ..hit
+            })
+        } else {
+            None
+        }
+    }
+
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        let revq = self.quat.conj();
+        self.shape.pdf_value(revq.rotate(o), revq.rotate(v))
+    }
+
+    fn random(&self, o: Vec3) -> Vec3 {
+        self.quat.rotate(self.shape.random(self.quat.conj().rotate(o)))
+    }
+
+    fn power(&self) -> f64 {
+        self.shape.power()
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        self.shape.light_bounds().map(|b| b.rotate(&self.quat))
+    }
+
+    fn area(&self) -> f64 {
+        self.shape.area()
+    }
+
+    fn sample_surface(&self) -> Option<(HitInfo, f64)> {
+        self.shape.sample_surface().map(|(hit, pdf)| {
+            let hit = HitInfo {
+                p: self.quat.rotate(hit.p),
+                n: self.quat.rotate(hit.n),
+                tangent: self.quat.rotate(hit.tangent),
+                dpdu: self.quat.rotate(hit.dpdu),
+                dpdv: self.quat.rotate(hit.dpdv),
+                ..hit
+            };
+            (hit, pdf)
+        })
+    }
+}
+
+// 接ベクトルの向きを変える．axisを接平面に射影した向きにしてから，法線周りにangle(度)回す．
+struct TangentFrame {
+    shape: Box<dyn Shape>,
+    axis: Option<Vec3>,
+    angle: f64,
+}
+
+impl TangentFrame {
+    fn new(shape: Box<dyn Shape>, axis: Option<Vec3>, angle: f64) -> Self {
+        Self { shape, axis, angle }
+    }
+}
+
+impl Shape for TangentFrame {
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        let hit = self.shape.hit(ray, t0, t1)?;
+        let onb = ONB::from_tangent(hit.n, self.axis.unwrap_or(hit.tangent));
+        let (s, c) = self.angle.to_radians().sin_cos();
+        let tangent = onb.u() * c + onb.v() * s;
+        Some(hit.with_tangent(tangent))
+    }
+
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        self.shape.pdf_value(o, v)
+    }
+
+    fn random(&self, o: Vec3) -> Vec3 {
+        self.shape.random(o)
+    }
+
+    fn power(&self) -> f64 {
+        self.shape.power()
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        self.shape.light_bounds()
+    }
+
+    fn area(&self) -> f64 {
+        self.shape.area()
+    }
+
+    fn sample_surface(&self) -> Option<(HitInfo, f64)> {
+        self.shape.sample_surface()
+    }
+}
+
+// 不透明度マスク．葉っぱやフェンスなどの切り抜き形状用．
+// 透明な部分との衝突は無視してその先を探す．半透明な部分は確率的に判定する．
+struct AlphaMask {
+    shape: Box<dyn Shape>,
+    mask: Box<dyn Texture>,
+}
+
+impl AlphaMask {
+    fn new(shape: Box<dyn Shape>, mask: Box<dyn Texture>) -> Self {
+        Self { shape, mask }
+    }
+}
+
+impl Shape for AlphaMask {
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        let mut tmin = t0;
+        while let Some(hit) = self.shape.hit(ray, tmin, t1) {
+            let alpha = self.mask.alpha(hit.u, hit.v, hit.p);
+            if alpha >= 1.0 || (alpha > 0.0 && Vec3::random_full().x() < alpha) {
+                return Some(hit);
+            }
+            tmin = hit.t + EPS;
+        }
+        None
+    }
+
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        self.shape.pdf_value(o, v)
+    }
+
+    fn random(&self, o: Vec3) -> Vec3 {
+        self.shape.random(o)
+    }
+
+    fn power(&self) -> f64 {
+        self.shape.power()
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        self.shape.light_bounds()
+    }
+
+    fn area(&self) -> f64 {
+        self.shape.area()
+    }
+
+    fn sample_surface(&self) -> Option<(HitInfo, f64)> {
+        self.shape.sample_surface()
+    }
+}
+
+// 表面下散乱．境界形状の内側を散乱媒質で満たし，内部でランダムウォークさせる．
+// 境界の材質(Dielectricなど)が屈折を，媒質が内部散乱を受け持つ．
+// mfpは平均自由行程で，光が散乱するまでに進む平均距離．
+struct Subsurface {
+    boundary: Box<dyn Shape>,
+    mfp: f64,
+    phase: Arc<dyn Material>,
+}
+
+impl Subsurface {
+    fn new(boundary: Box<dyn Shape>, albedo: Box<dyn Texture>, mfp: f64) -> Self {
+        Self { boundary, mfp, phase: Arc::new(Isotropic::new(albedo)) }
+    }
+}
+
+impl Shape for Subsurface {
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        let surface = self.boundary.hit(ray, t0, t1)?;
+        // 内側から出ていく光線のときだけ，境界に着く前に散乱するか判定する．
+        if ray.direction.dot(surface.n) <= 0.0 {
+            return Some(surface);
+        }
+        let r = Vec3::random_full().x();
+        let distance = -self.mfp * (1.0 - r).ln();
+        let t = distance / ray.direction.length();
+        if t0 < t && t < surface.t {
+            let p = ray.at(t);
+            Some(HitInfo::new(t, p, -ray.direction.normalize(), Arc::clone(&self.phase), surface.u, surface.v))
+        } else {
+            Some(surface)
+        }
+    }
+
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        self.boundary.pdf_value(o, v)
+    }
+
+    fn random(&self, o: Vec3) -> Vec3 {
+        self.boundary.random(o)
+    }
+}
+
+// 物体リスト．複数物体の管理．
+struct ShapeList {
+    pub objects: Vec<Arc<dyn Shape>>,
+}
+
+impl ShapeList {
+    pub fn new() -> Self {
+        Self { objects: Vec::new() }
+    }
+
+    pub fn push(&mut self, object: Box<dyn Shape>){
+        self.objects.push(Arc::from(object));
+    }
+
+    // 光を放つ物体を光源リストにまとめる
+    pub fn emitters(&self) -> LightList {
+        LightList::from_lights(self.objects.iter()
+            .map(|object| (Arc::clone(object), object.power()))
+            .filter(|(_, power)| *power > 0.0)
+            .collect())
+    }
+
+    // 光る物体から光源の階層構造を作る
+    pub fn light_bvh(&self) -> LightBvh {
+        let lights = self.objects.iter().filter(|s| s.power() > 0.0).map(Arc::clone).collect();
+        LightBvh::new(lights)
+    }
+}
+
+impl Shape for ShapeList {
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        let mut hit_info: Option<HitInfo> = None;
+        let mut closest_so_far = t1;
+        for object in &self.objects {
+            if let Some(info) = object.hit(ray, t0, closest_so_far) {
+                closest_so_far = info.t;
+                hit_info = Some(info);
+            }
+        }
+
+        hit_info
+    }
+
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        if self.objects.is_empty() { panic!(); }
+        let weight = 1.0 / self.objects.len() as f64;
+        self.objects.iter().fold(0.0,|acc, s| acc + weight * s.pdf_value(o, v))
+    }
+
+    fn random(&self, o: Vec3) -> Vec3 {
+        if self.objects.is_empty() { panic!(); }
+        let index = (Vec3::random_full().x() * self.objects.len() as f64).floor() as usize;
+        self.objects[index].random(o)
+    }
+
+    fn power(&self) -> f64 {
+        self.objects.iter().map(|s| s.power()).sum()
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        self.objects.iter().filter_map(|s| s.light_bounds()).reduce(|a, b| a.union(&b))
+    }
+
+    fn area(&self) -> f64 {
+        self.objects.iter().map(|s| s.area()).sum()
+    }
+
+    // 面積に比例して物体を選ぶので，全体で面積について一様になる
+    fn sample_surface(&self) -> Option<(HitInfo, f64)> {
+        let total = self.area();
+        let mut r = Vec3::random_full().x() * total;
+        for object in &self.objects {
+            let area = object.area();
+            if r < area {
+                return object.sample_surface().map(|(hit, _)| (hit, 1.0 / total));
+            }
+            r -= area;
+        }
+        None
+    }
+}
+
+// 光源リスト．放射束に比例する確率で光源を選ぶので，明るい光源ほどよくサンプリングされる．
+// 面光源はShapeList::emittersで自動的に集める．環境光などはfrom_lightsで明るさを指定して加える．
+struct LightList {
+    lights: Vec<Arc<dyn Shape>>,
+    powers: Vec<f64>,
+    distribution: Distribution1D,
+}
+
+impl LightList {
+    fn new() -> Self {
+        Self { lights: Vec::new(), powers: Vec::new(), distribution: Distribution1D::new(Vec::new()) }
+    }
+
+    // 光源と放射束の組から作る．選ぶ確率の分布は最後に一度だけ作る．
+    fn from_lights(lights: Vec<(Arc<dyn Shape>, f64)>) -> Self {
+        let (lights, powers): (Vec<_>, Vec<_>) = lights.into_iter().unzip();
+        let distribution = Distribution1D::new(powers.clone());
+        Self { lights, powers, distribution }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+}
+
+impl Shape for LightList {
+    fn hit(&self, _ray: &Ray, _t0: f64, _t1: f64) -> Option<HitInfo> {
+        None
+    }
+
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        self.lights.iter().enumerate().fold(0.0, |acc, (i, light)| {
+            acc + self.distribution.pmf(i) * light.pdf_value(o, v)
+        })
+    }
+
+    fn random(&self, o: Vec3) -> Vec3 {
+        if self.is_empty() { panic!(); }
+        let (index, _) = self.distribution.sample_discrete(Vec3::random_full().x());
+        self.lights[index].random(o)
+    }
+
+    fn power(&self) -> f64 {
+        self.powers.iter().sum()
+    }
+}
+
+// 光源の範囲(AABB)，向きの円錐，放射束．
+// axisを中心に半角theta_oの円錐内に法線があり，各法線からtheta_eの範囲に光を出す．
+#[derive(Clone, Copy)]
+struct LightBounds {
+    min: Point3,
+    max: Point3,
+    axis: Vec3,
+    theta_o: f64,
+    theta_e: f64,
+    power: f64,
+}
+
+impl LightBounds {
+    fn new(min: Point3, max: Point3, axis: Vec3, theta_o: f64, theta_e: f64, power: f64) -> Self {
+        Self { min, max, axis: axis.normalize(), theta_o, theta_e, power }
+    }
+
+    fn center(&self) -> Point3 {
+        (self.min + self.max) * 0.5
+    }
+
+    fn union(&self, other: &Self) -> Self {
+        let min = self.min.iter().zip(other.min.iter()).map(|(a, b)| a.min(*b)).collect();
+        let max = self.max.iter().zip(other.max.iter()).map(|(a, b)| a.max(*b)).collect();
+        // 向きの円錐をまとめる．広い方をaとする．
+        let (a, b) = if self.theta_o >= other.theta_o { (self, other) } else { (other, self) };
+        let theta_d = a.axis.dot(b.axis).clamp(-1.0, 1.0).acos();
+        let (axis, theta_o) = if (theta_d + b.theta_o).min(PI) <= a.theta_o {
+            (a.axis, a.theta_o)
+        } else {
+            let theta_o = (a.theta_o + theta_d + b.theta_o) * 0.5;
+            let w = a.axis.cross(b.axis);
+            if theta_o >= PI || w.length_squared() < EPS {
+                (a.axis, PI)
+            } else {
+                (Quat::from_rot(w.normalize(), theta_o - a.theta_o).rotate(a.axis), theta_o)
+            }
+        };
+        Self {
+            min,
+            max,
+            axis,
+            theta_o,
+            theta_e: self.theta_e.max(other.theta_e),
+            power: self.power + other.power,
+        }
+    }
+
+    fn flip(self) -> Self {
+        Self { axis: -self.axis, ..self }
+    }
+
+    fn translate(self, offset: Vec3) -> Self {
+        Self { min: self.min + offset, max: self.max + offset, ..self }
+    }
+
+    fn rotate(self, quat: &Quat) -> Self {
+        let mut min = Point3::full(f64::MAX);
+        let mut max = Point3::full(f64::MIN);
+        for i in 0..8 {
+            let corner = Point3::new(
+                if i & 1 == 0 { self.min.x() } else { self.max.x() },
+                if i & 2 == 0 { self.min.y() } else { self.max.y() },
+                if i & 4 == 0 { self.min.z() } else { self.max.z() },
+            );
+            let p = quat.rotate(corner);
+            min = min.iter().zip(p.iter()).map(|(a, b)| a.min(*b)).collect();
+            max = max.iter().zip(p.iter()).map(|(a, b)| a.max(*b)).collect();
+        }
+        Self { min, max, axis: quat.rotate(self.axis), ..self }
+    }
+
+    // 点pから見た重要度．放射束を距離の二乗で割り，pへ光が向かいうる角度で弱める．
+    fn importance(&self, p: Point3) -> f64 {
+        let center = self.center();
+        let radius_squared = (self.max - self.min).length_squared() * 0.25;
+        let distance_squared = (p - center).length_squared();
+        let wi = (p - center).normalize();
+        let theta_w = if distance_squared > 0.0 { self.axis.dot(wi).clamp(-1.0, 1.0).acos() } else { 0.0 };
+        // 範囲が見込む角度
+        let theta_b = if distance_squared <= radius_squared {
+            PI
+        } else {
+            (radius_squared / distance_squared).sqrt().asin()
+        };
+        let theta = (theta_w - self.theta_o - theta_b).max(0.0);
+        if theta >= self.theta_e {
+            return 0.0;
+        }
+        self.power * theta.cos() / distance_squared.max(radius_squared)
+    }
+
+    // 半直線o + tvが範囲と交わるか
+    fn hit(&self, o: Point3, v: Vec3) -> bool {
+        let (mut t0, mut t1) = (0.0, f64::MAX);
+        for i in 0..3 {
+            let inv = v.to_array()[i].recip();
+            let o = o.to_array()[i];
+            let mut ta = (self.min.to_array()[i] - EPS - o) * inv;
+            let mut tb = (self.max.to_array()[i] + EPS - o) * inv;
+            if inv < 0.0 {
+                std::mem::swap(&mut ta, &mut tb);
+            }
+            t0 = ta.max(t0);
+            t1 = tb.min(t1);
+            if t1 < t0 {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+struct LightNode {
+    bounds: LightBounds,
+    // 内部節点なら子の番号，葉なら光源の番号
+    children: Option<[usize; 2]>,
+    light: usize,
+}
+
+// 光源の階層構造．範囲と向きで光源をまとめ，衝突点から見た重要度に比例する確率で
+// 木を下って光源を一つ選ぶ．光源が数千個あっても近くの明るい光源が選ばれやすい．
+// 環境光のように範囲を持たない光源は木に入れず，別に一様な確率で選ぶ．
+struct LightBvh {
+    lights: Vec<Arc<dyn Shape>>,
+    nodes: Vec<LightNode>,
+    infinite: Vec<Arc<dyn Shape>>,
+}
+
+impl LightBvh {
+    fn new(lights: Vec<Arc<dyn Shape>>) -> Self {
+        let (lights, infinite): (Vec<_>, Vec<_>) = lights.into_iter().partition(|l| l.light_bounds().is_some());
+        let bounds: Vec<LightBounds> = lights.iter().map(|l| l.light_bounds().unwrap()).collect();
+        let mut bvh = Self { lights, nodes: Vec::new(), infinite };
+        if !bounds.is_empty() {
+            let mut indices: Vec<usize> = (0..bounds.len()).collect();
+            bvh.build(&bounds, &mut indices);
+        }
+        bvh
+    }
+
+    // 重心の広がりが最大の軸で半分に分ける
+    fn build(&mut self, bounds: &[LightBounds], indices: &mut [usize]) -> usize {
+        let node = self.nodes.len();
+        if indices.len() == 1 {
+            self.nodes.push(LightNode { bounds: bounds[indices[0]], children: None, light: indices[0] });
+            return node;
+        }
+        let total = indices.iter().skip(1).fold(bounds[indices[0]], |acc, &i| acc.union(&bounds[i]));
+        self.nodes.push(LightNode { bounds: total, children: None, light: 0 });
+
+        let centers: Vec<Point3> = indices.iter().map(|&i| bounds[i].center()).collect();
+        let extent = (0..3).map(|k| {
+            let (lo, hi) = centers.iter().fold((f64::MAX, f64::MIN), |(lo, hi), c| (lo.min(c.to_array()[k]), hi.max(c.to_array()[k])));
+            hi - lo
+        }).collect::<Vec3>();
+        let axis = if extent.x() >= extent.y() && extent.x() >= extent.z() { 0 } else if extent.y() >= extent.z() { 1 } else { 2 };
+        indices.sort_by(|&a, &b| bounds[a].center().to_array()[axis].partial_cmp(&bounds[b].center().to_array()[axis]).unwrap());
+
+        let (left, right) = indices.split_at_mut(indices.len() / 2);
+        let l = self.build(bounds, left);
+        let r = self.build(bounds, right);
+        self.nodes[node].children = Some([l, r]);
+        node
+    }
+
+    fn is_empty(&self) -> bool {
+        self.lights.is_empty() && self.infinite.is_empty()
+    }
+
+    // 範囲を持たない光源の方を選ぶ確率．木全体を一つの光源と同じ重みで扱う．
+    fn infinite_probability(&self) -> f64 {
+        let n = self.infinite.len() as f64;
+        if self.lights.is_empty() { 1.0 } else { n / (n + 1.0) }
+    }
+
+    // 点pで左の子を選ぶ確率
+    fn left_probability(&self, children: [usize; 2], p: Point3) -> f64 {
+        let l = self.nodes[children[0]].bounds.importance(p);
+        let r = self.nodes[children[1]].bounds.importance(p);
+        if l + r > 0.0 { l / (l + r) } else { 0.5 }
+    }
+
+    // 木から光源を一つ選ぶ．(番号, 確率)を返す．
+    fn sample(&self, p: Point3, r: f64) -> (usize, f64) {
+        let mut node = 0;
+        let mut r = r;
+        let mut pmf = 1.0;
+        while let Some(children) = self.nodes[node].children {
+            let pl = self.left_probability(children, p);
+            if r < pl {
+                r /= pl;
+                pmf *= pl;
+                node = children[0];
+            } else {
+                r = ((r - pl) / (1.0 - pl)).min(1.0 - f64::EPSILON);
+                pmf *= 1.0 - pl;
+                node = children[1];
+            }
+        }
+        (self.nodes[node].light, pmf)
+    }
+
+    // 光線と交わる範囲だけを辿って，選ぶ確率で重み付けしたpdfを足し合わせる
+    fn pdf_node(&self, node: usize, o: Point3, v: Vec3, pmf: f64) -> f64 {
+        let n = &self.nodes[node];
+        if pmf <= 0.0 || !n.bounds.hit(o, v) {
+            return 0.0;
+        }
+        match n.children {
+            Some(children) => {
+                let pl = self.left_probability(children, o);
+                self.pdf_node(children[0], o, v, pmf * pl) + self.pdf_node(children[1], o, v, pmf * (1.0 - pl))
+            }
+            None => pmf * self.lights[n.light].pdf_value(o, v),
+        }
+    }
+}
+
+impl Shape for LightBvh {
+    fn hit(&self, _ray: &Ray, _t0: f64, _t1: f64) -> Option<HitInfo> {
+        None
+    }
+
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        let p_infinite = self.infinite_probability();
+        let infinite = self.infinite.iter().map(|l| l.pdf_value(o, v)).sum::<f64>();
+        let mut pdf = if infinite > 0.0 { p_infinite / self.infinite.len() as f64 * infinite } else { 0.0 };
+        if !self.lights.is_empty() {
+            pdf += (1.0 - p_infinite) * self.pdf_node(0, o, v, 1.0);
+        }
+        pdf
+    }
+
+    fn random(&self, o: Vec3) -> Vec3 {
+        if self.is_empty() { panic!(); }
+        let r = Vec3::random_full().x();
+        let p_infinite = self.infinite_probability();
+        if r < p_infinite {
+            let i = ((r / p_infinite * self.infinite.len() as f64) as usize).min(self.infinite.len() - 1);
+            return self.infinite[i].random(o);
+        }
+        let r = ((r - p_infinite) / (1.0 - p_infinite)).min(1.0 - f64::EPSILON);
+        let (index, _) = self.sample(o, r);
+        self.lights[index].random(o)
+    }
+
+    fn power(&self) -> f64 {
+        self.nodes.first().map_or(0.0, |n| n.bounds.power) + self.infinite.iter().map(|l| l.power()).sum::<f64>()
+    }
+}
+
+// Arcで共有している形状もそのまま物体として扱えるようにする．
+// 環境光を背景と光源リストの両方から参照するときなどに使う．
+impl<T: Shape + ?Sized> Shape for Arc<T> {
+    fn hit(&self, ray: &Ray, t0: f64, t1: f64) -> Option<HitInfo> {
+        (**self).hit(ray, t0, t1)
+    }
+
+    fn pdf_value(&self, o: Vec3, v: Vec3) -> f64 {
+        (**self).pdf_value(o, v)
+    }
+
+    fn random(&self, o: Vec3) -> Vec3 {
+        (**self).random(o)
+    }
+
+    fn power(&self) -> f64 {
+        (**self).power()
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        (**self).light_bounds()
+    }
+
+    fn area(&self) -> f64 {
+        (**self).area()
+    }
+
+    fn sample_surface(&self) -> Option<(HitInfo, f64)> {
+        (**self).sample_surface()
+    }
+}
+
+// 無限遠から届く光．光線が何にも当たらなければ背景として見え，
+// 光源リストに入れると直接光のサンプリングにも使える．
+trait Environment: Shape {
+    // 方向dから届く光
+    fn value(&self, d: Vec3) -> Color;
+}
+
+// 正距円筒図法のHDR画像による環境光．無限遠にあるので衝突はしない．
+// 輝度に比例して方向を選べるので，光源リストに入れると直接光のサンプリングに使える．
+// rotationはy軸周りの回転(度)，intensityは明るさの倍率．
+struct EnvironmentLight {
+    image: FloatImage,
+    distribution: Distribution2D,
+    quat: Quat,
+    intensity: f64,
+}
+
+impl EnvironmentLight {
+    fn new(path: &str, rotation: f64, intensity: f64) -> Self {
+        Self::from_image(read_float_image(path).unwrap(), rotation, intensity)
+    }
+
+    fn from_image(image: FloatImage, rotation: f64, intensity: f64) -> Self {
+        // 画素の立体角は緯度のcosに比例するので重みに掛ける
+        let func: Vec<f64> = image.pixels.iter().enumerate().map(|(i, c)| {
+            let y = i / image.width;
+            let theta = PI * (0.5 - (y as f64 + 0.5) / image.height as f64);
+            c.luminance().max(0.0) * theta.cos()
+        }).collect();
+        let distribution = Distribution2D::new(&func, image.width, image.height);
+        Self { image, distribution, quat: Quat::from_rot_y(rotation.to_radians()), intensity }
+    }
+
+    // 方向を画像座標(s, t)と緯度に変換する
+    fn to_image(&self, d: Vec3) -> (f64, f64, f64) {
+        let d = self.quat.conj().rotate(d.normalize());
+        let phi = d.z().atan2(d.x());
+        let theta = d.y().clamp(-1.0, 1.0).asin();
+        let s = 1.0 - (phi + PI) / PI2;
+        let t = 0.5 - theta / PI;
+        (s.clamp(0.0, 1.0 - f64::EPSILON), t.clamp(0.0, 1.0 - f64::EPSILON), theta)
+    }
+
+    fn direction(&self, s: f64, t: f64) -> Vec3 {
+        let phi = (1.0 - s) * PI2 - PI;
+        let theta = (0.5 - t) * PI;
+        let (sin_t, cos_t) = theta.sin_cos();
+        let (sin_p, cos_p) = phi.sin_cos();
+        self.quat.rotate(Vec3::new(cos_t * cos_p, sin_t, cos_t * sin_p))
+    }
+
+}
+
+impl Environment for EnvironmentLight {
+    fn value(&self, d: Vec3) -> Color {
+        let (s, t, _) = self.to_image(d);
+        let x = (s * self.image.width as f64) as usize;
+        let y = (t * self.image.height as f64) as usize;
+        self.image.pixels[x + self.image.width * y] * self.intensity
+    }
+}
+
+impl Shape for EnvironmentLight {
+    fn hit(&self, _ray: &Ray, _t0: f64, _t1: f64) -> Option<HitInfo> {
+        None
+    }
+
+    // 立体角あたりの確率密度
+    fn pdf_value(&self, _o: Vec3, v: Vec3) -> f64 {
+        let (s, t, theta) = self.to_image(v);
+        let cos_theta = theta.cos();
+        if cos_theta <= 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf(s, t) / (2.0 * PI * PI * cos_theta)
+    }
+
+    fn random(&self, _o: Vec3) -> Vec3 {
+        let [r1, r2, _] = Vec3::random().to_array();
+        let ((s, t), _) = self.distribution.sample(r1, r2);
+        self.direction(s, t)
+    }
+}
+
+// 昼光の空．太陽の円盤だけを光源として直接サンプリングする．
+// 空の残りは散乱方向のサンプリングに任せる．
+impl Shape for Sky {
+    fn hit(&self, _ray: &Ray, _t0: f64, _t1: f64) -> Option<HitInfo> {
+        None
+    }
+
+    fn pdf_value(&self, _o: Vec3, v: Vec3) -> f64 {
+        if self.sun_radiance().luminance() <= 0.0 || v.normalize().dot(self.sun_direction()) < self.cos_sun() {
+            return 0.0;
+        }
+        1.0 / self.sun_solid_angle()
+    }
+
+    fn random(&self, _o: Vec3) -> Vec3 {
+        self.sample_sun()
+    }
+}
+
+impl Environment for Sky {
+    fn value(&self, d: Vec3) -> Color {
+        Sky::value(self, d)
+    }
+}
+
+// 点光源などの大きさを持たない光源から届く光．wiは光源への単位ベクトル．
+struct LightSample {
+    wi: Vec3,
+    distance: f64,
+    li: Color,
+}
+
+// 大きさを持たない光源．偶然当たることはないので，衝突点から明示的にサンプリングする．
+trait PunctualLight: Send + Sync {
+    fn illuminate(&self, p: Point3) -> Option<LightSample>;
+}
+
+// 点光源．intensityは放射強度．
+struct PointLight {
+    position: Point3,
+    intensity: Color,
+}
+
+impl PointLight {
+    fn new(position: Point3, intensity: Color) -> Self {
+        Self { position, intensity }
+    }
+}
+
+impl PunctualLight for PointLight {
+    fn illuminate(&self, p: Point3) -> Option<LightSample> {
+        let d = self.position - p;
+        let distance = d.length();
+        Some(LightSample { wi: d / distance, distance, li: self.intensity / (distance * distance) })
+    }
+}
+
+// スポットライト．targetの方向を照らす．
+// angleは照らす範囲の半角，falloffは減衰が始まる半角(度)で，その間は滑らかに暗くなる．
+struct SpotLight {
+    position: Point3,
+    direction: Vec3,
+    intensity: Color,
+    cos_total: f64,
+    cos_falloff: f64,
+}
+
+impl SpotLight {
+    fn new(position: Point3, target: Point3, intensity: Color, angle: f64, falloff: f64) -> Self {
+        Self {
+            position,
+            direction: (target - position).normalize(),
+            intensity,
+            cos_total: angle.to_radians().cos(),
+            cos_falloff: falloff.min(angle).to_radians().cos(),
+        }
+    }
+
+    fn falloff(&self, cos_theta: f64) -> f64 {
+        if cos_theta >= self.cos_falloff {
+            return 1.0;
+        }
+        if cos_theta <= self.cos_total {
+            return 0.0;
+        }
+        let t = (cos_theta - self.cos_total) / (self.cos_falloff - self.cos_total);
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+impl PunctualLight for SpotLight {
+    fn illuminate(&self, p: Point3) -> Option<LightSample> {
+        let d = self.position - p;
+        let distance = d.length();
+        let wi = d / distance;
+        let falloff = self.falloff(-wi.dot(self.direction));
+        if falloff <= 0.0 {
+            return None;
+        }
+        Some(LightSample { wi, distance, li: self.intensity * falloff / (distance * distance) })
+    }
+}
+
+// 平行光源．directionは光の進む向き，irradianceは光に垂直な面での放射照度．
+struct DirectionalLight {
+    direction: Vec3,
+    irradiance: Color,
+}
+
+impl DirectionalLight {
+    fn new(direction: Vec3, irradiance: Color) -> Self {
+        Self { direction: direction.normalize(), irradiance }
+    }
+}
+
+impl PunctualLight for DirectionalLight {
+    fn illuminate(&self, _p: Point3) -> Option<LightSample> {
+        Some(LightSample { wi: -self.direction, distance: f64::MAX, li: self.irradiance })
+    }
+}
+
+// MISの重み．fのサンプリングで得たサンプルに対する，gとのパワーヒューリスティック(β=2)．
+fn power_heuristic(f: f64, g: f64) -> f64 {
+    let (f2, g2) = (f * f, g * g);
+    if f2 + g2 > 0.0 { f2 / (f2 + g2) } else { 0.0 }
+}
+
+// 点光源たちからの直接光．遮蔽されていなければBRDF・cosを掛けて足し合わせる．
+// albedoとscattering_pdfの積がBRDF・cosになる材質(pdfを持つ散乱)にだけ使う．
+fn punctual_lighting(world: &dyn Shape, lights: &[Box<dyn PunctualLight>], ray: &Ray, hit: &HitInfo, albedo: Color) -> Color {
+    lights.iter().fold(Color::zero(), |acc, light| {
+        let sample = match light.illuminate(hit.p) {
+            Some(sample) => sample,
+            None => return acc,
+        };
+        let shadow_ray = Ray::new(hit.p, sample.wi);
+        let f = hit.m.scattering_pdf(ray, hit, &shadow_ray);
+        if f <= 0.0 || world.hit(&shadow_ray, 0.001, sample.distance - 0.001).is_some() {
+            return acc;
+        }
+        acc + albedo * sample.li * f
+    })
+}
+
+struct ShapeBuilder {
+    texture: Option<Box<dyn Texture>>,
+    material: Option<Arc<dyn Material>>,
+    shape: Option<Box<dyn Shape>>,
+}
+
+impl ShapeBuilder {
+    fn new() -> Self {
+        Self { texture: None, material: None, shape: None }
+    }
+
+    // テクスチャ
+
+    fn color_texture(mut self, color: Color) -> Self {
+        self.texture = Some(Box::new(ColorTexture::new(color)));
+        self
+    }
+
+    fn checker_texture(mut self, odd_color: Color, even_color: Color, freq: f64) -> Self {
+        self.texture = Some(Box::new(CheckerTexture::new(
+            Box::new(ColorTexture::new(odd_color)),
+            Box::new(ColorTexture::new(even_color)),
+            freq,
+        )));
+        self
+    }
+
+    fn noise_texture(mut self, kind: NoiseType, color0: Color, color1: Color, freq: f64) -> Self {
+        self.texture = Some(Box::new(NoiseTexture::new(kind, color0, color1, freq)));
+        self
+    }
+
+    fn marble_texture(mut self, color0: Color, color1: Color, freq: f64, turbulence: f64) -> Self {
+        self.texture = Some(Box::new(MarbleTexture::new(color0, color1, freq, turbulence)));
+        self
+    }
+
+    fn wood_texture(mut self, color0: Color, color1: Color, freq: f64, turbulence: f64) -> Self {
+        self.texture = Some(Box::new(WoodTexture::new(color0, color1, freq, turbulence)));
+        self
+    }
+
+    fn image_texture(mut self, path: &str) -> Self {
+        self.texture = Some(Box::new(ImageTexture::new(path)));
+        self
+    }
+
+    // 色空間を指定した画像テクスチャ
+    fn image_texture_in(mut self, path: &str, space: ColorSpace) -> Self {
+        self.texture = Some(Box::new(ImageTexture::load(path, space)));
+        self
+    }
+
+    // 法線マップや粗さマップなど，色として変換しない画像
+    fn data_texture(self, path: &str) -> Self {
+        self.image_texture_in(path, ColorSpace::Data)
+    }
+
+    // 補間方法とラップモードを指定した画像テクスチャ
+    fn image_texture_with(mut self, path: &str, filter: TextureFilter, wrap_u: WrapMode, wrap_v: WrapMode) -> Self {
+        self.texture = Some(Box::new(ImageTexture::new(path).filter(filter).wrap(wrap_u, wrap_v)));
+        self
+    }
+
+    // 材質
+
+    fn lambertian(mut self) -> Self {
+        self.material = Some(Arc::new(Lambertian::new(self.texture.unwrap())));
+        self.texture = None;
+        self
+    }
+
+    fn oren_nayar(mut self, sigma: f64) -> Self {
+        self.material = Some(Arc::new(OrenNayar::new(self.texture.unwrap(), sigma)));
+        self.texture = None;
+        self
+    }
+
+    fn retro_reflector(mut self, exponent: f64) -> Self {
+        self.material = Some(Arc::new(RetroReflector::new(self.texture.unwrap(), exponent)));
+        self.texture = None;
+        self
+    }
+
+    fn metal(mut self, fuzz: f64) -> Self {
+        self.material = Some(Arc::new(Metal::new(self.texture.unwrap(), fuzz)));
+        self.texture = None;
+        self
+    }
+
+    fn anisotropic_metal(mut self, alpha_x: f64, alpha_y: f64) -> Self {
+        self.material = Some(Arc::new(AnisotropicMetal::new(self.texture.unwrap(), alpha_x, alpha_y)));
+        self.texture = None;
+        self
+    }
+
+    fn dielectric(mut self, ri: f64) -> Self {
+        self.material = Some(Arc::new(Dielectric::new(ri)));
+        self
+    }
+
+    // 設定済みの材質に薄膜コーティングを施す．thicknessはnm．
+    fn thin_film(mut self, thickness: f64, film_ri: f64, base_ri: f64) -> Self {
+        self.material = Some(Arc::new(ThinFilm::new(self.material.unwrap(), thickness, film_ri, base_ri)));
+        self
+    }
+
+    fn diffuse_light(mut self) -> Self {
+        self.material = Some(Arc::new(DiffuseLight::new(self.texture.unwrap())));
+        self.texture = None;
+        self
+    }
+
+    fn material(mut self, material: Arc<dyn Material>) -> Self {
+        self.material = Some(material);
+        self.texture = None;
+        self
+    }
+
+    // 設定済みの材質に別の材質をratioの割合で混ぜる
+    fn mix(mut self, other: Arc<dyn Material>, ratio: f64) -> Self {
+        assert!(self.texture.is_none(), "テクスチャで混ぜるときはmix_textureを使う");
+        self.material = Some(Arc::new(MixMaterial::with_ratio(self.material.unwrap(), other, ratio)));
+        self
+    }
+
+    // 設定済みの材質に別の材質を混ぜる．設定済みのテクスチャを重みに使う．
+    fn mix_texture(mut self, other: Arc<dyn Material>) -> Self {
+        self.material = Some(Arc::new(MixMaterial::new(self.material.unwrap(), other, self.texture.unwrap())));
+        self.texture = None;
+        self
+    }
+
+    // 形状
+
+    fn sphere(mut self, center: Point3, radius: f64) -> Self {
+        self.shape = Some(Box::new(Sphere::new(center, radius, self.material.unwrap())));
+        self.material = None;
+        self
+    }
+
+    fn rect_xy(mut self, x0: f64, x1: f64, y0: f64, y1: f64, k: f64) -> Self {
+        self.shape = Some(Box::new(Rect::new(x0, x1, y0, y1, k, RectAxisType::XY, self.material.unwrap())));
+        self.material = None;
+        self
+    }
+
+    fn rect_xz(mut self, x0: f64, x1: f64, y0: f64, y1: f64, k: f64) -> Self {
+        self.shape = Some(Box::new(Rect::new(x0, x1, y0, y1, k, RectAxisType::XZ, self.material.unwrap())));
+        self.material = None;
+        self
+    }
+
+    fn rect_yz(mut self, x0: f64, x1: f64, y0: f64, y1: f64, k: f64) -> Self {
+        self.shape = Some(Box::new(Rect::new(x0, x1, y0, y1, k, RectAxisType::YZ, self.material.unwrap())));
+        self.material = None;
+        self
+    }
+    fn box3d(mut self, p0: Point3, p1: Point3) -> Self {
+        self.shape = Some(Box::new(Box3D::new(p0, p1, self.material.unwrap())));
+        self.material = None;
+        self
+    }
+
+    // decorators
+
+    fn flip_face(mut self) -> Self {
+        self.shape = Some(Box::new(FlipFace::new(self.shape.unwrap())));
+        self
+    }
+
+    fn translate(mut self, offset: Point3) -> Self {
+        self.shape = Some(Box::new(Translate::new(self.shape.unwrap(), offset)));
+        self
+    }
+
+    fn rotate(mut self, axis: Vec3, angle: f64) -> Self {
+        self.shape = Some(Box::new(Rotate::new(self.shape.unwrap(), axis, angle)));
+        self
+    }
+
+    // 接ベクトルをaxisに揃える(異方性材質の向き)
+    fn tangent_axis(mut self, axis: Vec3) -> Self {
+        self.shape = Some(Box::new(TangentFrame::new(self.shape.unwrap(), Some(axis), 0.0)));
+        self
+    }
+
+    // 接ベクトルを法線周りにangle(度)回す
+    fn tangent_angle(mut self, angle: f64) -> Self {
+        self.shape = Some(Box::new(TangentFrame::new(self.shape.unwrap(), None, angle)));
+        self
+    }
+
+    // 形状の内側を散乱媒質で満たす．テクスチャは媒質のアルベド．
+    // 例: .color_texture(albedo).dielectric(1.4).sphere(center, radius).subsurface(mfp)
+    fn subsurface(mut self, mfp: f64) -> Self {
+        self.shape = Some(Box::new(Subsurface::new(self.shape.unwrap(), self.texture.unwrap(), mfp)));
+        self.texture = None;
+        self
+    }
+
+    // 画像のアルファチャンネルで形状を切り抜く
+    fn alpha_mask(mut self, path: &str) -> Self {
+        self.shape = Some(Box::new(AlphaMask::new(self.shape.unwrap(), Box::new(ImageTexture::new(path)))));
+        self
+    }
+
+    fn build(self) -> Box<dyn Shape> {
+        self.shape.unwrap()
+    }
+}
+
+// struct SimpleScene {
 //     world: ShapeList,
 // }
 
-// impl SimpleScene {
-    // fn new() -> Self {
-    //     let mut world = ShapeList::new();
-    //     world.push(ShapeBuilder::new()
-    //         .image_texture("resources/brick_diffuse.jpg")
-    //         .lambertian()
-    //         .sphere(Point3::new(0.6, 0.0, -1.0), 0.5)
-    //         .build());
-    //     world.push(ShapeBuilder::new()
-    //         .color_texture(Color::new(0.8, 0.8, 0.8))
-    //         .metal(0.4)
-    //         .sphere(Point3::new(-0.6, 0.0, -1.0), 0.5)
-    //         .build());
-    //     world.push(ShapeBuilder::new()
-    //         .checker_texture(
-    //             Color::new(0.8, 0.8, 0.0),
-    //             Color::new(0.8, 0.2, 0.0),
-    //             10.0
-    //         )
-    //         .lambertian()
-    //         .sphere(Point3::new(0.0, -100.5, -1.0), 100.0)
-    //         .build());
-        
-    //     Self { world }
-    // }
+// impl SimpleScene {
+    // fn new() -> Self {
+    //     let mut world = ShapeList::new();
+    //     world.push(ShapeBuilder::new()
+    //         .image_texture("resources/brick_diffuse.jpg")
+    //         .lambertian()
+    //         .sphere(Point3::new(0.6, 0.0, -1.0), 0.5)
+    //         .build());
+    //     world.push(ShapeBuilder::new()
+    //         .color_texture(Color::new(0.8, 0.8, 0.8))
+    //         .metal(0.4)
+    //         .sphere(Point3::new(-0.6, 0.0, -1.0), 0.5)
+    //         .build());
+    //     world.push(ShapeBuilder::new()
+    //         .checker_texture(
+    //             Color::new(0.8, 0.8, 0.0),
+    //             Color::new(0.8, 0.2, 0.0),
+    //             10.0
+    //         )
+    //         .lambertian()
+    //         .sphere(Point3::new(0.0, -100.5, -1.0), 100.0)
+    //         .build());
+        
+    //     Self { world }
+    // }
+
+    // fn background(&self, d: Vec3) -> Color {
+    //     let t = 0.5 * (d.normalize().y() + 1.0);
+    //     Color::one().lerp(Color::new(0.5, 0.7, 1.0), t)
+    // }
+
+    // 四角形のレンダリング
+//     fn new() -> Self {
+//         let mut world = ShapeList::new();
+//         world.push(ShapeBuilder::new()
+//             .color_texture(Color::full(0.5))
+//             .lambertian()
+//             .sphere(Point3::new(0.0, 2.0, 0.0), 2.0)
+//             .build());
+//         world.push(ShapeBuilder::new()
+//             .color_texture(Color::full(4.0))
+//             .diffuse_light()
+//             .rect_xy(3.0, 5.0, 1.0, 3.0, -2.0)
+//             .build());
+//         world.push(ShapeBuilder::new()
+//             .color_texture(Color::full(0.8))
+//             .lambertian()
+//             .sphere(Point3::new(0.0, -1000.0, 0.0), 1000.0)
+//             .build());
+        
+//         Self { world }
+//     }
+    
+//     fn background(&self, _:Vec3) -> Color {
+//         Color::full(0.1)
+//     }
+// }
+
+// impl SceneWithDepth for SimpleScene {
+//     fn camera(&self) -> Camera {
+//         Camera::from_lookat(
+//             Vec3::new(13.0, 2.0, 3.0),
+//             Vec3::yaxis(),
+//             Vec3::yaxis(),
+//             30.0,
+//             self.aspect(),
+//         )
+//     }
+
+//     fn trace(&self, ray: Ray, depth: usize) -> Color {
+//         let hit_info = self.world.hit(&ray, 0.001, f64::MAX);
+//         if let Some(hit) = hit_info {
+//             let emitted = hit.m.emitted(&ray, &hit);
+//             let scatter_info = if depth > 0 { hit.m.scatter(&ray, &hit) } else { None };
+//             if let Some(scatter) = scatter_info {
+//                 emitted + scatter.albedo * self.trace(scatter.ray, depth - 1)
+//             } else {
+//                 emitted
+//             }
+//         } else {
+//             self.background(ray.direction)
+//         }
+//     }
+// }
+
+struct CornelBoxScene {
+    world: ShapeList,
+    // 光源リストか光源の階層構造
+    lights: Arc<dyn Shape>,
+    // 光る物体の一覧．双方向パストレーシングで光源から経路を伸ばすのに使う．
+    emitters: Arc<LightList>,
+    punctual: Vec<Box<dyn PunctualLight>>,
+    depth_limits: DepthLimits,
+    // 経路誘導に使う入射光の分布
+    guide: Option<Arc<SdTree>>,
+    // 背景の環境光．無ければ黒．
+    environment: Option<Arc<dyn Environment>>,
+    sampler: SamplerKind,
+    seed: u64,
+}
+
+impl CornelBoxScene {
+    fn new() -> Self {
+        let mut world = ShapeList::new();
+
+        let red = Color::new(0.64, 0.05, 0.05);
+        let white = Color::full(0.73);
+        let green = Color::new(0.12, 0.45, 0.15);
+    
+        world.push(ShapeBuilder::new()
+            .color_texture(green)
+            .lambertian()
+            .rect_yz(0.0, 555.0, 0.0, 555.0, 555.0)
+            .flip_face()
+            .build());
+        world.push(ShapeBuilder::new()
+            .color_texture(red)
+            .lambertian()
+            .rect_yz(0.0, 555.0, 0.0, 555.0, 0.0)
+            .build());
+        world.push(ShapeBuilder::new()
+            .color_texture(Color::full(15.0))
+            .diffuse_light()
+            .rect_xz(213.0, 343.0, 227.0, 332.0, 554.0)
+            .flip_face()
+            .build());
+        world.push(ShapeBuilder::new()
+            .color_texture(white)
+            .lambertian()
+            .rect_xz(0.0, 555.0, 0.0, 555.0, 555.0)
+            .flip_face()
+            .build());
+        world.push(ShapeBuilder::new()
+            .color_texture(white)
+            .lambertian()
+            .rect_xz(0.0, 555.0, 0.0, 555.0, 0.0)
+            .build());
+        world.push(ShapeBuilder::new()
+            .color_texture(white)
+            .lambertian()
+            .rect_xy(0.0, 555.0, 0.0, 555.0, 555.0)
+            .flip_face()
+            .build());
+        
+        world.push(ShapeBuilder::new()
+            .dielectric(1.5)
+            .sphere(Point3::new(190.0, 90.0, 190.0), 90.0)
+            .build());
+        world.push(ShapeBuilder::new()
+            .color_texture(white)
+            .lambertian()
+            .box3d(Point3::zero(), Point3::new(165.0, 330.0, 165.0))
+            .rotate(Vec3::yaxis(), 15.0)
+            .translate(Point3::new(265.0, 0.0, 295.0))
+            .build());
+
+        Self::from_world(world)
+    }
+
+    // 光る物体を光源として集める．光源サンプリングと光源からの経路で同じ一覧を使う．
+    fn from_world(world: ShapeList) -> Self {
+        let emitters = Arc::new(world.emitters());
+        let lights: Arc<dyn Shape> = Arc::clone(&emitters) as Arc<dyn Shape>;
+        Self { world, lights, emitters, punctual: Vec::new(), depth_limits: DepthLimits::new(), guide: None, environment: None, sampler: SamplerKind::Independent, seed: 0 }
+    }
+
+    fn with_guide(mut self, guide: Arc<SdTree>) -> Self {
+        self.guide = Some(guide);
+        self
+    }
+
+    // 光源を光る物体の階層構造から選ぶ．光源が多くても，点ごとに寄与の大きい光源を選びやすい．
+    // 環境光を加えると光源を包み直すので，with_environmentより先に呼ぶ．
+    fn with_light_bvh(mut self) -> Self {
+        assert!(self.environment.is_none(), "with_light_bvhはwith_environmentより先に呼ぶ");
+        self.lights = Arc::new(self.world.light_bvh());
+        self
+    }
+
+    // 環境光を背景にして光源にも加える．powerは他の光源と比べて選ぶ割合の目安．
+    // 光源から経路を伸ばす双方向パストレーシングやフォトンマップの光源には含めない．
+    fn with_environment(mut self, environment: Arc<dyn Environment>, power: f64) -> Self {
+        self.lights = Arc::new(LightList::from_lights(vec![
+            (Arc::clone(&self.lights), self.lights.power()),
+            (Arc::clone(&environment) as Arc<dyn Shape>, power),
+        ]));
+        self.environment = Some(environment);
+        self
+    }
+
+    fn with_sampler(mut self, sampler: SamplerKind) -> Self {
+        self.sampler = sampler;
+        self
+    }
+
+    fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    fn with_depth_limits(mut self, depth_limits: DepthLimits) -> Self {
+        self.depth_limits = depth_limits;
+        self
+    }
+
+    fn push_light(&mut self, light: Box<dyn PunctualLight>) {
+        self.punctual.push(light);
+    }
+
+    fn background(&self, d: Vec3) -> Color {
+        self.environment.as_ref().map_or(Color::zero(), |environment| environment.value(d))
+    }
+
+    // 光線の先で見える光．物体の放射か背景．
+    fn emission(&self, ray: &Ray) -> Color {
+        match self.world.hit(ray, 0.001, f64::MAX) {
+            Some(hit) => {
+                let hit = hit.resolve();
+                hit.m.emitted(ray, &hit)
+            }
+            None => self.background(ray.direction),
+        }
+    }
+
+    // 光源サンプリングとBSDFサンプリングをパワーヒューリスティックで重み付けして足す(MIS)．
+    // path.prevは直前の衝突点とBSDFサンプリングのpdfで，光源に当たったときの重みに使う．
+    // カメラからの光線と鏡面反射の後はNoneで，放射をそのまま数える．
+    fn radiance(&self, ray: Ray, depth: usize, path: PathState) -> Color {
+        let weight = |light_pdf: f64| match path.prev {
+            Some((_, bsdf_pdf)) => power_heuristic(bsdf_pdf, light_pdf),
+            None => 1.0,
+        };
+        let light_pdf = |d: Vec3| match path.prev {
+            Some((p, _)) if self.lights.power() > 0.0 => self.lights.pdf_value(p, d),
+            _ => 0.0,
+        };
+        start_dimension(path.bounce, Site::Scatter);
+        let hit = match self.world.hit(&ray, 0.001, f64::MAX) {
+            Some(hit) => hit.resolve().with_ray_differential(&ray),
+            None => return self.background(ray.direction) * weight(light_pdf(ray.direction)),
+        };
+        let mut emitted = hit.m.emitted(&ray, &hit);
+        if emitted != Color::zero() {
+            emitted *= weight(light_pdf(ray.direction));
+        }
+        let scatter = match if depth > 0 { hit.m.scatter(&ray, &hit) } else { None } {
+            Some(scatter) => scatter,
+            None => return emitted,
+        };
+        // 散乱の種類ごとの反射回数が上限に達したら，この点では散乱させない
+        if path.count(scatter.lobe) >= self.depth_limits.limit(scatter.lobe) {
+            return emitted;
+        }
+        if scatter.lobe.is_delta() {
+            return match self.continue_path(&path, scatter.albedo, scatter.lobe) {
+                Some(next) => emitted + scatter.albedo * self.radiance(scatter.ray, depth - 1, next.with_prev(None)) / next.survival,
+                None => emitted,
+            };
+        }
+        let pdf = scatter.pdf.unwrap();
+        // 経路誘導．学習した入射光の分布とBSDFを半分ずつ混ぜてサンプリングする．
+        let guide = self.guide.as_ref().map(|guide| guide.leaf(hit.p));
+        let pdf: Arc<dyn Pdf> = match guide.as_ref().and_then(|leaf| leaf.sampling()) {
+            Some(tree) => Arc::new(MixturePdf::new(pdf, Arc::new(GuidePdf::new(tree)))),
+            None => pdf,
+        };
+
+        let mut color = emitted + punctual_lighting(&self.world, &self.punctual, &ray, &hit, scatter.albedo);
+        // 光源サンプリング
+        if self.lights.power() > 0.0 {
+            start_dimension(path.bounce, Site::Light);
+            let light_ray = Ray::new(hit.p, self.lights.random(hit.p));
+            let light_pdf = self.lights.pdf_value(hit.p, light_ray.direction);
+            let f = hit.m.scattering_pdf(&ray, &hit, &light_ray);
+            if light_pdf > 0.0 && f > 0.0 {
+                let w = power_heuristic(light_pdf, pdf.value(&hit, light_ray.direction));
+                let le = self.emission(&light_ray);
+                color += scatter.albedo * f * le * (w / light_pdf);
+                // 光源サンプリングで数えた分の入射光も学習する
+                if let Some(leaf) = &guide {
+                    leaf.record(light_ray.direction, le.luminance() * w / light_pdf);
+                }
+            }
+        }
+        // BSDFサンプリング
+        start_dimension(path.bounce, Site::Bsdf);
+        let new_ray = Ray::new(hit.p, pdf.generate(&hit));
+        let bsdf_pdf = pdf.value(&hit, new_ray.direction);
+        if bsdf_pdf > 0.0 {
+            let f = hit.m.scattering_pdf(&ray, &hit, &new_ray);
+            let weight = scatter.albedo * f / bsdf_pdf;
+            if let Some(next) = self.continue_path(&path, weight, scatter.lobe) {
+                let next = next.with_prev(Some((hit.p, bsdf_pdf)));
+                let li = self.radiance(new_ray, depth - 1, next);
+                color += weight * li / next.survival;
+                if let Some(leaf) = &guide {
+                    leaf.record(new_ray.direction, li.luminance() / bsdf_pdf);
+                }
+            }
+        }
+        color
+    }
+
+    // ロシアンルーレット．roulette_depth回目の反射からは，スループットに比例する確率でだけ
+    // 光線を続ける．続けた光線は生き残った確率で割るので偏りは出ない．
+    fn continue_path(&self, path: &PathState, weight: Color, lobe: Lobe) -> Option<PathState> {
+        let throughput = path.throughput * weight;
+        let survival = if path.bounce < self.roulette_depth() {
+            1.0
+        } else {
+            throughput.iter().fold(0.0_f64, |acc, &x| acc.max(x)).min(0.95)
+        };
+        start_dimension(path.bounce, Site::Roulette);
+        if survival <= 0.0 || (survival < 1.0 && Vec3::random_full().x() >= survival) {
+            return None;
+        }
+        let mut lobes = path.lobes;
+        lobes[lobe as usize] += 1;
+        Some(PathState { bounce: path.bounce + 1, lobes, throughput: throughput / survival, prev: None, survival })
+    }
+}
+
+// 散乱の種類ごとの反射回数の上限．鏡面反射は全体の上限(max_depth)だけで打ち切る．
+#[derive(Clone, Copy)]
+struct DepthLimits {
+    diffuse: usize,
+    glossy: usize,
+    transmission: usize,
+}
+
+impl DepthLimits {
+    // 制限なし
+    fn new() -> Self {
+        Self { diffuse: usize::MAX, glossy: usize::MAX, transmission: usize::MAX }
+    }
+
+    fn limit(&self, lobe: Lobe) -> usize {
+        match lobe {
+            Lobe::Diffuse => self.diffuse,
+            Lobe::Glossy => self.glossy,
+            Lobe::Specular => usize::MAX,
+            Lobe::Transmission => self.transmission,
+        }
+    }
+}
+
+// 経路の状態．反射回数，カメラからのスループット，MIS用の直前の衝突点とpdf．
+#[derive(Clone, Copy)]
+struct PathState {
+    bounce: usize,
+    // 散乱の種類ごとの反射回数
+    lobes: [usize; 4],
+    throughput: Color,
+    prev: Option<(Point3, f64)>,
+    // 直前のロシアンルーレットで生き残った確率
+    survival: f64,
+}
+
+impl PathState {
+    fn new() -> Self {
+        Self { bounce: 0, lobes: [0; 4], throughput: Color::one(), prev: None, survival: 1.0 }
+    }
+
+    fn count(&self, lobe: Lobe) -> usize {
+        self.lobes[lobe as usize]
+    }
+
+    fn with_prev(self, prev: Option<(Point3, f64)>) -> Self {
+        Self { prev, ..self }
+    }
+}
+
+// 双方向パストレーシングの経路の長さの上限
+const BDPT_MAX_DEPTH: usize = 10;
+// 光源からの経路に使う乱数の次元は，カメラからの経路(最大BDPT_MAX_DEPTH + 2頂点)の後の反復回数から始める
+const BDPT_LIGHT_BOUNCE: usize = BDPT_MAX_DEPTH + 2;
+
+// 双方向パストレーシングの経路の頂点
+#[derive(Clone, Copy, PartialEq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+}
+
+// pdf_fwdは経路を作った向きに，pdf_revは逆向きにこの頂点を選ぶ面積あたりの確率密度．MISの重みに使う．
+#[derive(Clone)]
+struct Vertex {
+    kind: VertexKind,
+    p: Point3,
+    // カメラでは視線の向き
+    n: Vec3,
+    hit: Option<HitInfo>,
+    // この頂点に届いた光線
+    ray_in: Option<Ray>,
+    beta: Color,
+    // 鏡面反射などのデルタ分布の散乱．他の頂点と接続できない．
+    delta: bool,
+    pdf_fwd: f64,
+    pdf_rev: f64,
+}
+
+impl Vertex {
+    fn camera(p: Point3, forward: Vec3) -> Self {
+        Self {
+            kind: VertexKind::Camera, p, n: forward, hit: None, ray_in: None,
+            beta: Color::one(), delta: false, pdf_fwd: 1.0, pdf_rev: 0.0,
+        }
+    }
+
+    fn light(hit: HitInfo, beta: Color, pdf: f64) -> Self {
+        Self {
+            kind: VertexKind::Light, p: hit.p, n: hit.n, hit: Some(hit), ray_in: None,
+            beta, delta: false, pdf_fwd: pdf, pdf_rev: 0.0,
+        }
+    }
+
+    fn surface(hit: HitInfo, ray_in: Ray, beta: Color, pdf_fwd: f64) -> Self {
+        Self {
+            kind: VertexKind::Surface, p: hit.p, n: hit.n, hit: Some(hit), ray_in: Some(ray_in),
+            beta, delta: false, pdf_fwd, pdf_rev: 0.0,
+        }
+    }
+
+    // 頂点fromから見た立体角あたりのpdfを，この頂点での面積あたりに直す
+    fn convert_density(&self, pdf: f64, from: Point3) -> f64 {
+        let w = self.p - from;
+        let d2 = w.length_squared();
+        if d2 == 0.0 {
+            return 0.0;
+        }
+        let pdf = pdf / d2;
+        match self.kind {
+            VertexKind::Camera => pdf,
+            _ => pdf * self.n.dot(w.normalize()).abs(),
+        }
+    }
+
+    // 方向woへの寄与．表面ではBSDFとcosの積，光源では放射輝度とcosの積，カメラでは重要度とcosの積．
+    fn fcos(&self, camera: &Camera, wo: Vec3) -> Color {
+        let wo = wo.normalize();
+        match (self.kind, &self.hit) {
+            (VertexKind::Camera, _) => Color::full(camera.importance(wo) * wo.dot(self.n).max(0.0)),
+            (VertexKind::Light, Some(hit)) => {
+                hit.m.emitted(&Ray::new(self.p + wo, -wo), hit) * wo.dot(self.n).max(0.0)
+            }
+            (VertexKind::Surface, Some(hit)) => match hit.m.scatter(self.ray_in.as_ref().unwrap(), hit) {
+                Some(scatter) if !scatter.lobe.is_delta() => {
+                    scatter.albedo * hit.m.scattering_pdf(self.ray_in.as_ref().unwrap(), hit, &Ray::new(self.p, wo))
+                }
+                _ => Color::zero(),
+            },
+            _ => Color::zero(),
+        }
+    }
+
+    // 前の頂点prevから来て，次の頂点nextを選ぶ面積あたりの確率密度
+    fn pdf(&self, camera: &Camera, prev: Option<&Vertex>, next: &Vertex) -> f64 {
+        let w = next.p - self.p;
+        let pdf = match self.kind {
+            VertexKind::Camera => camera.pdf_direction(w),
+            VertexKind::Light => self.pdf_emission(w),
+            VertexKind::Surface => {
+                let (hit, prev) = match (&self.hit, prev) {
+                    (Some(hit), Some(prev)) => (hit, prev),
+                    _ => return 0.0,
+                };
+                match hit.m.scatter(&Ray::new(prev.p, self.p - prev.p), hit).and_then(|s| s.pdf) {
+                    Some(pdf) => pdf.value(hit, w),
+                    None => 0.0,
+                }
+            }
+        };
+        next.convert_density(pdf, self.p)
+    }
+
+    // 光源として方向wに光を出す立体角あたりの確率密度．法線の周りのcos分布．
+    fn pdf_emission(&self, w: Vec3) -> f64 {
+        w.normalize().dot(self.n).max(0.0) * FRAC_1_PI
+    }
+
+    // 光を出す表面か
+    fn is_emitter(&self) -> bool {
+        match (&self.hit, &self.ray_in) {
+            (Some(hit), Some(ray)) => hit.m.emitted(ray, hit) != Color::zero(),
+            _ => false,
+        }
+    }
+}
+
+// 双方向パストレーシング．カメラと光源から経路を伸ばし，頂点同士をつないだ全ての経路を
+// パワーヒューリスティックで重み付けして足す．光源の経路をカメラに直接つないだ光はスプラットする．
+impl CornelBoxScene {
+    fn bidirectional(&self, ray: Ray, depth: usize, splats: &mut Vec<(f64, f64, Color)>) -> Color {
+        let camera = self.camera();
+        let depth = depth.min(BDPT_MAX_DEPTH);
+        let (camera_path, escaped) = self.camera_subpath(&camera, ray, depth + 2);
+        let light_path = self.light_subpath(&camera, depth + 1);
+
+        let mut color = escaped;
+        for t in 1..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                if s + t < 2 || s + t - 2 > depth || (s == 1 && t == 1) {
+                    continue;
+                }
+                let (c, uv) = match self.connect(&camera, &light_path, &camera_path, s, t) {
+                    Some(connection) => connection,
+                    None => continue,
+                };
+                let c = c * self.mis_weight(&camera, &light_path, &camera_path, s, t);
+                match uv {
+                    Some((u, v)) => splats.push((u, v, c)),
+                    None => color += c,
+                }
+            }
+        }
+        // 点光源は経路を伸ばして当たることがないので，カメラの経路の頂点から直接つないで重み1で足す
+        for vertex in camera_path.iter().take(depth + 1).skip(1) {
+            if vertex.kind != VertexKind::Surface || vertex.delta {
+                continue;
+            }
+            for light in &self.punctual {
+                let sample = match light.illuminate(vertex.p) {
+                    Some(sample) => sample,
+                    None => continue,
+                };
+                let shadow_ray = Ray::new(vertex.p, sample.wi);
+                if self.world.hit(&shadow_ray, 0.001, sample.distance - 0.001).is_none() {
+                    color += vertex.beta * vertex.fcos(&camera, sample.wi) * sample.li;
+                }
+            }
+        }
+        color
+    }
+
+    // カメラからの経路．戻り値の色は背景に抜けた光．
+    fn camera_subpath(&self, camera: &Camera, ray: Ray, max_vertices: usize) -> (Vec<Vertex>, Color) {
+        let mut path = vec![Vertex::camera(ray.origin, camera.forward())];
+        let pdf = camera.pdf_direction(ray.direction);
+        let escaped = self.random_walk(ray, Color::one(), pdf, max_vertices, 0, &mut path);
+        (path, escaped)
+    }
+
+    // 光源からの経路．光源を放射束に比例して選び，表面上の点から法線の周りのcos分布で光を出す．
+    fn light_subpath(&self, camera: &Camera, max_vertices: usize) -> Vec<Vertex> {
+        let mut path = Vec::new();
+        if self.emitters.is_empty() || max_vertices == 0 {
+            return path;
+        }
+        start_dimension(BDPT_LIGHT_BOUNCE, Site::Light);
+        let (index, pmf) = self.emitters.distribution.sample_discrete(Vec3::random_full().x());
+        let (hit, pdf_pos) = match self.emitters.lights[index].sample_surface() {
+            Some(sample) => sample,
+            None => return path,
+        };
+        let hit = hit.resolve();
+        let light = Vertex::light(hit, Color::full(1.0 / (pmf * pdf_pos)), pmf * pdf_pos);
+        start_dimension(BDPT_LIGHT_BOUNCE, Site::Bsdf);
+        let wo = ONB::new(light.n).local(Vec3::random_cosine_direction());
+        let pdf_dir = light.pdf_emission(wo);
+        if pdf_dir <= 0.0 {
+            return path;
+        }
+        let beta = light.beta * light.fcos(camera, wo) / pdf_dir;
+        path.push(light);
+        if beta != Color::zero() {
+            self.random_walk(Ray::new(path[0].p, wo), beta, pdf_dir, max_vertices, BDPT_LIGHT_BOUNCE + 1, &mut path);
+        }
+        path
+    }
+
+    // 経路を伸ばす．pdfは光線の向きを選んだ立体角あたりの確率密度．背景に抜けた光を返す．
+    // first_bounceは最初の反射で使う乱数の次元の反射回数．
+    fn random_walk(&self, ray: Ray, beta: Color, pdf: f64, max_vertices: usize, first_bounce: usize, path: &mut Vec<Vertex>) -> Color {
+        let (mut ray, mut beta, mut pdf_fwd) = (ray, beta, pdf);
+        while path.len() < max_vertices {
+            let bounce = first_bounce + path.len() - 1;
+            start_dimension(bounce, Site::Scatter);
+            let hit = match self.world.hit(&ray, 0.001, f64::MAX) {
+                Some(hit) => hit.resolve(),
+                None => return self.background(ray.direction) * beta,
+            };
+            let prev = path.len() - 1;
+            let mut vertex = Vertex::surface(hit.clone(), ray, beta, 0.0);
+            vertex.pdf_fwd = vertex.convert_density(pdf_fwd, path[prev].p);
+            let scatter = match hit.m.scatter(&ray, &hit) {
+                Some(scatter) if path.len() + 1 < max_vertices => scatter,
+                _ => {
+                    path.push(vertex);
+                    break;
+                }
+            };
+            let (new_ray, pdf_rev) = match scatter.pdf.clone().filter(|_| !scatter.lobe.is_delta()) {
+                Some(pdf) => {
+                    start_dimension(bounce, Site::Bsdf);
+                    let new_ray = Ray::new(hit.p, pdf.generate(&hit));
+                    pdf_fwd = pdf.value(&hit, new_ray.direction);
+                    let f = hit.m.scattering_pdf(&ray, &hit, &new_ray);
+                    if pdf_fwd <= 0.0 || f <= 0.0 {
+                        path.push(vertex);
+                        break;
+                    }
+                    beta = beta * scatter.albedo * f / pdf_fwd;
+                    // 逆向きに光が来たときに元の方向を選ぶ確率密度
+                    let reverse = Ray::new(hit.p + new_ray.direction, -new_ray.direction);
+                    let pdf_rev = match hit.m.scatter(&reverse, &hit).filter(|s| !s.lobe.is_delta()).and_then(|s| s.pdf) {
+                        Some(pdf) => pdf.value(&hit, -ray.direction),
+                        None => 0.0,
+                    };
+                    (new_ray, pdf_rev)
+                }
+                None => {
+                    vertex.delta = true;
+                    pdf_fwd = 0.0;
+                    beta = beta * scatter.albedo;
+                    (scatter.ray, 0.0)
+                }
+            };
+            path[prev].pdf_rev = path[prev].convert_density(pdf_rev, hit.p);
+            path.push(vertex);
+            if beta == Color::zero() {
+                break;
+            }
+            ray = new_ray;
+        }
+        Color::zero()
+    }
+
+    // 光源の経路のs個とカメラの経路のt個の頂点をつないだ経路の寄与．
+    // t = 1のときは画面上の位置も返す．
+    fn connect(&self, camera: &Camera, light: &[Vertex], eye: &[Vertex], s: usize, t: usize) -> Option<(Color, Option<(f64, f64)>)> {
+        let pt = &eye[t - 1];
+        if s == 0 {
+            // カメラの経路が光源に当たった
+            let hit = pt.hit.as_ref()?;
+            return Some((pt.beta * hit.m.emitted(pt.ray_in.as_ref()?, hit), None));
+        }
+        let qs = &light[s - 1];
+        if qs.delta || pt.delta {
+            return None;
+        }
+        let d = qs.p - pt.p;
+        let uv = if t == 1 { Some(camera.project(qs.p)?) } else { None };
+        let c = qs.beta * qs.fcos(camera, -d) * pt.fcos(camera, d) * pt.beta / d.length_squared();
+        if c == Color::zero() || !self.visible(pt.p, qs.p) {
+            return None;
+        }
+        Some((c, uv))
+    }
+
+    fn visible(&self, p: Point3, q: Point3) -> bool {
+        self.world.hit(&Ray::new(p, q - p), 0.001 / (q - p).length(), 1.0 - 0.001 / (q - p).length()).is_none()
+    }
+
+    // 光源として頂点vを選ぶ面積あたりの確率密度
+    fn pdf_light_origin(&self, v: &Vertex) -> f64 {
+        let (ray, t) = match (&v.ray_in, &v.hit) {
+            (Some(ray), Some(hit)) => (ray, hit.t),
+            _ => return 0.0,
+        };
+        self.emitters.lights.iter().enumerate()
+            .find(|(_, light)| light.hit(ray, 0.001, f64::MAX).is_some_and(|h| (h.t - t).abs() < EPS))
+            .map_or(0.0, |(i, light)| self.emitters.distribution.pmf(i) / light.area())
+    }
+
+    // 同じ経路を他の(s, t)の組で作る確率密度との比から，パワーヒューリスティックの重みを求める
+    fn mis_weight(&self, camera: &Camera, light: &[Vertex], eye: &[Vertex], s: usize, t: usize) -> f64 {
+        if s + t == 2 {
+            return 1.0;
+        }
+        // (pdf_fwd, pdf_rev, delta)．つなぐ頂点の周りだけ逆向きのpdfを付け替える．
+        let mut lp: Vec<(f64, f64, bool)> = light[..s].iter().map(|v| (v.pdf_fwd, v.pdf_rev, v.delta)).collect();
+        let mut ep: Vec<(f64, f64, bool)> = eye[..t].iter().map(|v| (v.pdf_fwd, v.pdf_rev, v.delta)).collect();
+        let pt = &eye[t - 1];
+        let pt_minus = if t > 1 { Some(&eye[t - 2]) } else { None };
+        if s > 0 {
+            let qs = &light[s - 1];
+            let qs_minus = if s > 1 { Some(&light[s - 2]) } else { None };
+            ep[t - 1].1 = qs.pdf(camera, qs_minus, pt);
+            if let Some(pm) = pt_minus {
+                ep[t - 2].1 = pt.pdf(camera, Some(qs), pm);
+            }
+            lp[s - 1].1 = pt.pdf(camera, pt_minus, qs);
+            if let Some(qm) = qs_minus {
+                lp[s - 2].1 = qs.pdf(camera, Some(pt), qm);
+            }
+        } else {
+            ep[t - 1].1 = self.pdf_light_origin(pt);
+            if let Some(pm) = pt_minus {
+                ep[t - 2].1 = pm.convert_density(pt.pdf_emission(pm.p - pt.p), pt.p);
+            }
+        }
+
+        let remap = |x: f64| if x != 0.0 { x * x } else { 1.0 };
+        let mut sum = 0.0;
+        let mut ri = 1.0;
+        for i in (1..t).rev() {
+            ri *= remap(ep[i].1) / remap(ep[i].0);
+            if !ep[i].2 && !ep[i - 1].2 {
+                sum += ri;
+            }
+        }
+        ri = 1.0;
+        for i in (0..s).rev() {
+            ri *= remap(lp[i].1) / remap(lp[i].0);
+            let prev_delta = i > 0 && lp[i - 1].2;
+            if !lp[i].2 && !prev_delta {
+                sum += ri;
+            }
+        }
+        1.0 / (1.0 + sum)
+    }
+}
+
+impl SceneWithDepth for CornelBoxScene {
+    fn camera(&self) -> Camera {
+        Camera::from_lookat(
+            Vec3::new(278.0, 278.0, -800.0),
+            Vec3::new(278.0, 278.0, 0.0),
+            Vec3::yaxis(),
+            40.0,
+            self.aspect(),
+        ).with_film(self.width(), self.height())
+    }
+
+    fn trace(&self, ray: Ray, depth: usize) -> Color {
+        self.radiance(ray, depth, PathState::new())
+    }
+
+    fn width(&self) -> u32 { 200 }
+    fn height(&self) -> u32 { 200 }
+    fn spp(&self) -> usize { 
+        if cfg!(test) {
+            32
+        } else {
+            1000
+        }
+    }
+    fn sampler(&self) -> SamplerKind { self.sampler }
+    fn seed(&self) -> u64 { self.seed }
+}
+
+impl SceneWithSplat for CornelBoxScene {
+    fn trace_splat(&self, ray: Ray, depth: usize, splats: &mut Vec<(f64, f64, Color)>) -> Color {
+        self.bidirectional(ray, depth, splats)
+    }
+}
+
+// 確率的プログレッシブフォトンマップ(Hachisuka and Jensen 2009)で探索半径を縮める割合
+const SPPM_ALPHA: f64 = 2.0 / 3.0;
+
+// 光源から運ばれてきた光．wiはフォトンが来た方向．
+struct Photon {
+    wi: Vec3,
+    power: Color,
+}
+
+// カメラから鏡面反射をたどって最初に当たった拡散面の点．betaはそこまでの重み．
+struct VisiblePoint {
+    hit: HitInfo,
+    ray: Ray,
+    beta: Color,
+}
+
+// 画素ごとの状態．nは数えたフォトンの数，tauは半径内のフォトンによる光の合計．
+struct PhotonPixel {
+    radius: f64,
+    n: f64,
+    tau: Color,
+    direct: Color,
+    passes: usize,
+}
+
+impl PhotonPixel {
+    // 半径内にm個のフォトンが見つかったら，一部だけ残すように半径を縮める
+    fn update(&mut self, phi: Color, m: usize) {
+        if m == 0 {
+            return;
+        }
+        let n = self.n + SPPM_ALPHA * m as f64;
+        let radius = self.radius * (n / (self.n + m as f64)).sqrt();
+        self.tau = (self.tau + phi) * (radius / self.radius).powi(2);
+        self.n = n;
+        self.radius = radius;
+    }
+}
+
+impl CornelBoxScene {
+    // カメラからの光線を鏡面反射と屈折に沿ってたどり，拡散面の点を返す．
+    // 途中で見えた放射と，拡散面での光源サンプリングによる直接光も返す．
+    fn visible_point(&self, ray: Ray, depth: usize) -> (Color, Option<VisiblePoint>) {
+        let (mut ray, mut beta, mut color) = (ray, Color::one(), Color::zero());
+        for bounce in 0..=depth {
+            start_dimension(bounce, Site::Scatter);
+            let hit = match self.world.hit(&ray, 0.001, f64::MAX) {
+                Some(hit) => hit.resolve().with_ray_differential(&ray),
+                None => return (color + beta * self.background(ray.direction), None),
+            };
+            color += beta * hit.m.emitted(&ray, &hit);
+            let scatter = match hit.m.scatter(&ray, &hit) {
+                Some(scatter) => scatter,
+                None => break,
+            };
+            if scatter.lobe.is_delta() {
+                beta = beta * scatter.albedo;
+                ray = scatter.ray;
+                continue;
+            }
+            if self.lights.power() > 0.0 {
+                start_dimension(bounce, Site::Light);
+                let light_ray = Ray::new(hit.p, self.lights.random(hit.p));
+                let pdf = self.lights.pdf_value(hit.p, light_ray.direction);
+                let f = hit.m.scattering_pdf(&ray, &hit, &light_ray);
+                if pdf > 0.0 && f > 0.0 {
+                    color += beta * scatter.albedo * f * self.emission(&light_ray) / pdf;
+                }
+            }
+            return (color, Some(VisiblePoint { hit, ray, beta: beta * scatter.albedo }));
+        }
+        (color, None)
+    }
+
+    // 光源からフォトンを1つ出して追跡し，拡散面に当たるたびに記録する．
+    // 光源から直接当たった点は視点側で光源サンプリングするので記録しない．
+    fn trace_photon(&self, depth: usize) -> Vec<(Point3, Photon)> {
+        let mut photons = Vec::new();
+        if self.emitters.is_empty() {
+            return photons;
+        }
+        let (index, pmf) = self.emitters.distribution.sample_discrete(Vec3::random_full().x());
+        let (hit, pdf_pos) = match self.emitters.lights[index].sample_surface() {
+            Some(sample) => sample,
+            None => return photons,
+        };
+        let hit = hit.resolve();
+        let wo = ONB::new(hit.n).local(Vec3::random_cosine_direction());
+        // cos分布で方向を選ぶので，放射輝度・cos / (cos / π)
+        let mut power = hit.m.emitted(&Ray::new(hit.p + wo, -wo), &hit) * PI / (pmf * pdf_pos);
+        let mut ray = Ray::new(hit.p, wo);
+        for bounce in 0..depth {
+            let hit = match self.world.hit(&ray, 0.001, f64::MAX) {
+                Some(hit) => hit.resolve(),
+                None => break,
+            };
+            let scatter = match hit.m.scatter(&ray, &hit) {
+                Some(scatter) => scatter,
+                None => break,
+            };
+            let weight = match scatter.pdf.clone().filter(|_| !scatter.lobe.is_delta()) {
+                Some(pdf) => {
+                    if bounce > 0 {
+                        photons.push((hit.p, Photon { wi: -ray.direction.normalize(), power }));
+                    }
+                    let new_ray = Ray::new(hit.p, pdf.generate(&hit));
+                    let pdf_value = pdf.value(&hit, new_ray.direction);
+                    if pdf_value <= 0.0 {
+                        break;
+                    }
+                    let weight = scatter.albedo * hit.m.scattering_pdf(&ray, &hit, &new_ray) / pdf_value;
+                    ray = new_ray;
+                    weight
+                }
+                None => {
+                    ray = scatter.ray;
+                    scatter.albedo
+                }
+            };
+            // 反射率に比例する確率で続ける
+            let survival = weight.iter().fold(0.0_f64, |acc, &x| acc.max(x)).min(1.0);
+            if survival <= 0.0 || Vec3::random_full().x() >= survival {
+                break;
+            }
+            power = power * weight / survival;
+        }
+        photons
+    }
+}
+
+// 確率的プログレッシブフォトンマップ．反復ごとに光源からフォトンを出してkd木に入れ，
+// 各画素の拡散面の点で半径内のフォトンから明るさを推定する．半径は反復ごとに縮む．
+// 鏡面反射を通った光(コースティクス)もくっきり出る．
+struct Sppm {
+    scene: CornelBoxScene,
+    passes: usize,
+    // 1回の反復で出すフォトンの数
+    photons: usize,
+    // 最初の探索半径
+    radius: f64,
+}
+
+impl Sppm {
+    fn new(scene: CornelBoxScene, passes: usize, photons: usize, radius: f64) -> Self {
+        Self { scene, passes, photons, radius }
+    }
+}
+
+impl SceneWithDepth for Sppm {
+    fn camera(&self) -> Camera {
+        self.scene.camera()
+    }
+
+    fn trace(&self, ray: Ray, depth: usize) -> Color {
+        self.scene.trace(ray, depth)
+    }
+
+    fn width(&self) -> u32 { self.scene.width() }
+    fn height(&self) -> u32 { self.scene.height() }
+    fn spp(&self) -> usize { self.passes }
+    fn sampler(&self) -> SamplerKind { self.scene.sampler() }
+    fn seed(&self) -> u64 { self.scene.seed() }
+}
+
+impl SceneWithPasses for Sppm {
+    type Pixel = PhotonPixel;
+
+    fn new_pixel(&self) -> PhotonPixel {
+        PhotonPixel { radius: self.radius, n: 0.0, tau: Color::zero(), direct: Color::zero(), passes: 0 }
+    }
+
+    fn pass(&self, rays: &[Ray], pixels: &mut [PhotonPixel]) {
+        let depth = self.max_depth();
+        // 視点からの経路は光線を作ったサンプラーの続きで，フォトンは反復とフォトンの番号で決まる乱数列でたどる
+        let pass = pixels.first().map_or(0, |p| p.passes);
+        let points: Vec<(Color, Option<VisiblePoint>)> = rays.par_iter().enumerate()
+            .map(|(i, &ray)| self.resume(i, pass, || self.scene.visible_point(ray, depth)))
+            .collect();
+        let photons = (0..self.photons).into_par_iter()
+            .flat_map_iter(|i| with_stream(self.seed(), &[1, pass as u64, i as u64], || self.scene.trace_photon(depth)))
+            .collect();
+        let map = KdTree::new(photons);
+
+        pixels.par_iter_mut().zip(points).for_each(|(pixel, (direct, point))| {
+            pixel.direct += direct;
+            pixel.passes += 1;
+            let point = match point {
+                Some(point) => point,
+                None => return,
+            };
+            let (mut phi, mut m) = (Color::zero(), 0);
+            map.for_each_within(point.hit.p, pixel.radius, |_, photon| {
+                let cos = photon.wi.dot(point.hit.n);
+                if cos <= 0.0 {
+                    return;
+                }
+                // scattering_pdfはBRDFとcosの積なのでcosで割る
+                let f = point.hit.m.scattering_pdf(&point.ray, &point.hit, &Ray::new(point.hit.p, photon.wi)) / cos;
+                phi += photon.power * f;
+                m += 1;
+            });
+            pixel.update(phi * point.beta, m);
+        });
+    }
+
+    fn pixel_color(&self, pixel: &PhotonPixel) -> Color {
+        if pixel.passes == 0 {
+            return Color::zero();
+        }
+        let photons = (pixel.passes * self.photons) as f64;
+        pixel.direct / pixel.passes as f64 + pixel.tau / (photons * PI * pixel.radius * pixel.radius)
+    }
+}
+
+// 経路誘導付きのパストレーシング．反復ごとのサンプル数を1, 2, 4, ...と倍にしながら，
+// 反復の終わりに入射光の分布を学習し直す．学習の足りない前の反復はばらつきが大きいので，
+// 画像には最後の反復だけを使う．最後の反復が途中で終わったときは一つ前の反復と合わせる．
+struct Guided {
+    scene: CornelBoxScene,
+    guide: Arc<SdTree>,
+    passes: usize,
+}
+
+impl Guided {
+    // min, maxは入射光を学習する範囲
+    fn new(scene: CornelBoxScene, min: Point3, max: Point3, passes: usize) -> Self {
+        let guide = Arc::new(SdTree::new(min, max));
+        Self { scene: scene.with_guide(Arc::clone(&guide)), guide, passes }
+    }
+}
+
+// 空間の葉を分ける記録数の目安．反復のサンプル数の平方根に比例させる．
+const SDTREE_SPLIT: f64 = 4000.0;
+
+impl SceneWithDepth for Guided {
+    fn camera(&self) -> Camera {
+        self.scene.camera()
+    }
+
+    fn trace(&self, ray: Ray, depth: usize) -> Color {
+        self.scene.trace(ray, depth)
+    }
+
+    fn width(&self) -> u32 { self.scene.width() }
+    fn height(&self) -> u32 { self.scene.height() }
+    fn spp(&self) -> usize { self.passes }
+    fn sampler(&self) -> SamplerKind { self.scene.sampler() }
+    fn seed(&self) -> u64 { self.scene.seed() }
+}
+
+// 画素ごとの状態．今の反復と一つ前の反復の(色の合計, サンプル数)と，全体の反復の数．
+struct GuidedPixel {
+    current: (Color, usize),
+    previous: (Color, usize),
+    passes: usize,
+}
+
+impl SceneWithPasses for Guided {
+    type Pixel = GuidedPixel;
+
+    fn new_pixel(&self) -> GuidedPixel {
+        GuidedPixel { current: (Color::zero(), 0), previous: (Color::zero(), 0), passes: 0 }
+    }
+
+    fn pass(&self, rays: &[Ray], pixels: &mut [GuidedPixel]) {
+        let depth = self.max_depth();
+        // 経路は光線を作ったサンプラーの続きでたどる．学習する明るさは固定小数点で足すので，
+        // 記録する順番によらず同じ分布になる
+        pixels.par_iter_mut().zip(rays).enumerate().for_each(|(i, (pixel, &ray))| {
+            // 学習し直した後は新しい反復として数え直す
+            if pixel.passes > 0 && (pixel.passes + 1).is_power_of_two() {
+                pixel.previous = pixel.current;
+                pixel.current = (Color::zero(), 0);
+            }
+            pixel.current.0 += self.resume(i, pixel.passes, || self.scene.trace(ray, depth));
+            pixel.current.1 += 1;
+            pixel.passes += 1;
+        });
+        // 1, 3, 7, ...回目の後で学習し直す．その反復のサンプル数は1, 2, 4, ...
+        let passes = pixels.first().map_or(0, |p| p.passes);
+        if (passes + 1).is_power_of_two() {
+            let spp = passes / 2 + 1;
+            self.guide.refine((SDTREE_SPLIT * (spp as f64).sqrt()) as usize);
+        }
+    }
+
+    fn pixel_color(&self, pixel: &GuidedPixel) -> Color {
+        let (mut sum, mut samples) = pixel.current;
+        // 反復のサンプル数は前の反復の2倍なので，それより少なければ途中で終わっている
+        if samples < 2 * pixel.previous.1 {
+            sum += pixel.previous.0;
+            samples += pixel.previous.1;
+        }
+        if samples == 0 { Color::zero() } else { sum / samples as f64 }
+    }
+}
+
+pub fn run() {
+    render_aa_with_depth(CornelBoxScene::new());
+}
+
+pub fn run_bdpt() {
+    render_with_splats(CornelBoxScene::new());
+}
+
+pub fn run_sppm() {
+    render_progressive(Sppm::new(CornelBoxScene::new(), 64, 100_000, 4.0));
+}
+
+pub fn run_mlt() {
+    render_metropolis(CornelBoxScene::new());
+}
+
+// サンプラーを選んでコーネルボックスを描く
+pub fn run_with_sampler(sampler: SamplerKind) {
+    render_aa_with_depth(CornelBoxScene::new().with_sampler(sampler));
+}
+
+// 昼光の空の下の地面に箱とガラス球を置いた場面
+pub fn run_sky() {
+    let mut world = ShapeList::new();
+    world.push(ShapeBuilder::new()
+        .color_texture(Color::full(0.5))
+        .lambertian()
+        .rect_xz(-1.0e5, 1.0e5, -1.0e5, 1.0e5, 0.0)
+        .build());
+    world.push(ShapeBuilder::new()
+        .dielectric(1.5)
+        .sphere(Point3::new(190.0, 90.0, 190.0), 90.0)
+        .build());
+    world.push(ShapeBuilder::new()
+        .color_texture(Color::full(0.73))
+        .lambertian()
+        .box3d(Point3::zero(), Point3::new(165.0, 330.0, 165.0))
+        .rotate(Vec3::yaxis(), 15.0)
+        .translate(Point3::new(265.0, 0.0, 295.0))
+        .build());
+    let sky = Sky::new(35.0, -120.0, 3.0, Color::full(0.3));
+    render_aa_with_depth(CornelBoxScene::from_world(world).with_environment(Arc::new(sky), 1.0));
+}
+
+pub fn run_guided() {
+    render_progressive(Guided::new(CornelBoxScene::new(), Point3::zero(), Point3::full(555.0), 255));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mask_rect(alpha: u8) -> AlphaMask {
+        let rect = ShapeBuilder::new()
+            .color_texture(Color::one())
+            .lambertian()
+            .rect_xy(-1.0, 1.0, -1.0, 1.0, 0.0)
+            .build();
+        let mask = image::RgbaImage::from_pixel(2, 2, image::Rgba([255, 255, 255, alpha]));
+        AlphaMask::new(rect, Box::new(ImageTexture::from_rgba(mask, ColorSpace::Srgb)))
+    }
+
+    #[test]
+    fn test_alpha_mask() {
+        let ray = Ray::new(Point3::new(0.0, 0.0, 1.0), -Vec3::zaxis());
+        assert!(mask_rect(255).hit(&ray, 0.001, f64::MAX).is_some());
+        assert!(mask_rect(0).hit(&ray, 0.001, f64::MAX).is_none());
+        // 半透明なら不透明度の割合で当たる
+        let half = mask_rect(128);
+        let n = 10000;
+        let hits = (0..n).filter(|_| half.hit(&ray, 0.001, f64::MAX).is_some()).count();
+        assert!((hits as f64 / n as f64 - 128.0 / 255.0).abs() < 0.03);
+    }
+
+    #[test]
+    fn test_subsurface() {
+        let medium = |mfp| ShapeBuilder::new()
+            .color_texture(Color::one())
+            .dielectric(1.4)
+            .sphere(Point3::zero(), 1.0)
+            .subsurface(mfp)
+            .build();
+        // 外から入る光線は必ず境界に当たる
+        let outside = Ray::new(Point3::new(0.0, 0.0, 2.0), -Vec3::zaxis());
+        let hit = medium(1e-6).hit(&outside, 0.001, f64::MAX).unwrap();
+        assert!((hit.t - 1.0).abs() < EPS);
+        // 内側の光線は平均自由行程に応じて途中で散乱する
+        let inside = Ray::new(Point3::zero(), Vec3::zaxis());
+        assert!(medium(1e-6).hit(&inside, 1e-9, f64::MAX).unwrap().t < 0.5);
+        assert!((medium(1e6).hit(&inside, 1e-9, f64::MAX).unwrap().t - 1.0).abs() < EPS);
+    }
+
+    #[test]
+    fn test_oren_nayar() {
+        let m: Arc<dyn Material> = Arc::new(Lambertian::new(Box::new(ColorTexture::new(Color::one()))));
+        let smooth = OrenNayar::new(Box::new(ColorTexture::new(Color::one())), 0.0);
+        let rough = OrenNayar::new(Box::new(ColorTexture::new(Color::one())), 30.0);
+        let hit = HitInfo::new(1.0, Point3::zero(), Vec3::zaxis(), m, 0.0, 0.0);
+        let ray = Ray::new(Point3::new(1.0, 0.0, 1.0), Vec3::new(-1.0, 0.0, -1.0));
+        for _ in 0..100 {
+            let scattered = Ray::new(hit.p, CosinePdf::new().generate(&hit));
+            let expected = hit.m.scattering_pdf(&ray, &hit, &scattered);
+            assert!((smooth.scattering_pdf(&ray, &hit, &scattered) - expected).abs() < EPS);
+        }
+        // 粗い面は視線方向(後方)へ強く返す
+        let back = Ray::new(hit.p, Vec3::new(1.0, 0.0, 1.0));
+        let forward = Ray::new(hit.p, Vec3::new(-1.0, 0.0, 1.0));
+        assert!(rough.scattering_pdf(&ray, &hit, &back) > rough.scattering_pdf(&ray, &hit, &forward));
+    }
+
+    #[test]
+    fn test_thin_film() {
+        let base: Arc<dyn Material> = Arc::new(Dielectric::new(1.5));
+        // 膜が無ければ母材のフレネル反射と同じ灰色になる
+        let bare = ThinFilm::new(Arc::clone(&base), 0.0, 1.33, 1.5).reflectance_rgb(1.0);
+        for c in bare.iter() {
+            assert!((c - Dielectric::fresnel(1.0, 1.0, 1.5)).abs() < 1e-3);
+        }
+        // 膜があると波長によって反射率が変わり色がつく
+        let film = ThinFilm::new(Arc::clone(&base), 300.0, 1.33, 1.5).reflectance_rgb(1.0);
+        assert!((film.x() - film.z()).abs() > 1e-3);
+        // 反射と透過を合わせると入射した光のまま．母材のフレネル反射を重ねて数えない．
+        let material = ThinFilm::new(base, 0.0, 1.33, 1.5);
+        let ray = Ray::new(Point3::new(0.0, 1.0, 0.0), Vec3::new(0.3, -1.0, 0.0));
+        let hit = HitInfo::new(1.0, Point3::zero(), Vec3::yaxis(), Arc::new(Lambertian::new(Box::new(ColorTexture::new(Color::one())))), 0.0, 0.0);
+        let n = 20000;
+        let (mut reflected, mut transmitted) = (0.0, 0.0);
+        for _ in 0..n {
+            let s = material.scatter(&ray, &hit).unwrap();
+            if s.ray.direction.dot(hit.n) > 0.0 { reflected += s.albedo.y() } else { transmitted += s.albedo.y() }
+        }
+        let fresnel = Dielectric::fresnel(ray.direction.normalize().dot(-hit.n), 1.0, 1.5);
+        assert!((reflected / n as f64 - fresnel).abs() < 0.01);
+        assert!((transmitted / n as f64 - (1.0 - fresnel)).abs() < 0.01);
+        // 金属の上の膜(陽極酸化)では，膜を透過した光も金属で反射して戻る
+        let metal = ThinFilm::new(Arc::new(Metal::new(Box::new(ColorTexture::new(Color::full(0.5))), 0.0)), 300.0, 1.6, 2.5);
+        let reflectance = metal.reflectance_rgb(ray.direction.normalize().dot(-hit.n));
+        let mut total = Color::zero();
+        for _ in 0..n {
+            let s = metal.scatter(&ray, &hit).unwrap();
+            assert!(s.ray.direction.dot(hit.n) > 0.0);
+            total += s.albedo;
+        }
+        let expected = reflectance + (Color::one() - reflectance) * 0.5;
+        for (c, e) in (total / n as f64).iter().zip(expected.iter()) {
+            assert!((c - e).abs() < 0.02);
+        }
+    }
+
+    #[test]
+    fn test_ggx_pdf() {
+        let onb = ONB::from_tangent(Vec3::zaxis(), Vec3::xaxis());
+        let wi = Vec3::new(0.3, 0.2, 1.0);
+        let pdf = GgxPdf::new(onb, wi, 0.1, 0.5);
+        let m: Arc<dyn Material> = Arc::new(Dielectric::new(1.5));
+        let hit = HitInfo::new(1.0, Point3::zero(), Vec3::zaxis(), m, 0.0, 0.0);
+        // 生成した方向が範囲に入る割合は，その範囲でのpdfの積分に一致する．
+        // 積分はcosθとφの格子で求める．
+        let quadrant = |d: Vec3| d.z() > 0.0 && d.x() < 0.0 && d.y() < 0.0;
+        let (nt, np) = (400, 400);
+        let mut integral = [0.0; 2];
+        for i in 0..nt {
+            let cos_t = (i as f64 + 0.5) / nt as f64;
+            let sin_t = (1.0 - cos_t * cos_t).sqrt();
+            for j in 0..np {
+                let phi = (j as f64 + 0.5) / np as f64 * PI2;
+                let d = Vec3::new(sin_t * phi.cos(), sin_t * phi.sin(), cos_t);
+                let p = pdf.value(&hit, d) * PI2 / (nt * np) as f64;
+                integral[0] += p;
+                if quadrant(d) {
+                    integral[1] += p;
+                }
+            }
+        }
+        let n = 200000;
+        let (upper, inside) = (0..n).fold((0, 0), |(upper, inside), _| {
+            let d = pdf.generate(&hit);
+            (upper + (d.z() > 0.0) as usize, inside + quadrant(d) as usize)
+        });
+        assert!((upper as f64 / n as f64 - integral[0]).abs() < 0.01);
+        assert!((inside as f64 / n as f64 - integral[1]).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_perlin() {
+        let perlin = Perlin::new();
+        for _ in 0..1000 {
+            let p = Point3::random_limit(-10.0, 10.0);
+            // 勾配ノイズは格子点で0になる
+            let lattice = p.iter().map(|x| x.floor()).collect::<Point3>();
+            assert!(perlin.noise(lattice).abs() < EPS);
+            assert!(perlin.noise(p).abs() <= 1.0);
+            assert!(perlin.simplex(p).abs() <= 1.0);
+            assert!((0.0..=1.0).contains(&perlin.worley(p)));
+        }
+        // 表は作るたびに同じ
+        let other = Perlin::new();
+        let p = Point3::new(0.3, 1.7, -2.2);
+        assert_eq!(perlin.noise(p), other.noise(p));
+    }
 
-    // fn background(&self, d: Vec3) -> Color {
-    //     let t = 0.5 * (d.normalize().y() + 1.0);
-    //     Color::one().lerp(Color::new(0.5, 0.7, 1.0), t)
-    // }
+    #[test]
+    fn test_image_texture_sampling() {
+        // 左半分が黒，右半分が白の2x1画像
+        let mut img = image::RgbaImage::new(2, 1);
+        img.put_pixel(1, 0, image::Rgba([255, 255, 255, 255]));
+        img.put_pixel(0, 0, image::Rgba([0, 0, 0, 255]));
+        let p = Point3::zero();
 
-    // 四角形のレンダリング
-//     fn new() -> Self {
-//         let mut world = ShapeList::new();
-//         world.push(ShapeBuilder::new()
-//             .color_texture(Color::full(0.5))
-//             .lambertian()
-//             .sphere(Point3::new(0.0, 2.0, 0.0), 2.0)
-//             .build());
-//         world.push(ShapeBuilder::new()
-//             .color_texture(Color::full(4.0))
-//             .diffuse_light()
-//             .rect_xy(3.0, 5.0, 1.0, 3.0, -2.0)
-//             .build());
-//         world.push(ShapeBuilder::new()
-//             .color_texture(Color::full(0.8))
-//             .lambertian()
-//             .sphere(Point3::new(0.0, -1000.0, 0.0), 1000.0)
-//             .build());
-        
-//         Self { world }
-//     }
-    
-//     fn background(&self, _:Vec3) -> Color {
-//         Color::full(0.1)
-//     }
-// }
+        let nearest = ImageTexture::from_rgba(img.clone(), ColorSpace::Srgb);
+        assert_eq!(nearest.value(0.3, 0.5, p), Color::zero());
+        assert_eq!(nearest.value(1.3, 0.5, p), Color::one());
 
-// impl SceneWithDepth for SimpleScene {
-//     fn camera(&self) -> Camera {
-//         Camera::from_lookat(
-//             Vec3::new(13.0, 2.0, 3.0),
-//             Vec3::yaxis(),
-//             Vec3::yaxis(),
-//             30.0,
-//             self.aspect(),
-//         )
-//     }
+        let bilinear = ImageTexture::from_rgba(img.clone(), ColorSpace::Srgb).filter(TextureFilter::Bilinear);
+        assert!((bilinear.value(0.5, 0.5, p) - Color::full(0.5)).near_zero());
 
-//     fn trace(&self, ray: Ray, depth: usize) -> Color {
-//         let hit_info = self.world.hit(&ray, 0.001, f64::MAX);
-//         if let Some(hit) = hit_info {
-//             let emitted = hit.m.emitted(&ray, &hit);
-//             let scatter_info = if depth > 0 { hit.m.scatter(&ray, &hit) } else { None };
-//             if let Some(scatter) = scatter_info {
-//                 emitted + scatter.albedo * self.trace(scatter.ray, depth - 1)
-//             } else {
-//                 emitted
-//             }
-//         } else {
-//             self.background(ray.direction)
-//         }
-//     }
-// }
+        let bicubic = ImageTexture::from_rgba(img.clone(), ColorSpace::Srgb).filter(TextureFilter::Bicubic);
+        assert!((bicubic.value(0.5, 0.5, p) - Color::full(0.5)).near_zero());
 
-struct CornelBoxScene {
-    world: ShapeList,
-    light: Arc<dyn Shape>,
-}
+        let repeat = ImageTexture::from_rgba(img.clone(), ColorSpace::Srgb).wrap(WrapMode::Repeat, WrapMode::Repeat);
+        assert_eq!(repeat.value(1.3, 0.5, p), Color::zero());
+        let mirror = ImageTexture::from_rgba(img.clone(), ColorSpace::Srgb).wrap(WrapMode::Mirror, WrapMode::Mirror);
+        assert_eq!(mirror.value(1.3, 0.5, p), Color::one());
+        let border = ImageTexture::from_rgba(img, ColorSpace::Srgb).wrap(WrapMode::Border(Color::full(0.25)), WrapMode::Clamp);
+        assert_eq!(border.value(-0.3, 0.5, p), Color::full(0.25));
+    }
 
-impl CornelBoxScene {
-    fn new() -> Self {
+    #[test]
+    fn test_image_texture_color_space() {
+        let img = image::RgbaImage::from_pixel(1, 1, image::Rgba([128, 128, 128, 128]));
+        let p = Point3::zero();
+        let srgb = ImageTexture::from_rgba(img.clone(), ColorSpace::Srgb);
+        assert_eq!(srgb.value(0.5, 0.5, p), Color::from_rgb(128, 128, 128).srgb_to_linear());
+        assert!(srgb.value(0.5, 0.5, p).x() < 0.25);
+        let data = ImageTexture::from_rgba(img, ColorSpace::Data);
+        assert_eq!(data.value(0.5, 0.5, p), Color::from_rgb(128, 128, 128));
+        assert_eq!(srgb.alpha(0.5, 0.5, p), data.alpha(0.5, 0.5, p));
+    }
+
+    #[test]
+    fn test_mipmap() {
+        // 1画素ごとの白黒の縞は，遠くから見ると灰色になる
+        let img = image::RgbaImage::from_fn(64, 64, |x, _| {
+            if x % 2 == 0 { image::Rgba([0, 0, 0, 255]) } else { image::Rgba([255, 255, 255, 255]) }
+        });
+        let tex = ImageTexture::from_rgba(img, ColorSpace::Linear);
+        assert_eq!(tex.levels.len(), 7);
+        assert_eq!(tex.levels[6].width, 1);
+        assert!((tex.levels[1].pixels[0] - Color::full(0.5)).near_zero());
+
+        let rect = ShapeBuilder::new()
+            .color_texture(Color::one())
+            .lambertian()
+            .rect_xy(0.0, 1.0, 0.0, 1.0, 0.0)
+            .build();
+        let camera = Camera::from_lookat(Point3::new(0.5, 0.5, 100.0), Point3::new(0.5, 0.5, 0.0), Vec3::yaxis(), 1.0, 1.0);
+        let ray = camera.ray_differential(0.5, 0.5, 0.1, 0.1);
+        let hit = rect.hit(&ray, 0.001, f64::MAX).unwrap().with_ray_differential(&ray);
+        assert!(tex.lod(&hit.duv) > 1.0);
+        assert!((tex.evaluate(&hit) - Color::full(0.5)).near_zero());
+    }
+
+    #[test]
+    fn test_hdr_texture() {
+        let pixels = vec![Color::full(50.0), Color::full(0.25)];
+        let tex = ImageTexture::from_float(FloatImage::new(2, 1, pixels));
+        assert_eq!(tex.value(0.25, 0.5, Point3::zero()), Color::full(50.0));
+        assert_eq!(tex.value(0.75, 0.5, Point3::zero()), Color::full(0.25));
+    }
+
+    #[test]
+    fn test_environment_light() {
+        // 1画素だけ明るい環境
+        let mut pixels = vec![Color::full(0.01); 32 * 16];
+        pixels[5 + 32 * 4] = Color::full(1000.0);
+        let env = EnvironmentLight::from_image(FloatImage::new(32, 16, pixels), 30.0, 2.0);
+        let o = Point3::zero();
+        let bright = env.direction(5.5 / 32.0, 4.5 / 16.0);
+        assert_eq!(env.value(bright), Color::full(2000.0));
+        let mut hits = 0;
+        for _ in 0..1000 {
+            let d = env.random(o);
+            assert!(env.pdf_value(o, d) > 0.0);
+            if env.value(d).x() > 1.0 { hits += 1; }
+        }
+        assert!(hits > 900);
+        // 確率密度を球面上で積分すると1
+        let pixels = (0..32 * 16).map(|i| Color::full(1.0 + (i % 32) as f64)).collect();
+        let env = EnvironmentLight::from_image(FloatImage::new(32, 16, pixels), 0.0, 1.0);
+        let n = 100000;
+        let sum = (0..n).fold(0.0, |acc, _| acc + env.pdf_value(o, Vec3::random_unit_vector()));
+        assert!((sum / n as f64 * 4.0 * PI - 1.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_environment_furnace() {
+        // 一様な環境光の中の拡散反射の球．凸なので球の輝度は反射率と環境光の積．
         let mut world = ShapeList::new();
+        world.push(ShapeBuilder::new()
+            .color_texture(Color::full(0.5))
+            .lambertian()
+            .sphere(Point3::zero(), 1.0)
+            .build());
+        let env = EnvironmentLight::from_image(FloatImage::new(8, 4, vec![Color::one(); 32]), 0.0, 2.0);
+        let scene = CornelBoxScene::from_world(world).with_environment(Arc::new(env), 1.0);
+        // 外れた光線は環境光を見る
+        assert_eq!(scene.trace(Ray::new(Point3::new(0.0, 0.0, -5.0), Vec3::yaxis()), 10), Color::full(2.0));
+        let n = 2000;
+        let c = (0..n).fold(Color::zero(), |acc, _| {
+            acc + scene.trace(Ray::new(Point3::new(0.0, 0.0, -5.0), Vec3::zaxis()), 10)
+        }) / n as f64;
+        assert!((c.x() - 1.0).abs() < 0.03);
+    }
 
-        let red = Color::new(0.64, 0.05, 0.05);
-        let white = Color::full(0.73);
-        let green = Color::new(0.12, 0.45, 0.15);
-    
+    #[test]
+    fn test_mis() {
+        // 白い床を覆う天井全面の光源．床の輝度は反射率・光源の輝度に一致する．
+        let scene = |light_size: f64| {
+            let mut world = ShapeList::new();
+            world.push(ShapeBuilder::new()
+                .color_texture(Color::full(0.5))
+                .lambertian()
+                .rect_xz(-1000.0, 1000.0, -1000.0, 1000.0, 0.0)
+                .build());
+            world.push(ShapeBuilder::new()
+                .color_texture(Color::full(2.0 / (light_size * light_size)))
+                .diffuse_light()
+                .rect_xz(-light_size, light_size, -light_size, light_size, 1.0)
+                .flip_face()
+                .build());
+            CornelBoxScene::from_world(world)
+        };
+        let ray = || Ray::new(Point3::new(0.0, 0.5, -0.5), Vec3::new(0.0, -0.5, 0.5));
+        let n = 2000;
+        let large = scene(1000.0);
+        let c = (0..n).fold(Color::zero(), |acc, _| acc + large.trace(ray(), 2)) / n as f64;
+        assert!((c.x() - 0.5 * 2.0e-6).abs() < 0.02 * 1.0e-6);
+        // 小さく明るい光源でも放射を二重に数えない．
+        // 一辺2aの正方形から真下の距離1の点への形態係数
+        let a = 0.1_f64;
+        let x = a / (1.0 + a * a).sqrt();
+        let form_factor = 2.0 / PI * 2.0 * x * x.atan();
+        let small = scene(a);
+        let c = (0..n).fold(Color::zero(), |acc, _| acc + small.trace(ray(), 2)) / n as f64;
+        let expected = 0.5 * 2.0 / (a * a) * form_factor;
+        assert!((c.x() - expected).abs() < 0.02 * expected);
+    }
+
+    #[test]
+    fn test_lobe() {
+        let hit = |m: Arc<dyn Material>| HitInfo::new(1.0, Point3::zero(), Vec3::yaxis(), m, 0.0, 0.0);
+        let ray = Ray::new(Point3::new(0.0, 1.0, -1.0), Vec3::new(0.0, -1.0, 1.0));
+        let lobe = |m: Arc<dyn Material>| m.scatter(&ray, &hit(m.clone())).unwrap().lobe;
+        let white = || Box::new(ColorTexture::new(Color::one()));
+        assert_eq!(lobe(Arc::new(Lambertian::new(white()))), Lobe::Diffuse);
+        assert_eq!(lobe(Arc::new(Metal::new(white(), 0.0))), Lobe::Specular);
+        assert_eq!(lobe(Arc::new(AnisotropicMetal::new(white(), 0.2, 0.2))), Lobe::Glossy);
+        // デルタ分布でない散乱は光源サンプリングに使えるpdfを持つ
+        for m in [Arc::new(Metal::new(white(), 0.3)) as Arc<dyn Material>, Arc::new(Isotropic::new(white()))] {
+            let scatter = m.scatter(&ray, &hit(m.clone())).unwrap();
+            assert!(!scatter.lobe.is_delta() && scatter.pdf.is_some());
+        }
+        // ぼやけた金属の分布を球面上で積分すると1．分布は反射方向の周りで軸対称なので
+        // 反射方向とのなす角の余弦について中点則で積分する．
+        for fuzz in [0.3, 1.5] {
+            let reflected = Vec3::new(0.0, 1.0, 1.0).normalize();
+            let pdf = FuzzPdf::new(reflected, fuzz);
+            let h = hit(Arc::new(Metal::new(white(), fuzz)));
+            let n = 100000;
+            let sum = (0..n).fold(0.0, |acc, i| {
+                let cos = -1.0 + (i as f64 + 0.5) * 2.0 / n as f64;
+                let d = reflected * cos + Vec3::xaxis() * (1.0 - cos * cos).sqrt();
+                acc + pdf.value(&h, d)
+            });
+            assert!((sum * 2.0 / n as f64 * 2.0 * PI - 1.0).abs() < 0.01);
+        }
+        let glass: Arc<dyn Material> = Arc::new(Dielectric::new(1.5));
+        let lobes: Vec<Lobe> = (0..100).map(|_| lobe(Arc::clone(&glass))).collect();
+        assert!(lobes.contains(&Lobe::Specular) && lobes.contains(&Lobe::Transmission));
+        assert!(lobes.iter().all(|l| l.is_delta()));
+
+        // 拡散反射を1回に制限すると，光源からの1回反射だけが残る
+        let mut world = ShapeList::new();
         world.push(ShapeBuilder::new()
-            .color_texture(green)
+            .color_texture(Color::full(0.5))
             .lambertian()
-            .rect_yz(0.0, 555.0, 0.0, 555.0, 555.0)
-            .flip_face()
+            .rect_xz(-1000.0, 1000.0, -1000.0, 1000.0, 0.0)
             .build());
         world.push(ShapeBuilder::new()
-            .color_texture(red)
+            .color_texture(Color::full(0.5))
             .lambertian()
-            .rect_yz(0.0, 555.0, 0.0, 555.0, 0.0)
+            .rect_xz(-1000.0, 1000.0, -1000.0, 1000.0, 2.0)
+            .flip_face()
             .build());
         world.push(ShapeBuilder::new()
-            .color_texture(Color::full(15.0))
+            .color_texture(Color::one())
             .diffuse_light()
-            .rect_xz(213.0, 343.0, 227.0, 332.0, 554.0)
+            .rect_xz(-1.0, 1.0, -1.0, 1.0, 1.0)
             .flip_face()
             .build());
+        let limits = |diffuse| DepthLimits { diffuse, ..DepthLimits::new() };
+        let scene = CornelBoxScene::from_world(world).with_depth_limits(limits(1));
+        let ray = || Ray::new(Point3::new(0.0, 0.5, -0.5), Vec3::new(0.0, -0.5, 0.5));
+        let n = 2000;
+        let one = (0..n).fold(Color::zero(), |acc, _| acc + scene.trace(ray(), scene.max_depth())) / n as f64;
+        let a = 1.0 / 2.0_f64.sqrt();
+        let expected = 0.5 * 4.0 / PI * a * a.atan();
+        assert!((one.x() - expected).abs() < 0.02 * expected);
+        let scene = scene.with_depth_limits(limits(0));
+        assert_eq!(scene.trace(ray(), scene.max_depth()), Color::zero());
+    }
+
+    #[test]
+    fn test_russian_roulette() {
+        // 半分の確率で光り，残りは反射率0.8で拡散反射する球の内側．
+        // 輝度Lは L = 0.5 + 0.5 * 0.8 * L を満たす．
+        let light: Arc<dyn Material> = Arc::new(DiffuseLight::new(Box::new(ColorTexture::new(Color::one()))));
+        let mut world = ShapeList::new();
         world.push(ShapeBuilder::new()
-            .color_texture(white)
+            .color_texture(Color::full(0.8))
             .lambertian()
-            .rect_xz(0.0, 555.0, 0.0, 555.0, 555.0)
+            .mix(light, 0.5)
+            .sphere(Point3::zero(), 1.0)
             .flip_face()
             .build());
+        let scene = CornelBoxScene { lights: Arc::new(LightList::new()), ..CornelBoxScene::from_world(world) };
+        let n = 20000;
+        let c = (0..n).fold(Color::zero(), |acc, _| {
+            acc + scene.trace(Ray::new(Point3::zero(), Vec3::random_unit_vector()), scene.max_depth())
+        }) / n as f64;
+        assert!((c.x() - 0.5 / 0.6).abs() < 0.03);
+        // ルーレットを始める前は必ず続け，暗い経路ほど打ち切られやすい
+        let path = PathState::new();
+        assert_eq!(scene.continue_path(&path, Color::full(0.01), Lobe::Diffuse).unwrap().survival, 1.0);
+        let deep = PathState { bounce: scene.roulette_depth(), ..path };
+        let next = (0..100).find_map(|_| scene.continue_path(&deep, Color::full(0.5), Lobe::Diffuse)).unwrap();
+        assert_eq!(next.survival, 0.5);
+        assert_eq!(next.throughput, Color::one());
+    }
+
+    #[test]
+    fn test_light_list() {
+        let mut world = ShapeList::new();
         world.push(ShapeBuilder::new()
-            .color_texture(white)
+            .color_texture(Color::full(9.0))
+            .diffuse_light()
+            .rect_xz(-1.0, 0.0, -1.0, 0.0, 2.0)
+            .flip_face()
+            .build());
+        world.push(ShapeBuilder::new()
+            .color_texture(Color::one())
+            .diffuse_light()
+            .sphere(Point3::new(3.0, 2.0, 0.0), 0.5)
+            .translate(Point3::new(0.0, 1.0, 0.0))
+            .build());
+        world.push(ShapeBuilder::new()
+            .color_texture(Color::one())
             .lambertian()
-            .rect_xz(0.0, 555.0, 0.0, 555.0, 0.0)
+            .rect_xz(-5.0, 5.0, -5.0, 5.0, 0.0)
             .build());
+        // 光る物体だけが集まり，放射束は輝度・面積・π
+        let lights = world.emitters();
+        assert_eq!(lights.lights.len(), 2);
+        assert!((lights.powers[0] - 9.0 * PI).abs() < 1e-9);
+        assert!((lights.powers[1] - PI * PI * 4.0 * 0.25).abs() < 1e-9);
+        // 明るい光源ほどよく選ばれ，pdfは選ぶ確率で重み付けされる
+        let o = Point3::new(0.0, 0.5, 0.0);
+        let n = 10000;
+        let mut bright = 0;
+        for _ in 0..n {
+            let d = lights.random(o);
+            assert!(lights.pdf_value(o, d) > 0.0);
+            if d.x() < 0.0 { bright += 1; }
+        }
+        let p = lights.powers[0] / (lights.powers[0] + lights.powers[1]);
+        assert!((bright as f64 / n as f64 - p).abs() < 0.03);
+        // 球の確率密度を立体角で積分すると1
+        let sphere = &lights.lights[1];
+        let (o, n) = (Point3::new(3.0, 1.5, 0.0), 100000);
+        let sum = (0..n).fold(0.0, |acc, _| acc + sphere.pdf_value(o, Vec3::random_unit_vector()));
+        assert!((sum / n as f64 * 4.0 * PI - 1.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_light_bvh() {
+        // 下向きに光る小さな面光源を20x20個並べる
+        let mut world = ShapeList::new();
+        for i in 0..20 {
+            for j in 0..20 {
+                let (x, z) = (i as f64 * 5.0, j as f64 * 5.0);
+                world.push(ShapeBuilder::new()
+                    .color_texture(Color::one())
+                    .diffuse_light()
+                    .rect_xz(x, x + 1.0, z, z + 1.0, 10.0)
+                    .flip_face()
+                    .build());
+            }
+        }
+        let bvh = world.light_bvh();
+        assert_eq!(bvh.lights.len(), 400);
+        let root = bvh.nodes[0].bounds;
+        assert!((root.axis + Vec3::yaxis()).length() < 1e-9);
+        assert!((root.power - 400.0 * PI).abs() < 1e-6);
+
+        // 真上の光源が一様に選ぶより遥かに選ばれやすく，頻度が確率と一致する
+        let o = Point3::new(50.5, 9.0, 50.5);
+        let n = 10000;
+        let mut counts = vec![0; 400];
+        for k in 0..n {
+            let (i, _) = bvh.sample(o, (k as f64 + 0.5) / n as f64);
+            counts[i] += 1;
+        }
+        let top = (0..400).max_by_key(|&i| counts[i]).unwrap();
+        let d = Vec3::yaxis();
+        assert!(bvh.lights[top].pdf_value(o, d) > 0.0);
+        let pmf = (0..n).map(|k| bvh.sample(o, (k as f64 + 0.5) / n as f64)).find(|&(i, _)| i == top).unwrap().1;
+        assert!(pmf > 40.0 / 400.0);
+        assert!((counts[top] as f64 / n as f64 - pmf).abs() < 0.01);
+        // pdfは選ぶ確率と光源のpdfの積
+        let expected = pmf * bvh.lights[top].pdf_value(o, d);
+        assert!((bvh.pdf_value(o, d) - expected).abs() < 1e-9 * expected);
+        // 光源の裏側には光が届かない
+        assert_eq!(bvh.pdf_value(Point3::new(50.5, 11.0, 50.5), Vec3::yaxis()), 0.0);
+
+        // 範囲を持たない環境光は木とは別に選ぶ
+        let env: Arc<dyn Shape> = Arc::new(EnvironmentLight::from_image(FloatImage::new(8, 4, vec![Color::one(); 32]), 0.0, 1.0));
+        let mut lights: Vec<Arc<dyn Shape>> = world.objects.iter().map(Arc::clone).collect();
+        lights.push(Arc::clone(&env));
+        let bvh = LightBvh::new(lights);
+        assert_eq!((bvh.lights.len(), bvh.infinite.len()), (400, 1));
+        let up = Point3::new(50.5, 11.0, 50.5);
+        assert!((bvh.pdf_value(up, Vec3::yaxis()) - 0.5 * env.pdf_value(up, Vec3::yaxis())).abs() < 1e-12);
+        let hits = (0..1000).filter(|_| bvh.random(up).y() < 0.0).count();
+        assert!((hits as f64 / 1000.0 - 0.75).abs() < 0.06);
+
+        // シーンの光源にしても，光源リストと同じ明るさになる
         world.push(ShapeBuilder::new()
-            .color_texture(white)
+            .color_texture(Color::full(0.5))
             .lambertian()
-            .rect_xy(0.0, 555.0, 0.0, 555.0, 555.0)
-            .flip_face()
+            .rect_xz(-1000.0, 1000.0, -1000.0, 1000.0, 0.0)
             .build());
-        
+        let list = CornelBoxScene::from_world(world);
+        let ray = Ray::new(Point3::new(30.5, 5.0, 60.5), -Vec3::yaxis());
+        let n = 40000;
+        let render = |scene: &CornelBoxScene| (0..n).fold(Color::zero(), |acc, _| acc + scene.trace(ray, 1)) / n as f64;
+        let expected = render(&list);
+        let c = render(&list.with_light_bvh());
+        assert!(expected.x() > 0.0);
+        assert!((c.x() - expected.x()).abs() < 0.05 * expected.x());
+    }
+
+    #[test]
+    fn test_punctual_light() {
+        let mut world = ShapeList::new();
         world.push(ShapeBuilder::new()
-            .dielectric(1.5)
-            .sphere(Point3::new(190.0, 90.0, 190.0), 90.0)
+            .color_texture(Color::full(0.5))
+            .lambertian()
+            .rect_xz(-10.0, 10.0, -10.0, 10.0, 0.0)
             .build());
+        let shade = |world: &ShapeList, light: Box<dyn PunctualLight>, x: f64| {
+            let ray = Ray::new(Point3::new(x + 5.0, 5.0, 0.0), Vec3::new(-1.0, -1.0, 0.0));
+            let hit = world.hit(&ray, 0.001, f64::MAX).unwrap();
+            punctual_lighting(world, &[light], &ray, &hit, Color::full(0.5))
+        };
+        // ノイズのない厳密な値になる
+        let expected = 0.5 * FRAC_1_PI;
+        let c = shade(&world, Box::new(PointLight::new(Point3::new(0.0, 2.0, 0.0), Color::full(4.0))), 0.0);
+        assert!((c.x() - expected).abs() < 1e-9);
+        let c = shade(&world, Box::new(DirectionalLight::new(Vec3::new(1.0, -1.0, 0.0), Color::one())), 0.0);
+        assert!((c.x() - expected * 0.5_f64.sqrt()).abs() < 1e-9);
+        // スポットライトは円錐の外を照らさない
+        let spot = || Box::new(SpotLight::new(Point3::new(0.0, 2.0, 0.0), Point3::zero(), Color::full(4.0), 30.0, 20.0));
+        assert!((shade(&world, spot(), 0.0).x() - expected).abs() < 1e-9);
+        assert_eq!(shade(&world, spot(), 3.0), Color::zero());
+        // 遮蔽物があると影になる
         world.push(ShapeBuilder::new()
-            .color_texture(white)
+            .color_texture(Color::one())
             .lambertian()
-            .box3d(Point3::zero(), Point3::new(165.0, 330.0, 165.0))
-            .rotate(Vec3::yaxis(), 15.0)
-            .translate(Point3::new(265.0, 0.0, 295.0))
+            .sphere(Point3::new(0.0, 1.0, 0.0), 0.2)
             .build());
-        
-        let mut light = ShapeList::new();
-        light.push(ShapeBuilder::new()
-            .color_texture(Color::zero())
+        let c = shade(&world, Box::new(PointLight::new(Point3::new(0.0, 2.0, 0.0), Color::full(4.0))), 0.05);
+        assert_eq!(c, Color::zero());
+        // 双方向パストレーシングでもカメラの経路から点光源につなぐ．床だけなので直接光で一致する．
+        let mut floor = ShapeList::new();
+        floor.push(ShapeBuilder::new()
+            .color_texture(Color::full(0.5))
             .lambertian()
-            .rect_xz(213.0, 343.0, 227.0, 332.0, 554.0)
+            .rect_xz(-1000.0, 1000.0, -1000.0, 1000.0, 0.0)
             .build());
-        light.push(ShapeBuilder::new()
-            .color_texture(Color::zero())
+        let mut scene = CornelBoxScene::from_world(floor);
+        scene.push_light(Box::new(PointLight::new(Point3::new(278.0, 300.0, 0.0), Color::full(4.0e4))));
+        let ray = scene.camera().ray(0.5, 0.2);
+        let c = scene.trace(ray, scene.max_depth());
+        assert!(c.x() > 0.0);
+        let mut splats = Vec::new();
+        assert!((scene.trace_splat(ray, scene.max_depth(), &mut splats) - c).length() < 1e-9);
+        assert!(splats.is_empty());
+    }
+
+    #[test]
+    fn test_sky_light() {
+        let sky = Sky::new(30.0, 60.0, 2.5, Color::full(0.2));
+        let o = Point3::zero();
+        // 選んだ方向は必ず太陽の円盤に入る
+        for _ in 0..100 {
+            let d = sky.random(o);
+            assert!(sky.pdf_value(o, d) > 0.0);
+            assert!(sky.value(d).luminance() > sky.sun_radiance().luminance());
+        }
+        assert_eq!(sky.pdf_value(o, Vec3::yaxis()), 0.0);
+        // 地平線下の太陽はサンプリングしない
+        let night = Sky::new(-10.0, 0.0, 2.5, Color::zero());
+        assert_eq!(night.pdf_value(o, night.sun_direction()), 0.0);
+    }
+
+    #[test]
+    fn test_sky_background() {
+        // 空の下の拡散反射の床．輝度は反射率 / π・放射照度．
+        let sky = Sky::new(40.0, 30.0, 3.0, Color::zero());
+        let irradiance = sky.irradiance();
+        let mut world = ShapeList::new();
+        world.push(ShapeBuilder::new()
+            .color_texture(Color::full(0.5))
             .lambertian()
-            .sphere(Point3::new(190.0, 90.0, 190.0), 90.0)
+            .rect_xz(-1.0e6, 1.0e6, -1.0e6, 1.0e6, 0.0)
             .build());
-        
-        Self { world, light: Arc::new(light) }
+        let scene = CornelBoxScene::from_world(world).with_environment(Arc::new(sky), 1.0);
+        let ray = Ray::new(Point3::new(0.0, 1.0, 0.0), -Vec3::yaxis());
+        let n = 4000;
+        let c = (0..n).fold(Color::zero(), |acc, _| acc + scene.trace(ray, 10)) / n as f64;
+        let expected = irradiance * (0.5 * FRAC_1_PI);
+        assert!((c.luminance() / expected.luminance() - 1.0).abs() < 0.05);
     }
 
-    fn background(&self, _: Vec3) -> Color {
-        Color::full(0.0)
+    #[test]
+    fn test_mix_material() {
+        let m0: Arc<dyn Material> = Arc::new(Dielectric::new(1.5));
+        let m1: Arc<dyn Material> = Arc::new(DiffuseLight::new(Box::new(ColorTexture::new(Color::one()))));
+        for (ratio, expected) in [(0.0, &m0), (1.0, &m1)] {
+            let mix: Arc<dyn Material> = Arc::new(MixMaterial::with_ratio(Arc::clone(&m0), Arc::clone(&m1), ratio));
+            let hit = HitInfo::new(1.0, Point3::zero(), Vec3::yaxis(), mix, 0.0, 0.0).resolve();
+            assert!(Arc::ptr_eq(&hit.m, expected));
+        }
+        // 割合で混ぜる場合とテクスチャで混ぜる場合
+        let by_ratio = ShapeBuilder::new().material(Arc::clone(&m0)).mix(Arc::clone(&m1), 0.25).material.unwrap();
+        assert_eq!(by_ratio.emittance(), Color::full(0.25));
+        let by_texture = ShapeBuilder::new()
+            .material(Arc::clone(&m0))
+            .color_texture(Color::full(0.75))
+            .mix_texture(Arc::clone(&m1))
+            .material
+            .unwrap();
+        assert_eq!(by_texture.emittance(), Color::full(0.75));
     }
-}
 
-impl SceneWithDepth for CornelBoxScene {
-    fn camera(&self) -> Camera {
-        Camera::from_lookat(
-            Vec3::new(278.0, 278.0, -800.0),
-            Vec3::new(278.0, 278.0, 0.0),
-            Vec3::yaxis(),
-            40.0,
-            self.aspect(),
-        )
+    #[test]
+    fn test_bdpt() {
+        // 床を照らす下向きの光源．画面全体の平均をパストレーシングと比べる．
+        let mut world = ShapeList::new();
+        world.push(ShapeBuilder::new()
+            .color_texture(Color::full(0.5))
+            .lambertian()
+            .rect_xz(-1000.0, 1000.0, -1000.0, 1000.0, 0.0)
+            .build());
+        world.push(ShapeBuilder::new()
+            .color_texture(Color::full(4.0))
+            .diffuse_light()
+            .rect_xz(178.0, 378.0, 300.0, 500.0, 400.0)
+            .flip_face()
+            .build());
+        let scene = CornelBoxScene::from_world(world);
+        let camera = scene.camera();
+        // 右端の列に入る光線は少ないので多めに選ぶ
+        let n = 200000;
+        let mut splats = Vec::new();
+        // 直接見える光源のばらつきをそろえるため，同じ光線で比べる．光線は画素と同じ範囲から選ぶ．
+        let (film_u, film_v) = camera.film;
+        let rays: Vec<(f64, Ray)> = (0..n).map(|_| {
+            let [u, v, _] = Vec3::random().to_array();
+            (u * film_u, camera.ray(u * film_u, v * film_v))
+        }).collect();
+        let traced: Vec<Color> = rays.iter().map(|&(_, ray)| scene.trace_splat(ray, scene.max_depth(), &mut splats)).collect();
+        // 光源からカメラへ直接つないだ光も届いている
+        assert!(!splats.is_empty());
+        assert!(splats.iter().all(|&(u, v, _)| (0.0..=film_u).contains(&u) && (0.0..=film_v).contains(&v)));
+        let sum = |colors: &mut dyn Iterator<Item = Color>| colors.fold(Color::zero(), |acc, c| acc + c).x() / n as f64;
+        let c = sum(&mut traced.iter().copied()) + sum(&mut splats.iter().map(|&(_, _, s)| s));
+        let expected = rays.iter().map(|&(_, ray)| scene.trace(ray, scene.max_depth())).collect::<Vec<Color>>();
+        assert!((c - sum(&mut expected.iter().copied())).abs() < 0.03 * sum(&mut expected.iter().copied()));
+        // [0, 1]の外まで受け持つ右端の列の画素にも，光源からの光が正しい割合で届く
+        let edge = |u: f64| u > 1.0;
+        let c = sum(&mut rays.iter().zip(&traced).filter(|((u, _), _)| edge(*u)).map(|(_, &c)| c))
+            + sum(&mut splats.iter().filter(|&&(u, _, _)| edge(u)).map(|&(_, _, s)| s));
+        let expected = sum(&mut rays.iter().zip(&expected).filter(|((u, _), _)| edge(*u)).map(|(_, &c)| c));
+        assert!((c - expected).abs() < 0.15 * expected);
     }
 
-    fn trace(&self, ray: Ray, depth: usize) -> Color {
-        let hit_info = self.world.hit(&ray, 0.001, f64::MAX);
-        if let Some(hit) = hit_info {
-            let emitted = hit.m.emitted(&ray, &hit);
-            let scatter_info = if depth > 0 { hit.m.scatter(&ray, &hit) } else { None };
-            if let Some(scatter) = scatter_info {
-                if let Some(pdf) = scatter.pdf {
-                    let shape_pdf = Arc::new(ShapePdf::new(Arc::clone(&self.light), hit.p));
-                    let pdf = MixturePdf::new(shape_pdf, Arc::clone(&pdf));
-                    let new_ray = Ray::new(hit.p, pdf.generate(&hit));
-                    let spdf_value = pdf.value(&hit, new_ray.direction);
-                    if spdf_value > 0.0 {
-                        let pdf_value = hit.m.scattering_pdf(&new_ray, &hit);
-                        let albedo = scatter.albedo * pdf_value;
-                        emitted + albedo * self.trace(new_ray, depth - 1) / spdf_value
-                    } else {
-                        emitted
-                        
-                    }
+    #[test]
+    fn test_sppm() {
+        // 天井を照らす上向きの光源．床は天井からの間接光だけで照らされるので，
+        // 床の明るさはフォトンだけで決まる．パストレーシングと比べる．
+        let mut world = ShapeList::new();
+        world.push(ShapeBuilder::new()
+            .color_texture(Color::full(0.5))
+            .lambertian()
+            .rect_xz(-2000.0, 2000.0, -2000.0, 2000.0, 0.0)
+            .build());
+        world.push(ShapeBuilder::new()
+            .color_texture(Color::full(0.5))
+            .lambertian()
+            .rect_xz(-2000.0, 2000.0, -2000.0, 2000.0, 600.0)
+            .flip_face()
+            .build());
+        world.push(ShapeBuilder::new()
+            .color_texture(Color::full(4.0))
+            .diffuse_light()
+            .rect_xz(178.0, 378.0, 300.0, 500.0, 500.0)
+            .build());
+        let sppm = Sppm::new(CornelBoxScene::from_world(world), 20, 20000, 30.0);
+        let camera = sppm.camera();
+        // 床の見える画面の下半分
+        let rays: Vec<Ray> = (0..1000).map(|_| {
+            let [u, v, _] = Vec3::random().to_array();
+            camera.ray(u, v * 0.45)
+        }).collect();
+        let mut pixels: Vec<PhotonPixel> = rays.iter().map(|_| sppm.new_pixel()).collect();
+        for _ in 0..sppm.spp() {
+            sppm.pass(&rays, &mut pixels);
+        }
+        // 探索半径は縮んでいく
+        assert!(pixels.iter().any(|p| p.radius < 30.0));
+        assert!(pixels.iter().all(|p| p.radius <= 30.0));
+        let c = pixels.iter().fold(Color::zero(), |acc, p| acc + sppm.pixel_color(p)) / rays.len() as f64;
+        let n = 100;
+        let expected = rays.iter().fold(Color::zero(), |acc, &ray| {
+            (0..n).fold(acc, |acc, _| acc + sppm.trace(ray, sppm.max_depth()))
+        }) / (rays.len() * n) as f64;
+        assert!((c.x() - expected.x()).abs() < 0.08 * expected.x());
+    }
+
+    #[test]
+    fn test_metropolis() {
+        // 床と天井全面の光源の間にカメラを置く．画面の上半分の光源は下半分の床の2倍明るい．
+        let mut world = ShapeList::new();
+        world.push(ShapeBuilder::new()
+            .color_texture(Color::full(0.5))
+            .lambertian()
+            .rect_xz(-1.0e6, 1.0e6, -1.0e6, 1.0e6, 0.0)
+            .build());
+        world.push(ShapeBuilder::new()
+            .color_texture(Color::one())
+            .diffuse_light()
+            .rect_xz(-1.0e6, 1.0e6, -1.0e6, 1.0e6, 1000.0)
+            .flip_face()
+            .build());
+        let scene = CornelBoxScene::from_world(world);
+        let colors = metropolis(&scene, 2);
+        let (width, height) = (scene.width() as usize, scene.height() as usize);
+        let half = |rows: std::ops::Range<usize>| {
+            let c = rows.flat_map(|y| &colors[y * width..(y + 1) * width]).fold(Color::zero(), |acc, &c| acc + c);
+            c.x() / (height / 2 * width) as f64
+        };
+        let (top, bottom) = (half(0..height / 2), half(height / 2..height));
+        assert!((top - 1.0).abs() < 0.02);
+        assert!((bottom - 0.5).abs() < 0.02);
+        // 一番上の行と一番右の列にも光が届く．1行だけでは揺らぎが大きいので緩く比べる．
+        let top_row = colors[..width].iter().fold(0.0, |acc, c| acc + c.x()) / width as f64;
+        let last_column = (0..height / 2).fold(0.0, |acc, y| acc + colors[y * width + width - 1].x()) / (height / 2) as f64;
+        assert!((top_row - 1.0).abs() < 0.2);
+        assert!((last_column - 1.0).abs() < 0.2);
+    }
+
+    // 小さな画像で描画するコーネルボックス
+    struct SmallScene(CornelBoxScene);
+
+    impl SceneWithDepth for SmallScene {
+        fn camera(&self) -> Camera { self.0.camera() }
+        fn trace(&self, ray: Ray, depth: usize) -> Color { self.0.trace(ray, depth) }
+        fn width(&self) -> u32 { 24 }
+        fn height(&self) -> u32 { 24 }
+        fn spp(&self) -> usize { 4 }
+        fn sampler(&self) -> SamplerKind { self.0.sampler() }
+        fn seed(&self) -> u64 { self.0.seed() }
+    }
+
+    #[test]
+    fn test_deterministic_render() {
+        // 同じseedならスレッド数によらずビット単位で同じ画像になる
+        let render = |scene: &SmallScene, threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            pool.install(|| pixel_colors(scene))
+        };
+        for &kind in &[SamplerKind::Independent, SamplerKind::Sobol] {
+            let scene = SmallScene(CornelBoxScene::new().with_sampler(kind).with_seed(1));
+            let colors = render(&scene, 1);
+            assert_eq!(colors, render(&scene, 1));
+            assert_eq!(colors, render(&scene, 4));
+            let other = SmallScene(CornelBoxScene::new().with_sampler(kind).with_seed(2));
+            assert_ne!(colors, render(&other, 4));
+        }
+        // 反復ごとに画素をまたいで計算する描画も同じ．経路はサンプラーの続きでたどる．
+        // 経路誘導は分布を学習しながら描くので毎回作り直す．
+        let progressive = |guided: bool, threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            pool.install(|| {
+                let scene = CornelBoxScene::new().with_sampler(SamplerKind::Sobol).with_seed(1);
+                if guided {
+                    progressive_colors(&Guided::new(scene, Point3::zero(), Point3::full(555.0), 3))
                 } else {
-                    emitted + scatter.albedo * self.trace(scatter.ray, depth - 1)
+                    progressive_colors(&Sppm::new(scene, 2, 1000, 4.0))
                 }
-            } else {
-                emitted
-            }
-        } else {
-            self.background(ray.direction)
+            })
+        };
+        for &guided in &[false, true] {
+            let colors = progressive(guided, 1);
+            assert_eq!(colors, progressive(guided, 4));
         }
     }
 
-    fn width(&self) -> u32 { 200 }
-    fn height(&self) -> u32 { 200 }
-    fn spp(&self) -> usize { 
-        if cfg!(test) {
-            32
-        } else {
-            1000
+    #[test]
+    fn test_dtree() {
+        // 上から来る光を集めると，上向きの区画が細かく分かれて選ばれやすくなる
+        let tree = AtomicDTree::new(DTree::new());
+        for _ in 0..10000 {
+            let d = (Vec3::zaxis() * 4.0 + Vec3::random_unit_vector()).normalize();
+            tree.record(d, 1.0);
         }
+        let refined = AtomicDTree::new(tree.to_tree().refined());
+        assert!(refined.tree.nodes.len() > 1);
+        for _ in 0..10000 {
+            let d = (Vec3::zaxis() * 4.0 + Vec3::random_unit_vector()).normalize();
+            refined.record(d, 1.0);
+        }
+        let refined = refined.to_tree();
+        assert!(refined.pdf(Vec3::zaxis()) > 10.0 / (2.0 * PI2));
+        // pdfは全方向で積分すると1になり，選んだ方向の割合と一致する
+        let cap = |d: Vec3| d.z() > 0.95;
+        let (nt, np) = (400, 400);
+        let (mut integral, mut in_cap) = (0.0, 0.0);
+        for i in 0..nt {
+            for j in 0..np {
+                let d = DTree::from_square((i as f64 + 0.5) / nt as f64, (j as f64 + 0.5) / np as f64);
+                let p = refined.pdf(d) * 2.0 * PI2 / (nt * np) as f64;
+                integral += p;
+                if cap(d) {
+                    in_cap += p;
+                }
+            }
+        }
+        assert!((integral - 1.0).abs() < 1e-3);
+        let n = 100000;
+        let hits = (0..n).filter(|_| cap(refined.sample())).count();
+        assert!((hits as f64 / n as f64 - in_cap).abs() < 0.01);
     }
-}
 
-pub fn run() {
-    render_aa_with_depth(CornelBoxScene::new());
+    #[test]
+    fn test_path_guiding() {
+        // test_misと同じ小さな光源で照らした床．学習した分布を使っても床の明るさは変わらない．
+        let a = 0.1_f64;
+        let floor_and_light = |up: bool| {
+            let mut world = ShapeList::new();
+            world.push(ShapeBuilder::new()
+                .color_texture(Color::full(0.5))
+                .lambertian()
+                .rect_xz(-1000.0, 1000.0, -1000.0, 1000.0, 0.0)
+                .build());
+            let light = ShapeBuilder::new()
+                .color_texture(Color::full(2.0 / (a * a)))
+                .diffuse_light()
+                .rect_xz(-a, a, -a, a, 1.0);
+            world.push(if up { light.build() } else { light.flip_face().build() });
+            world
+        };
+        let train = |scene: &CornelBoxScene, guide: &SdTree, ray: &dyn Fn() -> Ray| {
+            for _ in 0..6 {
+                for _ in 0..4000 {
+                    scene.trace(ray(), 2);
+                }
+                guide.refine(1000);
+            }
+        };
+        let guide = Arc::new(SdTree::new(Point3::full(-1.0), Point3::full(1.0)));
+        let scene = CornelBoxScene::from_world(floor_and_light(false)).with_guide(Arc::clone(&guide));
+        let ray = || Ray::new(Point3::new(0.0, 0.5, -0.5), Vec3::new(0.0, -0.5, 0.5));
+        train(&scene, &guide, &ray);
+        // 床の上の点では光源の方向が選ばれやすい
+        let tree = guide.sampling(Point3::zero()).unwrap();
+        assert!(tree.pdf(Vec3::yaxis()) > 20.0 / (2.0 * PI2));
+
+        // 平均と分散
+        let n = 20000;
+        let stats = |scene: &CornelBoxScene, ray: &dyn Fn() -> Ray| {
+            let xs = (0..n).map(|_| scene.trace(ray(), 2).x()).collect::<Vec<_>>();
+            let mean = xs.iter().sum::<f64>() / n as f64;
+            let var = xs.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1) as f64;
+            (mean, var)
+        };
+        let (mean, _) = stats(&scene, &ray);
+        let x = a / (1.0 + a * a).sqrt();
+        let expected = 0.5 * 2.0 / (a * a) * (2.0 / PI * 2.0 * x * x.atan());
+        assert!((mean - expected).abs() < 0.02 * expected);
+
+        // 光源を上に向けて天井を照らすと，床は天井の明るいところからの間接光だけで照らされる．
+        // 光源サンプリングが効かないので，学習した分布で誘導すると誘導しないときより分散が小さい．
+        let indirect = || {
+            let mut world = floor_and_light(true);
+            world.push(ShapeBuilder::new()
+                .color_texture(Color::full(0.5))
+                .lambertian()
+                .rect_xz(-1000.0, 1000.0, -1000.0, 1000.0, 1.2)
+                .flip_face()
+                .build());
+            world
+        };
+        let ray = || Ray::new(Point3::new(0.0, 0.5, -1.0), Vec3::new(0.5, -0.5, 1.0));
+        let guide = Arc::new(SdTree::new(Point3::full(-2.0), Point3::full(2.0)));
+        let scene = CornelBoxScene::from_world(indirect()).with_guide(Arc::clone(&guide));
+        train(&scene, &guide, &ray);
+        let (_, guided) = stats(&scene, &ray);
+        let (_, unguided) = stats(&CornelBoxScene::from_world(indirect()), &ray);
+        assert!(guided < 0.5 * unguided);
+    }
 }
\ No newline at end of file
@@ -5,6 +5,8 @@ mod code1;
 mod code2;
 mod code3;
 
+use rayt_mod::SamplerKind;
+
 fn main() {
     let mut no = 0;
     let args: Vec<String> = std::env::args().collect();
@@ -19,6 +21,15 @@ fn run(no: i32) {
         0 => code1::run(),
         1 => code2::run(),
         2 => code3::run(),
+        3 => code3::run_bdpt(),
+        4 => code3::run_sppm(),
+        5 => code3::run_mlt(),
+        6 => code3::run_guided(),
+        7 => code1::run_sky(),
+        8 => code3::run_sky(),
+        9 => code3::run_with_sampler(SamplerKind::Stratified),
+        10 => code3::run_with_sampler(SamplerKind::Halton),
+        11 => code3::run_with_sampler(SamplerKind::Sobol),
         _ => {}
     }
 }
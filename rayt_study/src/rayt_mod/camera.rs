@@ -7,13 +7,15 @@ pub struct Camera {
     pub u: Vec3,
     pub v: Vec3,
     pub w: Vec3,
+    // 画面の範囲．u, vはそれぞれ[0, film.0], [0, film.1]の光線を打ち出す．
+    pub film: (f64, f64),
 }
 
 impl Camera {
 
     // 初期化関数
     pub fn new(u: Vec3, v: Vec3, w: Vec3) -> Self {
-        Self { origin: Point3::zero(), u, v, w }
+        Self { origin: Point3::zero(), u, v, w, film: (1.0, 1.0) }
     }
 
     // カメラの向いている方向，視線対象の位置
@@ -30,14 +32,82 @@ impl Camera {
             u: 2.0 * uw,
             v: 2.0 * vh,
             w: origin - uw - vh - w,
+            film: (1.0, 1.0),
         }
     }
 
+    // width x height画素で描くときの画面の範囲．画素の中の位置を右上にずらす分だけ，
+    // 右端の列と上端の行は[0, 1]の外にはみ出す．
+    pub fn with_film(mut self, width: u32, height: u32) -> Self {
+        self.film = (width as f64 / (width - 1) as f64, height as f64 / (height - 1) as f64);
+        self
+    }
+
     // カメラの打ち出す光線
     pub fn ray(&self, u: f64, v: f64) -> Ray {
-        Ray {
-            origin: self.origin,
-            direction: self.w + self.u * u + self.v *v - self.origin,
+        Ray::new(self.origin, self.w + self.u * u + self.v *v - self.origin)
+    }
+
+    // 光線微分付きの光線．du, dvは1画素分のテクスチャ座標の幅．
+    pub fn ray_differential(&self, u: f64, v: f64, du: f64, dv: f64) -> Ray {
+        let rx = self.ray(u + du, v);
+        let ry = self.ray(u, v + dv);
+        self.ray(u, v).with_differential(Some(RayDifferential {
+            rx_origin: rx.origin,
+            rx_direction: rx.direction,
+            ry_origin: ry.origin,
+            ry_direction: ry.direction,
+        }))
+    }
+
+    // 視線の向き
+    pub fn forward(&self) -> Vec3 {
+        (self.w + self.u * 0.5 + self.v * 0.5 - self.origin).normalize()
+    }
+
+    // 点pが写る画面上の位置(u, v)．画面の外ならNone．
+    pub fn project(&self, p: Point3) -> Option<(f64, f64)> {
+        let d = p - self.origin;
+        let depth = d.dot(self.forward());
+        if depth <= 0.0 {
+            return None;
         }
+        // 距離1の画面上の点
+        let q = self.origin + d / depth - self.w;
+        let u = q.dot(self.u) / self.u.length_squared();
+        let v = q.dot(self.v) / self.v.length_squared();
+        if (0.0..=self.film.0).contains(&u) && (0.0..=self.film.1).contains(&v) {
+            Some((u, v))
+        } else {
+            None
+        }
+    }
+
+    // ピンホールカメラの重要度We．画面全体で積分すると1になるように正規化している．
+    // 光線の方向dで，画面の面積をAとして 1 / (A cos^4)．画面の外では0．
+    pub fn importance(&self, d: Vec3) -> f64 {
+        match self.film_cos(d) {
+            Some(cos_theta) => 1.0 / (self.film_area() * cos_theta.powi(4)),
+            None => 0.0,
+        }
+    }
+
+    // 方向dに光線を打ち出す立体角あたりの確率密度 1 / (A cos^3)
+    pub fn pdf_direction(&self, d: Vec3) -> f64 {
+        match self.film_cos(d) {
+            Some(cos_theta) => 1.0 / (self.film_area() * cos_theta.powi(3)),
+            None => 0.0,
+        }
+    }
+
+    // 距離1の画面での面積
+    fn film_area(&self) -> f64 {
+        self.u.length() * self.v.length() * self.film.0 * self.film.1
+    }
+
+    // 方向dが画面の中を通るときの視線とのなす角の余弦
+    fn film_cos(&self, d: Vec3) -> Option<f64> {
+        self.project(self.origin + d)?;
+        Some(d.normalize().dot(self.forward()))
     }
 }
\ No newline at end of file
@@ -0,0 +1,122 @@
+// 区分的に一定な確率分布．環境光や光源の重点的サンプリングに使う．
+pub struct Distribution1D {
+    func: Vec<f64>,
+    cdf: Vec<f64>,
+    integral: f64,
+}
+
+impl Distribution1D {
+    pub fn new(func: Vec<f64>) -> Self {
+        let n = func.len();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 1..=n {
+            cdf[i] = cdf[i - 1] + func[i - 1].abs() / n as f64;
+        }
+        let integral = cdf[n];
+        for (i, c) in cdf.iter_mut().enumerate() {
+            // 全て0なら一様分布にする
+            *c = if integral > 0.0 { *c / integral } else { i as f64 / n as f64 };
+        }
+        Self { func, cdf, integral }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    // [0, 1]上での関数の積分
+    pub fn integral(&self) -> f64 {
+        self.integral
+    }
+
+    // cdf[i] <= r < cdf[i + 1] となるiを探す
+    fn find(&self, r: f64) -> usize {
+        let i = self.cdf.partition_point(|&c| c <= r);
+        i.saturating_sub(1).min(self.count() - 1)
+    }
+
+    // 乱数rから[0, 1)の値を選ぶ．(値, 確率密度, 区間の番号)を返す．
+    pub fn sample_continuous(&self, r: f64) -> (f64, f64, usize) {
+        let i = self.find(r);
+        let width = self.cdf[i + 1] - self.cdf[i];
+        let du = if width > 0.0 { (r - self.cdf[i]) / width } else { 0.0 };
+        let x = ((i as f64 + du) / self.count() as f64).min(1.0 - f64::EPSILON);
+        (x, self.pdf_continuous(x), i)
+    }
+
+    // 乱数rから区間の番号を選ぶ．(番号, 確率)を返す．
+    pub fn sample_discrete(&self, r: f64) -> (usize, f64) {
+        let i = self.find(r);
+        (i, self.pmf(i))
+    }
+
+    pub fn pdf_continuous(&self, x: f64) -> f64 {
+        let i = ((x * self.count() as f64) as usize).min(self.count() - 1);
+        if self.integral > 0.0 { self.func[i].abs() / self.integral } else { 1.0 }
+    }
+
+    pub fn pmf(&self, i: usize) -> f64 {
+        self.cdf[i + 1] - self.cdf[i]
+    }
+}
+
+// 2次元の分布．行(v)を選んでから列(u)を選ぶ．
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    // funcは行ごとに並べた width * height 個の値
+    pub fn new(func: &[f64], width: usize, height: usize) -> Self {
+        let conditional: Vec<Distribution1D> = func.chunks(width)
+            .take(height)
+            .map(|row| Distribution1D::new(row.to_vec()))
+            .collect();
+        let marginal = Distribution1D::new(conditional.iter().map(|d| d.integral()).collect());
+        Self { conditional, marginal }
+    }
+
+    // (u, v)と確率密度を返す
+    pub fn sample(&self, r1: f64, r2: f64) -> ((f64, f64), f64) {
+        let (v, pdf_v, row) = self.marginal.sample_continuous(r2);
+        let (u, pdf_u, _) = self.conditional[row].sample_continuous(r1);
+        ((u, v), pdf_u * pdf_v)
+    }
+
+    pub fn pdf(&self, u: f64, v: f64) -> f64 {
+        let row = ((v * self.marginal.count() as f64) as usize).min(self.marginal.count() - 1);
+        self.marginal.pdf_continuous(v) * self.conditional[row].pdf_continuous(u)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distribution1d() {
+        let d = Distribution1D::new(vec![1.0, 3.0, 0.0, 4.0]);
+        assert_eq!(d.integral(), 2.0);
+        assert_eq!(d.sample_discrete(0.0), (0, 0.125));
+        assert_eq!(d.sample_discrete(0.2).0, 1);
+        assert_eq!(d.sample_discrete(0.6).0, 3);
+        let (x, pdf, i) = d.sample_continuous(0.5);
+        assert_eq!(i, 3);
+        assert!((0.75..1.0).contains(&x));
+        assert_eq!(pdf, 2.0);
+        // 0の区間は選ばれない
+        for k in 0..100 {
+            assert_ne!(d.sample_discrete(k as f64 / 100.0).0, 2);
+        }
+    }
+
+    #[test]
+    fn test_distribution2d() {
+        let d = Distribution2D::new(&[0.0, 1.0, 0.0, 3.0], 2, 2);
+        let ((u, v), pdf) = d.sample(0.5, 0.9);
+        assert!(u >= 0.5 && v >= 0.5);
+        assert!((pdf - d.pdf(u, v)).abs() < 1e-12);
+        assert!((d.pdf(0.75, 0.75) - 3.0).abs() < 1e-12);
+    }
+}
@@ -1,5 +1,5 @@
 use crate::rayt_mod::*;
-use rand::prelude::*;   // 乱数
+use crate::rayt_mod::sampler;
 use std::iter::FromIterator;
 
 // f64型タプル構造体．コピーにしたのは簡単のため．
@@ -155,6 +155,21 @@ impl Float3 {
     pub fn g(&self) -> u8 { (255.99 * self.0[1].min(1.0).max(0.0)) as u8 }
     pub fn b(&self) -> u8 { (255.99 * self.0[2].min(1.0).max(0.0)) as u8 }
 
+    // 輝度(Rec.709の重み)
+    pub fn luminance(&self) -> f64 {
+        0.2126 * self.0[0] + 0.7152 * self.0[1] + 0.0722 * self.0[2]
+    }
+
+    // XYZからリニアsRGBへ
+    pub fn xyz_to_rgb(&self) -> Self {
+        let [x, y, z] = self.0;
+        Self::new(
+            3.2406 * x - 1.5372 * y - 0.4986 * z,
+            -0.9689 * x + 1.8758 * y + 0.0415 * z,
+            0.0557 * x - 0.2040 * y + 1.0570 * z,
+        )
+    }
+
     pub fn gamma(&self, factor: f64) -> Self {
         let recip = factor.recip();
         Self::from_iter(self.0.iter().map(|x| x.powf(recip)))
@@ -163,16 +178,30 @@ impl Float3 {
     pub fn degamma(&self, factor: f64) -> Self {
         Self::from_iter(self.0.iter().map(|x| x.powf(factor)))
     }
+
+    // sRGBの伝達関数(EOTF)．sRGBでエンコードされた値をリニアに戻す．
+    pub fn srgb_to_linear(&self) -> Self {
+        Self::from_iter(self.0.iter().map(|&x| {
+            if x <= 0.04045 { x / 12.92 } else { ((x + 0.055) / 1.055).powf(2.4) }
+        }))
+    }
+
+    // srgb_to_linearの逆変換
+    pub fn linear_to_srgb(&self) -> Self {
+        Self::from_iter(self.0.iter().map(|&x| {
+            if x <= 0.0031308 { x * 12.92 } else { 1.055 * x.powf(2.4_f64.recip()) - 0.055 }
+        }))
+    }
 }
 
 impl Float3 {
 
-    // ランダムなベクトルの生成
+    // ランダムなベクトルの生成．乱数はスレッドに設定された供給元から取り出す．
     pub fn random() -> Self {
-        Self::new(random::<f64>(), random::<f64>(), random::<f64>())
+        Self::new(sampler::next_f64(), sampler::next_f64(), sampler::next_f64())
     }
     pub fn random_full() -> Self {
-        Self::full(random::<f64>())
+        Self::full(sampler::next_f64())
     }
     pub fn random_limit(min: f64, max: f64) -> Self {
         Self::from_iter(Self::random().0.iter().map(|x| min + x * (max - min)))
@@ -339,4 +368,15 @@ mod tests {
         assert_eq!(Float3::new(0.0, 1.0, 1.0), Float3::from_rgb(0, 255, 255));
         assert_eq!(Float3::new(12.0 / 255.0, 96.0 / 255.0, 183.0 / 255.0), Float3::from_rgb(12, 96, 183));
     }
+
+    #[test]
+    fn test_srgb() {
+        assert_eq!(Float3::zero(), Float3::zero().srgb_to_linear());
+        assert!((Float3::one() - Float3::one().srgb_to_linear()).near_zero());
+        assert!((Float3::full(0.5).srgb_to_linear().x() - 0.214041).abs() < 1e-6);
+        for _ in 0..100 {
+            let c = Float3::random();
+            assert!((c.srgb_to_linear().linear_to_srgb() - c).near_zero());
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,178 @@
+// HDR画像の読み書き．Radiance .hdr，OpenEXR，PFMに対応する．
+// 値は8bitに丸めず，1.0を超える輝度もそのまま持つ．
+use crate::rayt_mod::*;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+pub type HdrResult<T> = Result<T, Box<dyn Error>>;
+
+// リニアな浮動小数点画像．画素は左上から行ごとに並ぶ．
+pub struct FloatImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Color>,
+    pub alphas: Vec<f64>,
+}
+
+impl FloatImage {
+    pub fn new(width: usize, height: usize, pixels: Vec<Color>) -> Self {
+        Self { width, height, alphas: vec![1.0; pixels.len()], pixels }
+    }
+}
+
+// 拡張子がHDR形式かどうか
+pub fn is_hdr_path(path: &str) -> bool {
+    let ext = Path::new(path).extension().and_then(|e| e.to_str()).unwrap_or("").to_ascii_lowercase();
+    matches!(ext.as_str(), "hdr" | "exr" | "pfm")
+}
+
+// 拡張子で形式を判断して読み込む
+pub fn read_float_image(path: &str) -> HdrResult<FloatImage> {
+    let ext = Path::new(path).extension().and_then(|e| e.to_str()).unwrap_or("").to_ascii_lowercase();
+    match ext.as_str() {
+        "hdr" => read_radiance(path),
+        "exr" => read_exr(path),
+        "pfm" => read_pfm(path),
+        _ => Err(format!("unsupported HDR format: {}", path).into()),
+    }
+}
+
+// Radiance RGBE (.hdr)
+pub fn read_radiance(path: &str) -> HdrResult<FloatImage> {
+    let decoder = image::codecs::hdr::HdrDecoder::new(BufReader::new(File::open(path)?))?;
+    let meta = decoder.metadata();
+    let pixels = decoder.read_image_hdr()?
+        .iter()
+        .map(|p| Color::new(p[0] as f64, p[1] as f64, p[2] as f64))
+        .collect();
+    Ok(FloatImage::new(meta.width as usize, meta.height as usize, pixels))
+}
+
+// OpenEXR．最初のRGB(A)レイヤーを読む．
+pub fn read_exr(path: &str) -> HdrResult<FloatImage> {
+    let image = exr::prelude::read_first_rgba_layer_from_file(
+        path,
+        |resolution, _| {
+            let size = resolution.width() * resolution.height();
+            FloatImage {
+                width: resolution.width(),
+                height: resolution.height(),
+                pixels: vec![Color::zero(); size],
+                alphas: vec![1.0; size],
+            }
+        },
+        |image: &mut FloatImage, position, (r, g, b, a): (f32, f32, f32, f32)| {
+            let i = position.x() + image.width * position.y();
+            image.pixels[i] = Color::new(r as f64, g as f64, b as f64);
+            image.alphas[i] = a as f64;
+        },
+    )?;
+    Ok(image.layer_data.channel_data.pixels)
+}
+
+// Portable Float Map．ヘッダの後に下の行から順に32bit浮動小数点が並ぶ．
+// scaleが負ならリトルエンディアン．
+pub fn read_pfm(path: &str) -> HdrResult<FloatImage> {
+    let mut data = Vec::new();
+    BufReader::new(File::open(path)?).read_to_end(&mut data)?;
+
+    // ヘッダは空白区切りの4トークン．最後のトークンの直後の空白1文字から画素データ．
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while tokens.len() < 4 {
+        while pos < data.len() && data[pos].is_ascii_whitespace() { pos += 1; }
+        let start = pos;
+        while pos < data.len() && !data[pos].is_ascii_whitespace() { pos += 1; }
+        if start == pos {
+            return Err("truncated PFM header".into());
+        }
+        tokens.push(std::str::from_utf8(&data[start..pos])?.to_string());
+    }
+    pos += 1;
+
+    let channels = match tokens[0].as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(format!("not a PFM file: {}", path).into()),
+    };
+    let width: usize = tokens[1].parse()?;
+    let height: usize = tokens[2].parse()?;
+    let scale: f64 = tokens[3].parse()?;
+    let little_endian = scale < 0.0;
+
+    let count = width * height * channels;
+    let body = data.get(pos..pos + count * 4).ok_or("truncated PFM data")?;
+    let values: Vec<f64> = body.chunks_exact(4).map(|b| {
+        let bytes = [b[0], b[1], b[2], b[3]];
+        let v = if little_endian { f32::from_le_bytes(bytes) } else { f32::from_be_bytes(bytes) };
+        v as f64
+    }).collect();
+
+    let mut pixels = vec![Color::zero(); width * height];
+    for y in 0..height {
+        // PFMは下の行から並んでいるので上下を反転する
+        let row = height - 1 - y;
+        for x in 0..width {
+            let i = (x + width * y) * channels;
+            pixels[x + width * row] = if channels == 3 {
+                Color::new(values[i], values[i + 1], values[i + 2])
+            } else {
+                Color::full(values[i])
+            };
+        }
+    }
+    Ok(FloatImage::new(width, height, pixels))
+}
+
+// PFMで書き出す(リトルエンディアン，カラー)
+pub fn write_pfm(path: &str, image: &FloatImage) -> HdrResult<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    write!(w, "PF\n{} {}\n-1.0\n", image.width, image.height)?;
+    for y in (0..image.height).rev() {
+        for x in 0..image.width {
+            for v in image.pixels[x + image.width * y].iter() {
+                w.write_all(&(*v as f32).to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient() -> FloatImage {
+        let pixels = (0..6).map(|i| Color::new(i as f64, 0.5, 100.0 + i as f64)).collect();
+        FloatImage::new(3, 2, pixels)
+    }
+
+    #[test]
+    fn test_pfm() {
+        let path = std::env::temp_dir().join("rayt_test.pfm");
+        let path = path.to_str().unwrap();
+        let image = gradient();
+        write_pfm(path, &image).unwrap();
+        let loaded = read_float_image(path).unwrap();
+        assert_eq!((loaded.width, loaded.height), (3, 2));
+        assert_eq!(loaded.pixels, image.pixels);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_exr() {
+        let path = std::env::temp_dir().join("rayt_test.exr");
+        let path = path.to_str().unwrap();
+        let image = gradient();
+        exr::prelude::write_rgba_file(path, image.width, image.height, |x, y| {
+            let [r, g, b] = image.pixels[x + image.width * y].to_array();
+            (r as f32, g as f32, b as f32, 0.5_f32)
+        }).unwrap();
+        let loaded = read_float_image(path).unwrap();
+        assert_eq!(loaded.pixels, image.pixels);
+        assert_eq!(loaded.alphas, vec![0.5; 6]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+// 点の集合に対するkd木．フォトンマップで半径内の点を探すのに使う．
+use crate::rayt_mod::*;
+
+pub struct KdTree<T> {
+    // 区間[lo, hi)の中央の要素がその部分木の根になるように並べてある
+    nodes: Vec<(Point3, T)>,
+    // 各要素で分割する軸
+    axes: Vec<usize>,
+}
+
+impl<T> KdTree<T> {
+    pub fn new(mut items: Vec<(Point3, T)>) -> Self {
+        let mut axes = vec![0; items.len()];
+        Self::build(&mut items, &mut axes);
+        Self { nodes: items, axes }
+    }
+
+    // 範囲の一番広い軸の中央値で分ける
+    fn build(items: &mut [(Point3, T)], axes: &mut [usize]) {
+        if items.len() <= 1 {
+            return;
+        }
+        let (min, max) = items.iter().fold(([f64::MAX; 3], [f64::MIN; 3]), |(mut min, mut max), (p, _)| {
+            for (i, &x) in p.iter().enumerate() {
+                min[i] = min[i].min(x);
+                max[i] = max[i].max(x);
+            }
+            (min, max)
+        });
+        let axis = (0..3).fold(0, |a, i| if max[i] - min[i] > max[a] - min[a] { i } else { a });
+        let mid = items.len() / 2;
+        items.select_nth_unstable_by(mid, |a, b| a.0.to_array()[axis].total_cmp(&b.0.to_array()[axis]));
+        axes[mid] = axis;
+        let (left, right) = items.split_at_mut(mid);
+        let (left_axes, right_axes) = axes.split_at_mut(mid);
+        Self::build(left, left_axes);
+        Self::build(&mut right[1..], &mut right_axes[1..]);
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    // 点pから半径radius以内の全ての要素についてfを呼ぶ
+    pub fn for_each_within(&self, p: Point3, radius: f64, mut f: impl FnMut(Point3, &T)) {
+        self.query(0, self.nodes.len(), p, radius * radius, &mut f);
+    }
+
+    fn query(&self, lo: usize, hi: usize, p: Point3, r2: f64, f: &mut impl FnMut(Point3, &T)) {
+        if lo >= hi {
+            return;
+        }
+        let mid = lo + (hi - lo) / 2;
+        let (q, item) = &self.nodes[mid];
+        if (*q - p).length_squared() <= r2 {
+            f(*q, item);
+        }
+        let axis = self.axes[mid];
+        let d = p.to_array()[axis] - q.to_array()[axis];
+        // 点のある側を先に調べ，反対側は分割面が半径内にあるときだけ調べる
+        let (near, far) = if d <= 0.0 { ((lo, mid), (mid + 1, hi)) } else { ((mid + 1, hi), (lo, mid)) };
+        self.query(near.0, near.1, p, r2, f);
+        if d * d <= r2 {
+            self.query(far.0, far.1, p, r2, f);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kdtree() {
+        let points: Vec<(Point3, usize)> = (0..1000).map(|i| (Vec3::random() * 10.0, i)).collect();
+        let tree = KdTree::new(points.clone());
+        assert_eq!(tree.len(), 1000);
+        for _ in 0..20 {
+            let p = Vec3::random() * 10.0;
+            let mut found = Vec::new();
+            tree.for_each_within(p, 1.5, |_, &i| found.push(i));
+            found.sort_unstable();
+            let expected: Vec<usize> = points.iter().filter(|(q, _)| (*q - p).length() <= 1.5).map(|&(_, i)| i).collect();
+            assert_eq!(found, expected);
+        }
+    }
+}
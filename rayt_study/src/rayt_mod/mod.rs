@@ -5,14 +5,24 @@ mod camera;
 mod window;
 mod render;
 mod onb;
+mod hdr;
+mod distribution;
+mod sky;
+mod kdtree;
+mod sampler;
 
 pub use self::float3::{Float3, Color, Vec3, Point3};
 pub use self::quat::Quat;
-pub use self::ray::Ray;
+pub use self::ray::{Ray, RayDifferential};
 pub use self::camera::Camera;
 pub use self::window::*;
 pub use self::render::*;
 pub use self::onb::ONB;
+pub use self::hdr::*;
+pub use self::distribution::{Distribution1D, Distribution2D};
+pub use self::sky::Sky;
+pub use self::kdtree::KdTree;
+pub use self::sampler::{MltSampler, Pcg32, RandomSource, SamplerKind, Site, new_sampler, resume_pixel_sample, start_dimension, stream_key, with_pixel_sample, with_source, with_stream};
 pub use std::sync::Arc;
 pub use std::f64::consts::PI;
 pub use std::f64::consts::FRAC_1_PI;
@@ -5,14 +5,27 @@ pub struct ONB {
 }
 
 impl ONB {
+    // 法線だけから任意の接ベクトルを決める．
+    // Duff et al. "Building an Orthonormal Basis, Revisited" の方法で，どの向きの法線でも破綻しない．
     pub fn new(n: Vec3) -> Self {
         let w = n.normalize();
-        let v = if w.x().abs() > 0.9 {
-            w.cross(Vec3::yaxis()).normalize()
-        } else {
-            w.cross(Vec3::yaxis()).normalize()
-        };
-        let u = w.cross(v);
+        let sign = 1.0_f64.copysign(w.z());
+        let a = -1.0 / (sign + w.z());
+        let b = w.x() * w.y() * a;
+        let u = Vec3::new(1.0 + sign * w.x() * w.x() * a, sign * b, -sign * w.x());
+        let v = Vec3::new(b, sign + w.y() * w.y() * a, -w.y());
+        Self { axis: [u, v, w]}
+    }
+
+    // 法線と接ベクトルから作る．接ベクトルは法線に直交するように補正する．
+    pub fn from_tangent(n: Vec3, tangent: Vec3) -> Self {
+        let w = n.normalize();
+        let t = tangent - w * tangent.dot(w);
+        if t.length_squared() < EPS {
+            return Self::new(n);
+        }
+        let u = t.normalize();
+        let v = w.cross(u);
         Self { axis: [u, v, w]}
     }
 
@@ -23,4 +36,44 @@ impl ONB {
     pub fn local(&self, v: Vec3) -> Vec3 {
         self.axis[0] * v.x() + self.axis[1] * v.y() + self.axis[2] * v.z()
     }
+
+    // localの逆変換．ワールド座標のベクトルを基底の座標で表す．
+    pub fn project(&self, v: Vec3) -> Vec3 {
+        Vec3::new(self.axis[0].dot(v), self.axis[1].dot(v), self.axis[2].dot(v))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_orthonormal(onb: &ONB) {
+        for (a, b) in [(onb.u(), onb.v()), (onb.v(), onb.w()), (onb.w(), onb.u())] {
+            assert!(a.dot(b).abs() < EPS);
+            assert!((a.length() - 1.0).abs() < EPS);
+        }
+        assert!((onb.u().cross(onb.v()) - onb.w()).near_zero());
+    }
+
+    #[test]
+    fn test_onb() {
+        for n in [Vec3::xaxis(), Vec3::yaxis(), Vec3::zaxis(), -Vec3::zaxis(), Vec3::new(0.0, 1e-9, -1.0)] {
+            assert_orthonormal(&ONB::new(n));
+        }
+        for _ in 0..100 {
+            let n = Vec3::random_unit_vector();
+            let onb = ONB::new(n);
+            assert_orthonormal(&onb);
+            let v = Vec3::random();
+            assert!((onb.local(onb.project(v)) - v).near_zero());
+        }
+    }
+
+    #[test]
+    fn test_onb_from_tangent() {
+        let onb = ONB::from_tangent(Vec3::zaxis(), Vec3::new(1.0, 0.0, 0.5));
+        assert_orthonormal(&onb);
+        assert!((onb.u() - Vec3::xaxis()).near_zero());
+        assert_orthonormal(&ONB::from_tangent(Vec3::zaxis(), Vec3::zaxis()));
+    }
 }
@@ -5,15 +5,40 @@ use crate::rayt_mod::*;
 pub struct Ray {
     pub origin: Point3,
     pub direction: Vec3,
+    // 隣の画素へ向かう光線．テクスチャのフィルタ幅を決めるのに使う．
+    pub differential: Option<RayDifferential>,
+}
+
+// 光線微分．画面上でx方向，y方向に1画素ずらした光線を持つ．
+#[derive(Debug, Clone, Copy)]
+pub struct RayDifferential {
+    pub rx_origin: Point3,
+    pub rx_direction: Vec3,
+    pub ry_origin: Point3,
+    pub ry_direction: Vec3,
 }
 
 impl Ray {
     pub fn new(origin: Point3, direction: Vec3) -> Self {
-        Self { origin, direction }
+        Self { origin, direction, differential: None }
+    }
+
+    pub fn with_differential(self, differential: Option<RayDifferential>) -> Self {
+        Self { differential, ..self }
     }
 
     // パラメータtを指定して始点から特定方向を指すベクトルを返す．
     pub fn at(&self, t: f64) -> Point3 {
         self.origin + t * self.direction
     }
+
+    // 隣の光線との差をs倍に縮める．1画素に複数サンプルを打つときに使う．
+    pub fn scale_differential(&mut self, s: f64) {
+        if let Some(d) = self.differential.as_mut() {
+            d.rx_origin = self.origin + (d.rx_origin - self.origin) * s;
+            d.ry_origin = self.origin + (d.ry_origin - self.origin) * s;
+            d.rx_direction = self.direction + (d.rx_direction - self.direction) * s;
+            d.ry_direction = self.direction + (d.ry_direction - self.direction) * s;
+        }
+    }
 }
@@ -2,12 +2,19 @@ use crate::rayt_mod::*;
 use image::{Rgb, RgbImage};
 use rayon::prelude::*;
 use std::{path::Path, fs};
+use std::{cell::RefCell, rc::Rc};
 
 const IMAGE_WIDTH: u32 = 200;
 const IMAGE_HEIGHT: u32 = 100;
 const SAMPLES_PER_PIXEL: usize = 100;   // サンプル数
-const GAMMA_FACTOR: f64 = 2.2;
-const MAX_RAY_BOUNCE_DEPTH: usize = 50;
+const MAX_RAY_BOUNCE_DEPTH: usize = 50;  // 安全のための上限．普段はロシアンルーレットで打ち切る
+const ROULETTE_DEPTH: usize = 3;         // ロシアンルーレットを始める反射回数
+const SPLAT_CHUNK_ROWS: usize = 8;       // スプラットをまとめる行数
+const MLT_BOOTSTRAP: usize = 100_000;    // メトロポリス法の正規化に使う標本数
+const MLT_CHAINS: usize = 1000;          // マルコフ連鎖の数
+const MLT_CHAINS_PER_FILM: usize = 50;   // 同じ画像に足し込む連鎖の数
+const MLT_SIGMA: f64 = 0.01;             // 小さな変異の幅
+const MLT_LARGE_STEP: f64 = 0.3;         // 大きな変異の確率
 const OUTPUT_FILENAME: &str = "render.png";
 const BACKUP_FILENAME: &str = "render_bak.png";
 
@@ -35,6 +42,9 @@ pub trait Scene {
     fn width(&self) -> u32 { IMAGE_WIDTH }
     fn height(&self) -> u32 { IMAGE_HEIGHT }
     fn spp(&self) -> usize { SAMPLES_PER_PIXEL }
+    fn sampler(&self) -> SamplerKind { SamplerKind::Independent }
+    // 乱数の種．同じ種なら同じ画像になる．
+    fn seed(&self) -> u64 { 0 }
     fn aspect(&self) -> f64 { self.width() as f64 / self.height() as f64 }
 }
 
@@ -44,9 +54,38 @@ pub trait SceneWithDepth {
     fn width(&self) -> u32 { IMAGE_WIDTH }
     fn height(&self) -> u32 { IMAGE_HEIGHT }
     fn spp(&self) -> usize { SAMPLES_PER_PIXEL }
+    fn max_depth(&self) -> usize { MAX_RAY_BOUNCE_DEPTH }
+    fn roulette_depth(&self) -> usize { ROULETTE_DEPTH }
+    fn sampler(&self) -> SamplerKind { SamplerKind::Independent }
+    // 乱数の種．同じ種なら同じ画像になる．
+    fn seed(&self) -> u64 { 0 }
     fn aspect(&self) -> f64 { self.width() as f64 / self.height() as f64 }
 }
 
+// 光源側から画面に届いた光を，光線を打ち出した画素とは別の画素に足し込めるシーン．
+// 双方向パストレーシングなどの光源からの追跡で使う．
+pub trait SceneWithSplat: SceneWithDepth {
+    // 戻り値は光線の画素に足す光．splatsには画面上の位置(u, v)と光を追加する．
+    fn trace_splat(&self, ray: Ray, depth: usize, splats: &mut Vec<(f64, f64, Color)>) -> Color;
+}
+
+// 全画素をまとめて反復ごとに更新していくシーン．プログレッシブフォトンマップのように，
+// 画素をまたいだ計算を反復ごとに行う．反復回数はspp．
+pub trait SceneWithPasses: SceneWithDepth {
+    // 画素ごとに持つ状態
+    type Pixel: Send;
+    fn new_pixel(&self) -> Self::Pixel;
+    // raysは各画素の光線．1回分の反復で画素の状態を更新する．
+    fn pass(&self, rays: &[Ray], pixels: &mut [Self::Pixel]);
+    fn pixel_color(&self, pixel: &Self::Pixel) -> Color;
+    // 反復passでi番目の画素の光線を作ったサンプルの続きとしてfを実行する
+    fn resume<R>(&self, i: usize, pass: usize, f: impl FnOnce() -> R) -> R {
+        let (x, y) = (i as u32 % self.width(), i as u32 / self.width());
+        let sampler = new_sampler(self.sampler(), self.spp(), self.seed());
+        resume_pixel_sample(&sampler, x, y, pass, f)
+    }
+}
+
 // シーンインスタンスのオブジェクトが複数のスレッドから参照されるため，Syncマーカーとレイトを指定．
 pub fn render(scene: impl Scene + Sync) {
     backup();
@@ -60,8 +99,9 @@ pub fn render(scene: impl Scene + Sync) {
             let u = *x as f64 / (scene.width() - 1) as f64;
             let v = (scene.height() - *y - 1) as f64 / (scene.height() - 1) as f64;
             let ray = camera.ray(u, v);
-            let rgb = scene.trace(ray).to_rgb();
-            // let rgb = scene.trace(ray).gamma(GAMMA_FACTOR).saturate().to_rgb();
+            let key = [*x as u64, *y as u64];
+            let rgb = with_stream(scene.seed(), &key, || scene.trace(ray)).to_rgb();
+            // let rgb = scene.trace(ray).linear_to_srgb().saturate().to_rgb();
             pixel[0] = rgb[0];
             pixel[1] = rgb[1];
             pixel[2] = rgb[2];
@@ -81,15 +121,18 @@ pub fn render_aa(scene: impl Scene + Sync) {
         .collect::<Vec<(u32, u32, &mut Rgb<u8>)>>()
         .par_iter_mut()
         .for_each(|(x, y, pixel)| {
-            let mut pixel_color = (0..scene.spp()).into_iter().fold(Color::zero(), |acc, _| {
-                let [rx, ry, _] = Float3::random().to_array();
-                let u = (*x as f64 + rx) / (scene.width() - 1) as f64;
-                let v = ((scene.height() - *y - 1) as f64 + ry) / (scene.height() - 1) as f64;
-                let ray = camera.ray(u, v);
-                acc + scene.trace(ray)
+            let sampler = new_sampler(scene.sampler(), scene.spp(), scene.seed());
+            let mut pixel_color = (0..scene.spp()).fold(Color::zero(), |acc, i| {
+                with_pixel_sample(&sampler, *x, *y, i, || {
+                    let (rx, ry) = (sampler::next_f64(), sampler::next_f64());
+                    let u = (*x as f64 + rx) / (scene.width() - 1) as f64;
+                    let v = ((scene.height() - *y - 1) as f64 + ry) / (scene.height() - 1) as f64;
+                    let ray = camera.ray(u, v);
+                    acc + scene.trace(ray)
+                })
             });
             pixel_color /= scene.spp() as f64;
-            let rgb = pixel_color.gamma(GAMMA_FACTOR).to_rgb();
+            let rgb = pixel_color.linear_to_srgb().to_rgb();
             pixel[0] = rgb[0];
             pixel[1] = rgb[1];
             pixel[2] = rgb[2];
@@ -98,29 +141,246 @@ pub fn render_aa(scene: impl Scene + Sync) {
     draw_in_widow(BACKUP_FILENAME, img).unwrap();
 }
 
+// 画素(x, y)の中からランダムに選んだ位置を通る光線微分付きの光線．
+// 位置にはサンプラーの最初の2次元(PIXEL_DIMENSIONS)だけを使う．
+fn pixel_ray(scene: &impl SceneWithDepth, camera: &Camera, x: u32, y: u32) -> Ray {
+    let (rx, ry) = (sampler::next_f64(), sampler::next_f64());
+    let u = (x as f64 + rx) / (scene.width() - 1) as f64;
+    let v = ((scene.height() - y - 1) as f64 + ry) / (scene.height() - 1) as f64;
+    screen_ray(scene, camera, u, v)
+}
+
+// 画面上の位置(u, v)を通る光線微分付きの光線
+fn screen_ray(scene: &impl SceneWithDepth, camera: &Camera, u: f64, v: f64) -> Ray {
+    let du = ((scene.width() - 1) as f64).recip();
+    let dv = ((scene.height() - 1) as f64).recip();
+    // サンプル数が多いほど1サンプルの受け持つ範囲は狭い
+    let scale = (scene.spp() as f64).sqrt().recip().max(0.125);
+    let mut ray = camera.ray_differential(u, v, du, dv);
+    ray.scale_differential(scale);
+    ray
+}
+
+// 画面上の位置(u, v)の画素の番号．1画素の幅は1 / (画素数 - 1)で，pixel_rayと同じく
+// 右端と上端の画素は[0, 1]の外の範囲(Camera::with_film)まで受け持つ．
+fn splat_index(width: usize, height: usize, u: f64, v: f64) -> usize {
+    let x = ((u * (width - 1) as f64) as usize).min(width - 1);
+    let y = height - 1 - ((v * (height - 1) as f64) as usize).min(height - 1);
+    x + y * width
+}
+
+// 画素の色を並べた画像を保存して表示する
+fn save_colors(width: u32, height: u32, colors: &[Color]) {
+    let mut img = RgbImage::new(width, height);
+    for (pixel, color) in img.pixels_mut().zip(colors) {
+        let rgb = color.linear_to_srgb().to_rgb();
+        pixel[0] = rgb[0];
+        pixel[1] = rgb[1];
+        pixel[2] = rgb[2];
+    }
+    img.save(OUTPUT_FILENAME).unwrap();
+    draw_in_widow(BACKUP_FILENAME, img).unwrap();
+}
+
 // 反射の計算が多くなりすぎてもよくないので打ち切りの実装
 pub fn render_aa_with_depth(scene: impl SceneWithDepth + Sync) {
     backup();
 
+    let colors = pixel_colors(&scene);
+    save_colors(scene.width(), scene.height(), &colors);
+}
+
+// 各画素のspp個のサンプルの平均．乱数は画素とサンプルの番号で決まるので，
+// 並列に処理する順番によらず同じ結果になる．
+pub fn pixel_colors(scene: &(impl SceneWithDepth + Sync)) -> Vec<Color> {
     let camera = scene.camera();
-    let mut img = RgbImage::new(scene.width(), scene.height());
-    img.enumerate_pixels_mut()
-        .collect::<Vec<(u32, u32, &mut Rgb<u8>)>>()
-        .par_iter_mut()
-        .for_each(|(x, y, pixel)| {
-            let mut pixel_color = (0..scene.spp()).into_iter().fold(Color::zero(), |acc, _| {
-                let [rx, ry, _] = Float3::random().to_array();
-                let u = (*x as f64 + rx) / (scene.width() - 1) as f64;
-                let v = ((scene.height() - *y - 1) as f64 + ry) / (scene.height() - 1) as f64;
-                let ray = camera.ray(u, v);
-                acc + scene.trace(ray, MAX_RAY_BOUNCE_DEPTH)
+    (0..scene.width() * scene.height())
+        .into_par_iter()
+        .map(|p| {
+            let (x, y) = (p % scene.width(), p / scene.width());
+            let sampler = new_sampler(scene.sampler(), scene.spp(), scene.seed());
+            let pixel_color = (0..scene.spp()).fold(Color::zero(), |acc, i| {
+                with_pixel_sample(&sampler, x, y, i, || {
+                    let ray = pixel_ray(scene, &camera, x, y);
+                    acc + scene.trace(ray, scene.max_depth())
+                })
             });
-            pixel_color /= scene.spp() as f64;
-            let rgb = pixel_color.gamma(GAMMA_FACTOR).to_rgb();
-            pixel[0] = rgb[0];
-            pixel[1] = rgb[1];
-            pixel[2] = rgb[2];
-        });
+            pixel_color / scene.spp() as f64
+        })
+        .collect()
+}
+
+// スプラット付きの描画．行をまとめて並列に処理し，まとまりごとに持ったスプラット用の画像を
+// 最後に順番に足し合わせる．
+pub fn render_with_splats(scene: impl SceneWithSplat + Sync) {
+    backup();
+
+    let camera = scene.camera();
+    let (width, height) = (scene.width() as usize, scene.height() as usize);
+    let rows: Vec<u32> = (0..scene.height()).collect();
+    let chunks: Vec<(Vec<Color>, Vec<Color>)> = rows
+        .par_chunks(SPLAT_CHUNK_ROWS)
+        .map(|ys| {
+            let mut film = vec![Color::zero(); width * height];
+            let mut splats = Vec::new();
+            let mut colors = Vec::with_capacity(ys.len() * width);
+            let sampler = new_sampler(scene.sampler(), scene.spp(), scene.seed());
+            for &y in ys {
+                for x in 0..scene.width() {
+                    let color = (0..scene.spp()).fold(Color::zero(), |acc, i| {
+                        let color = with_pixel_sample(&sampler, x, y, i, || {
+                            let ray = pixel_ray(&scene, &camera, x, y);
+                            scene.trace_splat(ray, scene.max_depth(), &mut splats)
+                        });
+                        for (u, v, c) in splats.drain(..) {
+                            film[splat_index(width, height, u, v)] += c;
+                        }
+                        acc + color
+                    });
+                    colors.push(color);
+                }
+            }
+            (colors, film)
+        })
+        .collect();
+
+    let mut colors = Vec::with_capacity(width * height);
+    let mut film = vec![Color::zero(); width * height];
+    for (chunk_colors, chunk_film) in chunks {
+        colors.extend(chunk_colors);
+        for (f, c) in film.iter_mut().zip(chunk_film) {
+            *f += c;
+        }
+    }
+
+    let mut img = RgbImage::new(scene.width(), scene.height());
+    for (i, pixel) in img.pixels_mut().enumerate() {
+        let pixel_color = (colors[i] + film[i]) / scene.spp() as f64;
+        let rgb = pixel_color.linear_to_srgb().to_rgb();
+        pixel[0] = rgb[0];
+        pixel[1] = rgb[1];
+        pixel[2] = rgb[2];
+    }
     img.save(OUTPUT_FILENAME).unwrap();
     draw_in_widow(BACKUP_FILENAME, img).unwrap();
+}
+
+// 反復ごとに全画素の光線を作り直してシーンに渡す描画
+pub fn render_progressive(scene: impl SceneWithPasses + Sync) {
+    backup();
+
+    let colors = progressive_colors(&scene);
+    save_colors(scene.width(), scene.height(), &colors);
+}
+
+// spp回の反復を終えた後の各画素の色
+pub fn progressive_colors(scene: &(impl SceneWithPasses + Sync)) -> Vec<Color> {
+    let camera = scene.camera();
+    let mut pixels: Vec<_> = (0..scene.width() * scene.height()).map(|_| scene.new_pixel()).collect();
+    for pass in 0..scene.spp() {
+        let rays: Vec<Ray> = (0..scene.width() * scene.height())
+            .into_par_iter()
+            .map(|i| {
+                let (x, y) = (i % scene.width(), i / scene.width());
+                let sampler = new_sampler(scene.sampler(), scene.spp(), scene.seed());
+                with_pixel_sample(&sampler, x, y, pass, || pixel_ray(scene, &camera, x, y))
+            })
+            .collect();
+        scene.pass(&rays, &mut pixels);
+    }
+    pixels.iter().map(|p| scene.pixel_color(p)).collect()
+}
+
+// 主標本空間のメトロポリス法(PSSMLT)による描画．画素あたりの変異の数はspp．
+pub fn render_metropolis(scene: impl SceneWithDepth + Sync) {
+    backup();
+
+    let colors = metropolis(&scene, scene.spp());
+    save_colors(scene.width(), scene.height(), &colors);
+}
+
+// 経路を作るのに使う乱数列を少しずつ変えていき，明るい経路ほど長く留まるように選ぶ．
+// 最初に独立な経路で画像全体の明るさを求めておき，それで正規化する．
+pub fn metropolis(scene: &(impl SceneWithDepth + Sync), mutations_per_pixel: usize) -> Vec<Color> {
+    let camera = scene.camera();
+    let (width, height) = (scene.width() as usize, scene.height() as usize);
+    // 乱数列から画素とその中の位置を選んで光線をたどる．画素の範囲はpixel_rayと同じ．
+    // 戻り値は画素の番号と光．
+    let path = |sampler: &Rc<RefCell<MltSampler>>| {
+        with_source(sampler.clone(), || {
+            let [u, v, _] = Float3::random().to_array();
+            let (px, py) = (u * width as f64, v * height as f64);
+            let index = (px as usize).min(width - 1) + (height - 1 - (py as usize).min(height - 1)) * width;
+            let ray = screen_ray(scene, &camera, px / (width - 1) as f64, py / (height - 1) as f64);
+            (index, scene.trace(ray, scene.max_depth()))
+        })
+    };
+    let contribution = |c: Color| {
+        let y = c.luminance();
+        if y.is_finite() && y > 0.0 { y } else { 0.0 }
+    };
+    let new_sampler = |index: usize| {
+        let seed = stream_key(&[scene.seed(), index as u64]);
+        Rc::new(RefCell::new(MltSampler::new(seed, MLT_SIGMA, MLT_LARGE_STEP)))
+    };
+
+    // 初期標本．連鎖の始めは同じseedの乱数列で作り直す．
+    let weights: Vec<f64> = (0..MLT_BOOTSTRAP)
+        .into_par_iter()
+        .map(|i| contribution(path(&new_sampler(i)).1))
+        .collect();
+    let b = weights.iter().sum::<f64>() / MLT_BOOTSTRAP as f64;
+    if b == 0.0 {
+        return vec![Color::zero(); width * height];
+    }
+    let bootstrap = Distribution1D::new(weights);
+
+    let mutations = mutations_per_pixel * width * height;
+    let chains: Vec<usize> = (0..MLT_CHAINS).collect();
+    let films: Vec<Vec<Color>> = chains
+        .par_chunks(MLT_CHAINS_PER_FILM)
+        .map(|chains| {
+            let mut film = vec![Color::zero(); width * height];
+            for &chain in chains {
+                let count = mutations / MLT_CHAINS + if chain < mutations % MLT_CHAINS { 1 } else { 0 };
+                // 連鎖の選択と採択の判定は連鎖ごとの乱数列で行う
+                let mut rng = Pcg32::new(scene.seed(), chain as u64);
+                let (index, _) = bootstrap.sample_discrete(rng.next_f64());
+                let sampler = new_sampler(index);
+                let mut current = path(&sampler);
+                let mut current_y = contribution(current.1);
+                for _ in 0..count {
+                    sampler.borrow_mut().start_iteration();
+                    let proposed = path(&sampler);
+                    let proposed_y = contribution(proposed.1);
+                    let accept = if current_y > 0.0 { (proposed_y / current_y).min(1.0) } else { 1.0 };
+                    // 採択されなかった方も採択確率で重み付けして足す
+                    if proposed_y > 0.0 {
+                        film[proposed.0] += proposed.1 * (accept / proposed_y);
+                    }
+                    if current_y > 0.0 {
+                        film[current.0] += current.1 * ((1.0 - accept) / current_y);
+                    }
+                    if rng.next_f64() < accept {
+                        current = proposed;
+                        current_y = proposed_y;
+                        sampler.borrow_mut().accept();
+                    } else {
+                        sampler.borrow_mut().reject();
+                    }
+                }
+            }
+            film
+        })
+        .collect();
+
+    // 主標本空間の面積1を全画素で等分しているので，1画素の明るさはb / 画素あたりの変異の数
+    let scale = b / mutations_per_pixel as f64;
+    let mut colors = vec![Color::zero(); width * height];
+    for film in films {
+        for (c, f) in colors.iter_mut().zip(film) {
+            *c += f * scale;
+        }
+    }
+    colors
 }
\ No newline at end of file
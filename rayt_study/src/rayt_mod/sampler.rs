@@ -0,0 +1,688 @@
+// 乱数の供給元．Float3::random*はスレッドごとに設定された供給元から乱数を取り出す．
+// 何も設定されていなければスレッドごとに別の乱数列を使う．描画では画素とサンプルの番号から
+// 決まる乱数列を設定するので，同じseedなら実行やスレッド数によらず同じ画像になる．
+// メトロポリス法では乱数列を記録して少しずつ変えながら同じ経路を作り直す．
+use crate::rayt_mod::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub trait RandomSource {
+    // [0, 1)の乱数
+    fn next_f64(&mut self) -> f64;
+    // 次の乱数をdimension次元目から取り出し，count個を超えた分は次元によらない乱数にする．
+    // 次元の並びに意味のない供給元では何もしない．
+    fn start_dimension(&mut self, _dimension: u32, _count: u32) {}
+}
+
+thread_local! {
+    static SOURCE: RefCell<Option<Rc<RefCell<dyn RandomSource>>>> = RefCell::new(None);
+    // 供給元が設定されていないときの乱数列．スレッドが同じ乱数列を使わないように，
+    // 最初に使ったスレッドから順に別の番号の乱数列にする．
+    static FALLBACK: RefCell<Pcg32> = RefCell::new(Pcg32::new(0, stream_key(&[FALLBACK_STREAMS.fetch_add(1, Ordering::Relaxed)])));
+}
+
+// 乱数列を作ったスレッドの数
+static FALLBACK_STREAMS: AtomicU64 = AtomicU64::new(0);
+
+// このスレッドの供給元から乱数を一つ取り出す
+pub fn next_f64() -> f64 {
+    let source = SOURCE.with(|s| s.borrow().clone());
+    match source {
+        Some(source) => source.borrow_mut().next_f64(),
+        None => FALLBACK.with(|rng| rng.borrow_mut().next_f64()),
+    }
+}
+
+// 画素の中の位置に使う次元の数
+pub const PIXEL_DIMENSIONS: u32 = 2;
+// 反射1回あたりに割り当てる次元の数
+pub const BOUNCE_DIMENSIONS: u32 = 10;
+
+// 反射ごとの乱数の用途．用途ごとに決まった次元を使うので，棄却法などで使う乱数の数が
+// サンプルごとに違っても，次の用途の次元はずれない．
+#[derive(Clone, Copy, Debug)]
+pub enum Site {
+    // 衝突の判定(透明度や媒質)，材質の選択，反射と屈折の選択
+    Scatter,
+    // 光源の選択と方向
+    Light,
+    // 混合分布の選択と散乱方向
+    Bsdf,
+    // ロシアンルーレット
+    Roulette,
+}
+
+impl Site {
+    // 反射の中での(先頭の次元, 次元の数)
+    fn range(self) -> (u32, u32) {
+        match self {
+            Site::Scatter => (0, 2),
+            Site::Light => (2, 4),
+            Site::Bsdf => (6, 3),
+            Site::Roulette => (9, 1),
+        }
+    }
+}
+
+// 次の乱数をbounce回目の反射のsiteの用途に割り当てた次元から取り出す
+pub fn start_dimension(bounce: usize, site: Site) {
+    let (first, count) = site.range();
+    let dimension = PIXEL_DIMENSIONS + bounce as u32 * BOUNCE_DIMENSIONS + first;
+    if let Some(source) = SOURCE.with(|s| s.borrow().clone()) {
+        source.borrow_mut().start_dimension(dimension, count);
+    }
+}
+
+// fを実行する間だけ，このスレッドの乱数の供給元をsourceにする
+pub fn with_source<R>(source: Rc<RefCell<dyn RandomSource>>, f: impl FnOnce() -> R) -> R {
+    // パニックしても元に戻す
+    struct Restore(Option<Rc<RefCell<dyn RandomSource>>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let prev = self.0.take();
+            SOURCE.with(|s| *s.borrow_mut() = prev);
+        }
+    }
+    let _restore = Restore(SOURCE.with(|s| s.borrow_mut().replace(source)));
+    f()
+}
+
+// PCG32(O'Neill 2014)．streamごとに別の乱数列になる．
+#[derive(Clone)]
+pub struct Pcg32 {
+    state: u64,
+    inc: u64,
+}
+
+impl Pcg32 {
+    pub fn new(seed: u64, stream: u64) -> Self {
+        let mut rng = Self { state: 0, inc: (stream << 1) | 1 };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(6364136223846793005).wrapping_add(self.inc);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        xorshifted.rotate_right((old >> 59) as u32)
+    }
+}
+
+impl RandomSource for Pcg32 {
+    fn next_f64(&mut self) -> f64 {
+        to_unit(self.next_u32())
+    }
+}
+
+// 値の組から乱数列の番号を作る(splitmix64の混ぜ方)
+pub fn stream_key(values: &[u64]) -> u64 {
+    values.iter().fold(0x9e3779b97f4a7c15_u64, |h, &v| {
+        let mut x = (h ^ v).wrapping_add(0x9e3779b97f4a7c15);
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+        x ^ (x >> 31)
+    })
+}
+
+// fをseedとkeyで決まる乱数列で実行する
+pub fn with_stream<R>(seed: u64, key: &[u64], f: impl FnOnce() -> R) -> R {
+    with_source(Rc::new(RefCell::new(Pcg32::new(seed, stream_key(key)))), f)
+}
+
+// 画素ごとのサンプル列．サンプルを始めるたびに次元を0から数え直し，
+// 取り出すたびに次の次元の値を返す．最初の2次元は画素の中の位置に使い，
+// その後はstart_dimensionで用途ごとの次元に移る．
+pub trait Sampler: RandomSource {
+    // 画素(x, y)のindex番目のサンプルを始める
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: usize);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+// sppは画素あたりのサンプル数．同じseedからは同じサンプル列ができる．
+pub fn new_sampler(kind: SamplerKind, spp: usize, seed: u64) -> Rc<RefCell<dyn Sampler>> {
+    match kind {
+        SamplerKind::Independent => Rc::new(RefCell::new(IndependentSampler::new(seed))),
+        SamplerKind::Stratified => Rc::new(RefCell::new(StratifiedSampler::new(spp, seed))),
+        SamplerKind::Halton => Rc::new(RefCell::new(HaltonSampler::new(seed))),
+        SamplerKind::Sobol => Rc::new(RefCell::new(SobolSampler::new(seed))),
+    }
+}
+
+// fを画素(x, y)のindex番目のサンプルとして，samplerの乱数で実行する
+pub fn with_pixel_sample<R>(sampler: &Rc<RefCell<dyn Sampler>>, x: u32, y: u32, index: usize, f: impl FnOnce() -> R) -> R {
+    sampler.borrow_mut().start_pixel_sample(x, y, index);
+    with_source(sampler.clone(), f)
+}
+
+// 光線を作った後で，同じサンプルの続きとしてfを実行する．画素の中の位置に使った
+// 次元(独立な乱数では同じ値)を使わないように，その分を読み飛ばしてから始める．
+pub fn resume_pixel_sample<R>(sampler: &Rc<RefCell<dyn Sampler>>, x: u32, y: u32, index: usize, f: impl FnOnce() -> R) -> R {
+    with_pixel_sample(sampler, x, y, index, || {
+        for _ in 0..PIXEL_DIMENSIONS {
+            next_f64();
+        }
+        f()
+    })
+}
+
+// 整数のハッシュ(lowbias32)．値を順に混ぜる．
+fn hash(values: &[u32]) -> u32 {
+    values.iter().fold(0x9e3779b9_u32, |h, &v| {
+        let mut x = h ^ v.wrapping_add(0x7feb352d);
+        x ^= x >> 16;
+        x = x.wrapping_mul(0x7feb352d);
+        x ^= x >> 15;
+        x = x.wrapping_mul(0x846ca68b);
+        x ^= x >> 16;
+        x
+    })
+}
+
+fn to_unit(x: u32) -> f64 {
+    x as f64 / 4294967296.0
+}
+
+// サンプルの中で次に使う次元．用途に割り当てた範囲を使い切ったら，
+// 残りはoverflow番目の次元によらない乱数にする．同じ値を二度使わないように
+// overflowはサンプルの間数え続ける．
+#[derive(Clone, Copy)]
+struct Dimensions {
+    next: u32,
+    end: u32,
+    overflow: u32,
+}
+
+impl Dimensions {
+    fn new() -> Self {
+        Self { next: 0, end: u32::MAX, overflow: 0 }
+    }
+
+    fn start(&mut self, dimension: u32, count: u32) {
+        self.next = dimension;
+        self.end = dimension.saturating_add(count);
+    }
+
+    // 次の次元．範囲を超えていればErrで何番目に超えたか
+    fn take(&mut self) -> Result<u32, u32> {
+        if self.next < self.end {
+            self.next += 1;
+            Ok(self.next - 1)
+        } else {
+            self.overflow += 1;
+            Err(self.overflow - 1)
+        }
+    }
+}
+
+// 範囲を超えた分の乱数
+fn overflow_value(key: u32, index: u32, overflow: u32) -> f64 {
+    to_unit(hash(&[key, index, u32::MAX, overflow]))
+}
+
+// seedと画素からサンプラーのハッシュの種を作る
+fn pixel_key(seed: u64, x: u32, y: u32) -> u32 {
+    hash(&[seed as u32, (seed >> 32) as u32, x, y])
+}
+
+// 互いに独立な一様乱数．サンプルごとに画素とサンプルの番号で乱数列を選ぶ．
+pub struct IndependentSampler {
+    seed: u64,
+    rng: Pcg32,
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u64) -> Self {
+        Self { seed, rng: Pcg32::new(seed, 0) }
+    }
+}
+
+impl RandomSource for IndependentSampler {
+    fn next_f64(&mut self) -> f64 {
+        self.rng.next_f64()
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: usize) {
+        self.rng = Pcg32::new(self.seed, stream_key(&[x as u64, y as u64, index as u64]));
+    }
+}
+
+// 次元ごとに[0, 1)をspp個の区間に分け，各サンプルが別の区間に入るようにする．
+// 区間の割り当ては画素と次元ごとに並べ替えて次元の間の相関をなくす．
+pub struct StratifiedSampler {
+    spp: u32,
+    seed: u64,
+    key: u32,
+    index: u32,
+    dimensions: Dimensions,
+}
+
+impl StratifiedSampler {
+    pub fn new(spp: usize, seed: u64) -> Self {
+        Self { spp: spp.max(1) as u32, seed, key: 0, index: 0, dimensions: Dimensions::new() }
+    }
+}
+
+impl RandomSource for StratifiedSampler {
+    fn next_f64(&mut self) -> f64 {
+        let d = match self.dimensions.take() {
+            Ok(d) => d,
+            Err(i) => return overflow_value(self.key, self.index, i),
+        };
+        let stratum = permute(self.index % self.spp, self.spp, hash(&[self.key, d]));
+        let jitter = to_unit(hash(&[self.key, d, self.index]));
+        (stratum as f64 + jitter) / self.spp as f64
+    }
+
+    fn start_dimension(&mut self, dimension: u32, count: u32) {
+        self.dimensions.start(dimension, count);
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: usize) {
+        self.key = pixel_key(self.seed, x, y);
+        self.index = index as u32;
+        self.dimensions = Dimensions::new();
+    }
+}
+
+// 0..nの並べ替えのi番目(Kensler 2013)
+fn permute(mut i: u32, n: u32, seed: u32) -> u32 {
+    let mut w = n - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < n {
+            break;
+        }
+    }
+    i.wrapping_add(seed) % n
+}
+
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131,
+];
+
+// Halton列．次元dはd番目の素数を基数とする根基逆関数．
+// 桁ごとに上の桁に応じた乱数でずらすOwenスクランブルを画素ごとにかける．
+// 素数の表を超えた次元は独立な乱数にする．
+pub struct HaltonSampler {
+    seed: u64,
+    key: u32,
+    index: u64,
+    dimensions: Dimensions,
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> Self {
+        Self { seed, key: 0, index: 0, dimensions: Dimensions::new() }
+    }
+}
+
+fn scrambled_radical_inverse(base: u32, mut a: u64, seed: u32) -> f64 {
+    let inv_base = 1.0 / base as f64;
+    let (mut result, mut scale, mut prefix) = (0.0, inv_base, seed);
+    // 上の桁が全て0でも並べ替えるので，倍精度の桁数だけ回す
+    while scale > f64::EPSILON {
+        let digit = (a % base as u64) as u32;
+        a /= base as u64;
+        let shifted = (digit + hash(&[prefix]) % base) % base;
+        result += shifted as f64 * scale;
+        prefix = hash(&[prefix, digit]);
+        scale *= inv_base;
+    }
+    result.min(1.0 - f64::EPSILON)
+}
+
+impl RandomSource for HaltonSampler {
+    fn next_f64(&mut self) -> f64 {
+        let d = match self.dimensions.take() {
+            Ok(d) => d,
+            Err(i) => return overflow_value(self.key, self.index as u32, i),
+        };
+        match PRIMES.get(d as usize) {
+            Some(&base) => scrambled_radical_inverse(base, self.index, hash(&[self.key, d])),
+            None => to_unit(hash(&[self.key, d, self.index as u32])),
+        }
+    }
+
+    fn start_dimension(&mut self, dimension: u32, count: u32) {
+        self.dimensions.start(dimension, count);
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: usize) {
+        self.key = pixel_key(self.seed, x, y);
+        self.index = index as u64;
+        self.dimensions = Dimensions::new();
+    }
+}
+
+// Owenスクランブルをかけた2次元のSobol列(Burley 2020)．
+// 2次元ずつ組にして，組ごとに番号をスクランブルで並べ替えて使う．
+pub struct SobolSampler {
+    seed: u64,
+    key: u32,
+    index: u32,
+    dimensions: Dimensions,
+    // 最後に求めた組の番号とその値
+    pair: Option<(u32, [f64; 2])>,
+}
+
+impl SobolSampler {
+    pub fn new(seed: u64) -> Self {
+        Self { seed, key: 0, index: 0, dimensions: Dimensions::new(), pair: None }
+    }
+}
+
+// Sobol列の2つ目の次元．生成行列はパスカルの三角形を2で割った余り．
+fn sobol1(mut i: u32) -> u32 {
+    let (mut v, mut result) = (1_u32 << 31, 0);
+    while i != 0 {
+        if i & 1 != 0 {
+            result ^= v;
+        }
+        i >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+// 上位のビットに応じて下位のビットを入れ替える(Laine and Karras 2011)
+fn owen_scramble(x: u32, seed: u32) -> u32 {
+    let mut x = x.reverse_bits().wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x.reverse_bits()
+}
+
+impl RandomSource for SobolSampler {
+    fn next_f64(&mut self) -> f64 {
+        let d = match self.dimensions.take() {
+            Ok(d) => d,
+            Err(i) => return overflow_value(self.key, self.index, i),
+        };
+        let pair = match self.pair {
+            Some((k, pair)) if k == d / 2 => pair,
+            _ => {
+                let seed = hash(&[self.key, d / 2]);
+                let i = owen_scramble(self.index, seed);
+                let pair = [
+                    to_unit(owen_scramble(i.reverse_bits(), hash(&[seed, 0]))),
+                    to_unit(owen_scramble(sobol1(i), hash(&[seed, 1]))),
+                ];
+                self.pair = Some((d / 2, pair));
+                pair
+            }
+        };
+        pair[(d % 2) as usize]
+    }
+
+    fn start_dimension(&mut self, dimension: u32, count: u32) {
+        self.dimensions.start(dimension, count);
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: usize) {
+        self.key = pixel_key(self.seed, x, y);
+        self.index = index as u32;
+        self.dimensions = Dimensions::new();
+        self.pair = None;
+    }
+}
+
+// 主標本空間の1つの座標．modifyは最後に値を変えた反復．
+#[derive(Clone, Copy)]
+struct PrimarySample {
+    value: f64,
+    modify: usize,
+    backup: f64,
+    modify_backup: usize,
+}
+
+// 主標本空間のメトロポリス法(Kelemen et al. 2002)の乱数列．
+// 大きな変異では全ての値を新しく選び直し，小さな変異では前の値の近くに動かす．
+// 値は使われたときに遅延して変異させるので，経路ごとに使う乱数の数が違ってもよい．
+pub struct MltSampler {
+    rng: Pcg32,
+    samples: Vec<PrimarySample>,
+    index: usize,
+    iteration: usize,
+    large_step: bool,
+    last_large_step: usize,
+    sigma: f64,
+    large_step_probability: f64,
+}
+
+impl MltSampler {
+    // 同じseedからは同じ乱数列ができる．最初の反復は大きな変異．
+    pub fn new(seed: u64, sigma: f64, large_step_probability: f64) -> Self {
+        Self {
+            rng: Pcg32::new(seed, 0),
+            samples: Vec::new(),
+            index: 0,
+            iteration: 0,
+            large_step: true,
+            last_large_step: 0,
+            sigma,
+            large_step_probability,
+        }
+    }
+
+    // 次の変異を始める
+    pub fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.next_f64() < self.large_step_probability;
+        self.index = 0;
+    }
+
+    pub fn is_large_step(&self) -> bool {
+        self.large_step
+    }
+
+    pub fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    // この反復で変えた値を元に戻す
+    pub fn reject(&mut self) {
+        let iteration = self.iteration;
+        for s in self.samples.iter_mut().filter(|s| s.modify == iteration) {
+            s.value = s.backup;
+            s.modify = s.modify_backup;
+        }
+        self.iteration -= 1;
+    }
+
+    fn ensure_ready(&mut self, i: usize) {
+        if i >= self.samples.len() {
+            self.samples.resize(i + 1, PrimarySample { value: 0.0, modify: 0, backup: 0.0, modify_backup: 0 });
+        }
+        let iteration = self.iteration;
+        let s = &mut self.samples[i];
+        // 最後の大きな変異より前の値は，その大きな変異で選び直されていたはず
+        if s.modify < self.last_large_step {
+            s.value = self.rng.next_f64();
+            s.modify = self.last_large_step;
+        }
+        s.backup = s.value;
+        s.modify_backup = s.modify;
+        if self.large_step {
+            s.value = self.rng.next_f64();
+        } else {
+            // 変異させていなかった回数分まとめて正規分布で動かす
+            let n = (iteration - s.modify) as f64;
+            let (r1, r2) = (self.rng.next_f64(), self.rng.next_f64());
+            let normal = (-2.0 * (1.0 - r1).ln()).sqrt() * (PI2 * r2).cos();
+            s.value = (s.value + normal * self.sigma * n.sqrt()).rem_euclid(1.0);
+        }
+        s.modify = iteration;
+    }
+}
+
+impl RandomSource for MltSampler {
+    fn next_f64(&mut self) -> f64 {
+        let i = self.index;
+        self.index += 1;
+        if self.samples.get(i).is_none_or(|s| s.modify != self.iteration) {
+            self.ensure_ready(i);
+        }
+        self.samples[i].value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mlt_sampler() {
+        let draw = |sampler: &Rc<RefCell<MltSampler>>| -> Vec<f64> {
+            with_source(sampler.clone(), || (0..4).map(|_| Float3::random_full().x()).collect())
+        };
+        // 同じseedなら同じ乱数列になる
+        let a = Rc::new(RefCell::new(MltSampler::new(7, 0.01, 0.0)));
+        let b = Rc::new(RefCell::new(MltSampler::new(7, 0.01, 0.0)));
+        let first = draw(&a);
+        assert_eq!(first, draw(&b));
+        // 同じ反復の中では同じ値を返す
+        a.borrow_mut().index = 0;
+        assert_eq!(first, draw(&a));
+        a.borrow_mut().accept();
+
+        // 小さな変異は近くに動き，棄却すると元に戻る
+        a.borrow_mut().start_iteration();
+        let moved = draw(&a);
+        for (x, y) in first.iter().zip(&moved) {
+            let d = (x - y).abs();
+            assert!(d.min(1.0 - d) < 0.1);
+        }
+        assert_ne!(first, moved);
+        a.borrow_mut().reject();
+        assert!(a.borrow().samples.iter().zip(&first).all(|(s, &x)| s.value == x));
+
+        // 設定しなければ元の乱数を使う
+        let x = next_f64();
+        assert!((0.0..1.0).contains(&x));
+    }
+
+    #[test]
+    fn test_pcg32() {
+        // 同じseedと番号なら同じ乱数列，番号が違えば別の乱数列になる
+        let draw = |seed: u64, key: &[u64]| -> Vec<f64> {
+            with_stream(seed, key, || (0..8).map(|_| Float3::random_full().x()).collect())
+        };
+        let a = draw(1, &[2, 3]);
+        assert_eq!(a, draw(1, &[2, 3]));
+        assert_ne!(a, draw(1, &[3, 2]));
+        assert_ne!(a, draw(2, &[2, 3]));
+        assert!(a.iter().all(|x| (0.0..1.0).contains(x)));
+        let mean = draw(5, &[0]).iter().chain(&draw(5, &[1])).sum::<f64>() / 16.0;
+        assert!((mean - 0.5).abs() < 0.25);
+    }
+
+    #[test]
+    fn test_sampler_dimensions() {
+        // 前の用途で使った乱数の数によらず，用途ごとに同じ次元の値になる
+        let kinds = [SamplerKind::Stratified, SamplerKind::Halton, SamplerKind::Sobol];
+        for &kind in &kinds {
+            let sampler = new_sampler(kind, 16, 0);
+            let draw = |skip: usize| with_pixel_sample(&sampler, 1, 2, 3, || {
+                start_dimension(1, Site::Light);
+                let light: Vec<f64> = (0..skip).map(|_| next_f64()).collect();
+                start_dimension(1, Site::Bsdf);
+                let bsdf: Vec<f64> = (0..3).map(|_| next_f64()).collect();
+                (light, bsdf)
+            });
+            let (_, expected) = draw(0);
+            for skip in 1..8 {
+                let (light, bsdf) = draw(skip);
+                assert_eq!(bsdf, expected, "{:?}", kind);
+                // 範囲を超えた分は他の用途の値と重ならない
+                assert!(light.iter().all(|x| !bsdf.contains(x)), "{:?}", kind);
+            }
+        }
+        // 光線を作った後の続きでは，画素の中の位置に使った値を使い回さない
+        let sampler = new_sampler(SamplerKind::Independent, 16, 0);
+        let position = with_pixel_sample(&sampler, 1, 2, 3, || (next_f64(), next_f64()));
+        let resumed = resume_pixel_sample(&sampler, 1, 2, 3, next_f64);
+        assert!(resumed != position.0 && resumed != position.1);
+    }
+
+    #[test]
+    fn test_samplers() {
+        let spp = 16;
+        let kinds = [SamplerKind::Stratified, SamplerKind::Halton, SamplerKind::Sobol];
+        // 最初の次元はspp個の区間に1つずつ入る
+        for &kind in &kinds {
+            let sampler = new_sampler(kind, spp, 0);
+            let mut strata: Vec<usize> = (0..spp)
+                .map(|i| with_pixel_sample(&sampler, 3, 5, i, || (Float3::random_full().x() * spp as f64) as usize))
+                .collect();
+            strata.sort_unstable();
+            assert_eq!(strata, (0..spp).collect::<Vec<_>>(), "{:?}", kind);
+        }
+
+        // 滑らかな関数の積分は独立な乱数より誤差が小さい
+        let f = |x: f64, y: f64, z: f64| (x * 3.0).sin() * y * y + z;
+        let exact = (1.0 - 3_f64.cos()) / 9.0 + 0.5;
+        let error = |kind: SamplerKind| {
+            let sampler = new_sampler(kind, spp, 0);
+            (0..200).map(|pixel| {
+                let sum: f64 = (0..spp).map(|i| {
+                    with_pixel_sample(&sampler, pixel, 0, i, || {
+                        let [x, y, z] = Float3::random().to_array();
+                        assert!((0.0..1.0).contains(&x) && (0.0..1.0).contains(&y) && (0.0..1.0).contains(&z));
+                        f(x, y, z)
+                    })
+                }).sum();
+                (sum / spp as f64 - exact).powi(2)
+            }).sum::<f64>()
+        };
+        let independent = error(SamplerKind::Independent);
+        for &kind in &kinds {
+            assert!(error(kind) < 0.5 * independent, "{:?}", kind);
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,190 @@
+// 昼光の解析モデル(Preetham et al. 1999)．空の輝度分布と太陽の円盤．
+
+use crate::rayt_mod::*;
+
+// 輝度(kcd/m^2)をレンダラの放射輝度に換算する倍率
+const LUMINANCE_SCALE: f64 = 0.1;
+// 大気圏外での太陽の輝度(kcd/m^2)
+const SUN_LUMINANCE: f64 = 2.0e6;
+// 太陽の視半径(度)
+const SUN_ANGULAR_RADIUS: f64 = 0.265;
+
+// elevationとazimuthは太陽の高度と方位(度)．方位はx軸からz軸へ測る．
+// turbidityは大気の濁り(2:快晴〜10:霞)，ground_albedoは地平線より下の地面の反射率．
+pub struct Sky {
+    sun: Vec3,
+    cos_sun: f64,
+    sun_radiance: Color,
+    // 天頂の(Y, x, y)
+    zenith: Vec3,
+    // Y, x, yそれぞれのPerez関数の係数A〜E
+    perez: [[f64; 5]; 3],
+    // 天頂方向での正規化に使うF(0, θs)
+    perez_zenith: Vec3,
+    ground: Color,
+}
+
+impl Sky {
+    pub fn new(elevation: f64, azimuth: f64, turbidity: f64, ground_albedo: Color) -> Self {
+        let (el, az) = (elevation.to_radians(), azimuth.to_radians());
+        let sun = Vec3::new(el.cos() * az.cos(), el.sin(), el.cos() * az.sin());
+        // モデルは太陽が地平線より上にあるときのみ有効
+        let theta_s = (PI * 0.5 - el).clamp(0.0, PI * 0.5);
+        let t = turbidity;
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_y = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let cubic = |c: [f64; 4]| c[0] * theta_s.powi(3) + c[1] * theta_s.powi(2) + c[2] * theta_s + c[3];
+        let zenith_x = t * t * cubic([0.00166, -0.00375, 0.00209, 0.0])
+            + t * cubic([-0.02903, 0.06377, -0.03202, 0.00394])
+            + cubic([0.11693, -0.21196, 0.06052, 0.25886]);
+        let zenith_yc = t * t * cubic([0.00275, -0.00610, 0.00317, 0.0])
+            + t * cubic([-0.04214, 0.08970, -0.04153, 0.00516])
+            + cubic([0.15346, -0.26756, 0.06670, 0.26688]);
+
+        let perez = [
+            [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+            [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+            [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+        ];
+        let perez_zenith: Vec3 = perez.iter().map(|c| Self::perez(c, 1.0, theta_s)).collect();
+
+        let mut sky = Self {
+            sun,
+            cos_sun: SUN_ANGULAR_RADIUS.to_radians().cos(),
+            sun_radiance: Self::sun_transmittance(theta_s, t) * SUN_LUMINANCE * LUMINANCE_SCALE,
+            zenith: Vec3::new(zenith_y, zenith_x, zenith_yc),
+            perez,
+            perez_zenith,
+            ground: Color::zero(),
+        };
+        if elevation < 0.0 {
+            sky.sun_radiance = Color::zero();
+        }
+        sky.ground = ground_albedo * sky.irradiance() * FRAC_1_PI;
+        sky
+    }
+
+    // Perez関数 F(θ, γ)
+    fn perez(c: &[f64; 5], cos_theta: f64, gamma: f64) -> f64 {
+        (1.0 + c[0] * (c[1] / cos_theta.max(0.01)).exp())
+            * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * gamma.cos().powi(2))
+    }
+
+    // 大気を通過した太陽光の透過率．レイリー散乱とエアロゾルによる減衰をRGBの代表波長で求める．
+    fn sun_transmittance(theta_s: f64, turbidity: f64) -> Color {
+        // 相対エアマス(Kasten & Young)
+        let m = 1.0 / (theta_s.cos() + 0.15 * (93.885 - theta_s.to_degrees()).powf(-1.253));
+        let beta = 0.04608 * turbidity - 0.04586;
+        [0.68_f64, 0.55, 0.44].iter().map(|&lambda| {
+            let tau_r = 0.008735 * lambda.powf(-4.08);
+            let tau_a = beta * lambda.powf(-1.3);
+            (-m * (tau_r + tau_a)).exp()
+        }).collect()
+    }
+
+    pub fn sun_direction(&self) -> Vec3 {
+        self.sun
+    }
+
+    pub fn sun_radiance(&self) -> Color {
+        self.sun_radiance
+    }
+
+    // 太陽の円盤の縁のcos
+    pub fn cos_sun(&self) -> f64 {
+        self.cos_sun
+    }
+
+    // 太陽の円盤の立体角
+    pub fn sun_solid_angle(&self) -> f64 {
+        PI2 * (1.0 - self.cos_sun)
+    }
+
+    // 太陽の円盤がなす円錐内で一様に方向を選ぶ
+    pub fn sample_sun(&self) -> Vec3 {
+        let [r1, r2, _] = Vec3::random().to_array();
+        let z = 1.0 - r1 * (1.0 - self.cos_sun);
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let phi = PI2 * r2;
+        ONB::new(self.sun).local(Vec3::new(r * phi.cos(), r * phi.sin(), z))
+    }
+
+    // 太陽を除いた空の放射輝度
+    pub fn sky(&self, d: Vec3) -> Color {
+        let d = d.normalize();
+        if d.y() < 0.0 {
+            return self.ground;
+        }
+        let gamma = d.dot(self.sun).clamp(-1.0, 1.0).acos();
+        let [y, x, yc] = (0..3).map(|i| {
+            self.zenith.to_array()[i] * Self::perez(&self.perez[i], d.y(), gamma) / self.perez_zenith.to_array()[i]
+        }).collect::<Vec3>().to_array();
+        // xyYからXYZへ
+        let xyz = Vec3::new(x / yc * y, y, (1.0 - x - yc) / yc * y);
+        xyz.xyz_to_rgb().iter().map(|c| c.max(0.0) * LUMINANCE_SCALE).collect()
+    }
+
+    // 方向dから届く光．太陽の円盤も含む．
+    pub fn value(&self, d: Vec3) -> Color {
+        let d = d.normalize();
+        if d.dot(self.sun) >= self.cos_sun {
+            self.sky(d) + self.sun_radiance
+        } else {
+            self.sky(d)
+        }
+    }
+
+    // 水平面が受ける放射照度．地面の明るさに使う．
+    pub fn irradiance(&self) -> Color {
+        let (nt, np) = (16, 32);
+        let mut sum = Color::zero();
+        for i in 0..nt {
+            let theta = (i as f64 + 0.5) / nt as f64 * PI * 0.5;
+            let (sin_t, cos_t) = theta.sin_cos();
+            for j in 0..np {
+                let phi = (j as f64 + 0.5) / np as f64 * PI2;
+                let d = Vec3::new(sin_t * phi.cos(), cos_t, sin_t * phi.sin());
+                sum += self.sky(d) * (cos_t * sin_t);
+            }
+        }
+        let d_omega = (PI * 0.5 / nt as f64) * (PI2 / np as f64);
+        sum * d_omega + self.sun_radiance * self.sun_solid_angle() * self.sun.y().max(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sky() {
+        let sky = Sky::new(45.0, 30.0, 3.0, Color::full(0.3));
+        let sun = sky.sun_direction();
+        assert!((sun.length() - 1.0).abs() < 1e-9);
+        assert!((sun.y() - 45_f64.to_radians().sin()).abs() < 1e-9);
+
+        // 太陽の円盤は周りの空よりずっと明るい
+        let near = (sun + Vec3::yaxis() * 0.05).normalize();
+        assert!(sky.value(sun).luminance() > 100.0 * sky.value(near).luminance());
+        // 太陽の近くは反対側より明るく，天頂は青い
+        assert!(sky.value(near).luminance() > sky.value(Vec3::new(-sun.x(), sun.y(), -sun.z())).luminance());
+        let zenith = sky.value(Vec3::yaxis());
+        assert!(zenith.z() > zenith.x());
+
+        // 地面は反射率に比例し，方向によらない
+        let dark = Sky::new(45.0, 30.0, 3.0, Color::full(0.1));
+        let down = Vec3::new(0.3, -1.0, 0.2);
+        let g = sky.value(down);
+        assert!(g.luminance() > 0.0);
+        assert!((g.luminance() - 3.0 * dark.value(down).luminance()).abs() < 1e-9);
+        assert!((g.luminance() - sky.value(-Vec3::yaxis()).luminance()).abs() < 1e-9);
+
+        // 夕方の太陽は赤い
+        let sunset = Sky::new(2.0, 0.0, 3.0, Color::zero());
+        let c = sunset.sun_radiance();
+        assert!(c.x() > c.z());
+        // 地平線下の太陽は光らない
+        assert_eq!(Sky::new(-5.0, 0.0, 3.0, Color::zero()).sun_radiance().luminance(), 0.0);
+    }
+}